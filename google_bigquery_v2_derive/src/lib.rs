@@ -1,4 +1,3 @@
-#[allow(unused)]
 extern crate proc_macro;
 
 use proc_macro2::{Ident, TokenStream};
@@ -10,6 +9,43 @@ struct Field {
     local_name: std::string::String,
     ty: syn::Type,
     required: bool,
+    sensitive: bool,
+    empty_as_null: bool,
+    /// the db name prefix of a `#[db_flatten]` field
+    flatten: Option<std::string::String>,
+    /// a `#[db_record]` field, stored in one RECORD column
+    record: bool,
+    /// the functions of a `#[db_with(encode, decode)]` field
+    with: Option<Codec>,
+    /// the SQL expression of a read-only `#[db_expr]` field
+    expr: Option<std::string::String>,
+}
+
+/// The functions of `#[db_with(encode, decode, "TYPE")]` that convert a field
+/// from and to the `serde_json::Value` of the param layer.
+#[derive(Clone)]
+struct Codec {
+    encode: syn::Path,
+    decode: syn::Path,
+    /// the BigQuery type of the column, `STRING` if not given
+    column_type: std::string::String,
+}
+
+impl syn::parse::Parse for Codec {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let encode = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let decode = input.parse()?;
+        let mut column_type = std::string::String::from("STRING");
+        if input.parse::<Option<syn::Token![,]>>()?.is_some() && !input.is_empty() {
+            column_type = input.parse::<syn::LitStr>()?.value();
+        }
+        Ok(Codec {
+            encode,
+            decode,
+            column_type,
+        })
+    }
 }
 
 struct Attribute {
@@ -19,40 +55,208 @@ struct Attribute {
 
 //region Derive macro 'BigDataTableDerive'
 
-#[proc_macro_derive(BigDataTableDerive, attributes(db_name, required, client, primary_key))]
+#[proc_macro_derive(
+    BigDataTableDerive,
+    attributes(
+        db_name,
+        required,
+        client,
+        primary_key,
+        db_sensitive,
+        db_version,
+        db_flatten,
+        db_empty_as_null,
+        db_view,
+        db_record,
+        db_dataset,
+        db_with,
+        db_expr,
+        db_partition_by,
+        db_cluster_by,
+        db_require_partition_filter,
+        db_default_limit,
+        db_ignore,
+        db_builder
+    )
+)]
 pub fn big_query_table_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let ast = syn::parse(input).unwrap();
-    let tokens = impl_big_query_table_derive(&ast);
+    let ast = syn::parse_macro_input!(input as syn::DeriveInput);
+    let tokens = impl_big_query_table_derive(&ast).unwrap_or_else(|e| e.to_compile_error());
     tokens.into()
 }
 
-fn impl_big_query_table_derive(ast: &syn::DeriveInput) -> proc_macro2::TokenStream {
-    let pk_field = get_pk_field(&ast);
-    let client_field = get_client_field(&ast.data);
-    implement_big_query_table_base(&ast, &pk_field, &client_field)
+fn impl_big_query_table_derive(ast: &syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let pk_field = get_pk_field(ast)?;
+    let client_field = get_client_field(ast)?;
+    check_unique_db_names(ast)?;
+    let check_flattened_db_names = implement_check_flattened_db_names(ast)?;
+    let impl_base = implement_big_query_table_base(ast, &pk_field, &client_field)?;
+    let impl_pk_accessors = implement_pk_accessors(ast, &pk_field);
+    let impl_writable = implement_writable(ast);
+    let impl_builder = implement_builder(ast, &client_field)?;
+    Ok(quote::quote! {
+        #check_flattened_db_names
+        #impl_base
+        #impl_pk_accessors
+        #impl_writable
+        #impl_builder
+    })
+}
+
+/// Generates `<Table>Builder` with a `with_<field>` method per field and
+/// `<Table>::builder()` for tables with `#[db_builder]`.
+///
+/// Fields of type `Option` or `Patch` that are not set are `None` or
+/// `Undefined`, `#[db_ignore]` and `#[db_record]` fields get their default
+/// value. All other fields have to be set.
+fn implement_builder(ast: &DeriveInput, client_field: &Field) -> syn::Result<TokenStream> {
+    let has_builder = ast
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("db_builder"));
+    if !has_builder {
+        return Ok(TokenStream::new());
+    }
+    let table_ident = &ast.ident;
+    let vis = &ast.vis;
+    let builder_ident = quote::format_ident!("{}Builder", table_ident);
+    let client_ident = &client_field.field_ident;
+    let ignored_fields: Vec<Ident> = get_ignored_fields(ast)?
+        .into_iter()
+        .map(|f| f.field_ident)
+        .collect();
+    let fields: Vec<Field> = get_fields_including_ignored(ast)?
+        .into_iter()
+        .filter(|f| f.field_ident != *client_ident)
+        .collect();
+    let builder_doc = format!(
+        "Builds a [`{}`] field by field, see [`{}::builder`].",
+        table_ident, table_ident
+    );
+    let builder_fields = fields.iter().map(|f| {
+        let field_ident = &f.field_ident;
+        let field_ty = &f.ty;
+        quote::quote! { #field_ident: Option<#field_ty> }
+    });
+    let with_methods = fields.iter().map(|f| {
+        let field_ident = &f.field_ident;
+        let field_ty = &f.ty;
+        let method_ident = quote::format_ident!("with_{}", field_ident);
+        quote::quote! {
+            pub fn #method_ident(mut self, value: #field_ty) -> Self {
+                self.#field_ident = Some(value);
+                self
+            }
+        }
+    });
+    let build_fields = fields.iter().map(|f| {
+        let field_ident = &f.field_ident;
+        let local_name = &f.local_name;
+        let ignored = ignored_fields.contains(field_ident);
+        match is_optional_type(&f.ty) || ignored || f.record {
+            true => quote::quote! {
+                #field_ident: self.#field_ident.unwrap_or_default()
+            },
+            false => quote::quote! {
+                #field_ident: self.#field_ident.ok_or_else(|| {
+                    google_bigquery_v2::prelude::anyhow!("{} of {} is not set", #local_name, stringify!(#table_ident))
+                })?
+            },
+        }
+    });
+    Ok(quote::quote! {
+        #[doc = #builder_doc]
+        #[derive(Default)]
+        #vis struct #builder_ident {
+            #(#builder_fields,)*
+        }
+
+        impl #builder_ident {
+            #(#with_methods)*
+
+            /// Creates the instance with the client. Fails if a column that is
+            /// not an `Option`, `Patch` or record was not set.
+            pub fn build(self, client: google_bigquery_v2::prelude::BigqueryClient) -> google_bigquery_v2::prelude::Result<#table_ident> {
+                Ok(#table_ident {
+                    #client_ident: client,
+                    #(#build_fields,)*
+                })
+            }
+        }
+
+        impl #table_ident {
+            /// Returns a builder to create an instance field by field.
+            pub fn builder() -> #builder_ident {
+                #builder_ident::default()
+            }
+        }
+    })
+}
+
+/// Views are read-only, so they do not get the insert/update/delete builders.
+fn implement_writable(ast: &DeriveInput) -> TokenStream {
+    let table_ident = &ast.ident;
+    let is_view = ast.attrs.iter().any(|attr| attr.path().is_ident("db_view"));
+    match is_view {
+        true => TokenStream::new(),
+        false => quote::quote! {
+            impl google_bigquery_v2::data::BigQueryWritable for #table_ident {}
+        },
+    }
+}
+
+fn implement_pk_accessors(ast: &DeriveInput, pk_field: &Field) -> TokenStream {
+    let table_ident = &ast.ident;
+    let pk_ident = &pk_field.field_ident;
+    let pk_ty = &pk_field.ty;
+    quote::quote! {
+        impl #table_ident {
+            /// Returns the value of the primary key.
+            pub fn pk(&self) -> &#pk_ty {
+                &self.#pk_ident
+            }
+            /// Sets the value of the primary key.
+            pub fn set_pk(&mut self, value: #pk_ty) {
+                self.#pk_ident = value;
+            }
+        }
+    }
 }
 
 fn implement_big_query_table_base(
     ast: &DeriveInput,
     pk_field: &Field,
     client_field: &Field,
-) -> proc_macro2::TokenStream {
+) -> syn::Result<proc_macro2::TokenStream> {
     let table_ident = &ast.ident;
-    let table_name = get_table_name(&ast);
-    let impl_get_all_params = implement_get_all_params(&ast, &table_ident);
-    let impl_get_parameter_from_field = implement_get_parameter_from_field(&ast, &table_ident);
-    let impl_get_client = implement_get_client(&client_field);
-    let impl_set_client = implement_set_client(&client_field);
-    let impl_get_pk_field_name = implement_get_pk_field_name(&pk_field);
-    let impl_get_pk_db_name = implement_get_pk_db_name(&pk_field);
-    let impl_get_pk_value = implement_get_pk_value(&pk_field);
-    let impl_get_query_fields = implement_get_query_fields(&ast);
+    let table_name = get_table_name(ast)?;
+    let impl_get_all_params = implement_get_all_params(ast)?;
+    let impl_get_parameter_from_field = implement_get_parameter_from_field(ast)?;
+    let impl_get_client = implement_get_client(client_field);
+    let impl_set_client = implement_set_client(client_field);
+    let impl_get_pk_field_name = implement_get_pk_field_name(pk_field);
+    let impl_get_pk_db_name = implement_get_pk_db_name(pk_field);
+    let impl_get_pk_value = implement_get_pk_value(pk_field);
+    let impl_get_query_fields = implement_get_query_fields(ast)?;
     let impl_get_table_name = implement_impl_get_table_name(&table_name);
-    let impl_reload = implement_reload(&pk_field);
-    let impl_set_field_value = implement_set_field_value(&ast);
-    let impl_get_field_value = implement_get_field_value(&ast);
-    let impl_from_query_result_row = implement_from_query_result_row(&ast);
-    quote::quote! {
+    let impl_get_dataset_name = implement_get_dataset_name(ast)?;
+    let impl_get_default_limit = implement_get_default_limit(ast)?;
+    let impl_reload = implement_reload(pk_field);
+    let impl_get_sensitive_fields = implement_get_sensitive_fields(ast)?;
+    let impl_get_undefined_fields = implement_get_undefined_fields(ast)?;
+    let impl_get_field_types = implement_get_field_types(ast)?;
+    let impl_get_required_fields = implement_get_required_fields(ast)?;
+    let impl_get_version_field_name = implement_get_version_field_name(ast)?;
+    let impl_get_field_exprs = implement_get_field_exprs(ast)?;
+    let impl_get_record_columns = implement_get_record_columns(ast)?;
+    let impl_get_record_schemas = implement_get_record_schemas(ast)?;
+    let impl_get_partition_by = implement_get_partition_by(ast)?;
+    let impl_get_cluster_by = implement_get_cluster_by(ast)?;
+    let impl_get_required_partition_filter = implement_get_required_partition_filter(ast)?;
+    let impl_set_field_value = implement_set_field_value(ast)?;
+    let impl_get_field_value = implement_get_field_value(ast)?;
+    let impl_from_query_result_row = implement_from_query_result_row(ast)?;
+    Ok(quote::quote! {
         #[google_bigquery_v2::re_exports::async_trait::async_trait]
         impl BigQueryTableBase for #table_ident {
             #impl_get_all_params
@@ -64,61 +268,138 @@ fn implement_big_query_table_base(
             #impl_get_pk_value
             #impl_get_query_fields
             #impl_get_table_name
+            #impl_get_dataset_name
+            #impl_get_default_limit
             #impl_reload
+            #impl_get_sensitive_fields
+            #impl_get_undefined_fields
+            #impl_get_field_types
+            #impl_get_required_fields
+            #impl_get_version_field_name
+            #impl_get_field_exprs
+            #impl_get_record_columns
+            #impl_get_record_schemas
+            #impl_get_partition_by
+            #impl_get_cluster_by
+            #impl_get_required_partition_filter
             #impl_set_field_value
             #impl_get_field_value
             #impl_from_query_result_row
         }
-    }
+    })
 }
 
-fn implement_get_all_params(ast: &DeriveInput, table_ident: &Ident) -> TokenStream {
+fn implement_get_all_params(ast: &DeriveInput) -> syn::Result<TokenStream> {
     fn get_param_from_field(f: Field, table_ident: &Ident) -> TokenStream {
+        let get_parameter = get_parameter_fn(&f);
         let field_ident = f.field_ident;
         let field_name = f.local_name;
+        if let Some(Codec {
+            encode,
+            column_type,
+            ..
+        }) = f.with
+        {
+            return quote::quote! {
+                google_bigquery_v2::data::encoded_param(&#table_ident::get_field_param_name(&#field_name.to_string())?, #column_type, &#encode(&self.#field_ident))?
+            };
+        }
+        match f.record {
+            true => quote::quote! {
+                google_bigquery_v2::data::BigQueryRecord::get_record_parameter(&self.#field_ident, &#table_ident::get_field_param_name(&#field_name.to_string())?)?
+            },
+            false => quote::quote! {
+                #table_ident::#get_parameter(&self.#field_ident, &#table_ident::get_field_param_name(&#field_name.to_string())?)
+            },
+        }
+    }
+    fn get_params_from_flattened_field(f: Field, table_ident: &Ident) -> TokenStream {
+        let field_ident = f.field_ident;
+        let ty = f.ty;
+        let local_prefix = get_flatten_local_prefix(&field_ident);
         quote::quote! {
-            #table_ident::get_parameter(&self.#field_ident, &#table_ident::get_field_param_name(&#field_name.to_string())?)
+            let mut columns: Vec<String> = <#ty as google_bigquery_v2::data::BigQueryColumns>::get_column_fields().into_keys().collect();
+            columns.sort();
+            for column in columns {
+                let field_name = format!("{}{}", #local_prefix, column);
+                params.push(google_bigquery_v2::data::BigQueryColumns::get_column_parameter(&self.#field_ident, &column, &#table_ident::get_field_param_name(&field_name)?)?);
+            }
         }
     }
     let table_ident = &ast.ident;
-    let fields = get_fields_without_client(&ast.data);
+    let fields = get_fields_without_client(ast)?;
     let fields = fields
         .into_iter()
-        .map(|f| get_param_from_field(f, &table_ident));
+        .map(|f| get_param_from_field(f, table_ident));
+    let flattened_fields = get_flattened_fields(ast)?;
+    let flattened_fields = flattened_fields
+        .into_iter()
+        .map(|f| get_params_from_flattened_field(f, table_ident));
 
-    quote::quote! {
+    Ok(quote::quote! {
         fn get_all_params(&self) -> google_bigquery_v2::prelude::Result<Vec<Option<google_bigquery_v2::data::QueryParameter>>> {
-            google_bigquery_v2::prelude::trace!("get_all_params() self:{:?}", self);
-            Ok(vec![
+            google_bigquery_v2::prelude::trace!("get_all_params() self:{}", <Self as google_bigquery_v2::data::BigQueryTable>::compact_debug(self));
+            #[allow(unused_mut)]
+            let mut params = vec![
                 #(#fields),*
-            ])
+            ];
+            #(#flattened_fields)*
+            Ok(params)
         }
-    }
+    })
 }
 
-fn implement_get_parameter_from_field(ast: &DeriveInput, table_ident: &Ident) -> TokenStream {
+fn implement_get_parameter_from_field(ast: &DeriveInput) -> syn::Result<TokenStream> {
     fn get_param_from_field(f: Field, table_ident: &Ident) -> TokenStream {
+        let get_parameter = get_parameter_fn(&f);
         let field_ident = f.field_ident;
         let field_name = f.local_name;
+        if let Some(Codec {
+            encode,
+            column_type,
+            ..
+        }) = f.with
+        {
+            return quote::quote! {
+                #field_name => google_bigquery_v2::data::encoded_param(&#table_ident::get_field_param_name(&#field_name.to_string())?, #column_type, &#encode(&self.#field_ident)),
+            };
+        }
+        match f.record {
+            true => quote::quote! {
+                #field_name => google_bigquery_v2::data::BigQueryRecord::get_record_parameter(&self.#field_ident, &#table_ident::get_field_param_name(&#field_name.to_string())?),
+            },
+            false => quote::quote! {
+                #field_name => Ok(#table_ident::#get_parameter(&self.#field_ident, &#table_ident::get_field_param_name(&#field_name.to_string())?)),
+            },
+        }
+    }
+    fn get_param_from_flattened_field(f: Field, table_ident: &Ident) -> TokenStream {
+        let field_ident = f.field_ident;
+        let local_prefix = get_flatten_local_prefix(&field_ident);
         quote::quote! {
-            #field_name => Ok(#table_ident::get_parameter(&self.#field_ident, &#table_ident::get_field_param_name(&#field_name.to_string())?)),
+            name if name.starts_with(#local_prefix) => google_bigquery_v2::data::BigQueryColumns::get_column_parameter(&self.#field_ident, &name[#local_prefix.len()..], &#table_ident::get_field_param_name(name)?),
         }
     }
     let table_ident = &ast.ident;
-    let fields = get_fields_without_client(&ast.data);
+    let fields = get_fields_without_client(ast)?;
     let fields = fields
         .into_iter()
-        .map(|f| get_param_from_field(f, &table_ident));
+        .map(|f| get_param_from_field(f, table_ident));
+    let flattened_fields = get_flattened_fields(ast)?;
+    let flattened_fields = flattened_fields
+        .into_iter()
+        .map(|f| get_param_from_flattened_field(f, table_ident));
 
-    quote::quote! {
+    Ok(quote::quote! {
         fn get_parameter_from_field(&self, field_name: &str) -> google_bigquery_v2::prelude::Result<Option<google_bigquery_v2::data::QueryParameter>> {
-            google_bigquery_v2::prelude::trace!("get_parameter_from_field(); field_name: '{}' self:{:?}", field_name, self);
+            google_bigquery_v2::prelude::trace!("get_parameter_from_field(); field_name: '{}' self:{}", field_name, <Self as google_bigquery_v2::data::BigQueryTable>::compact_debug(self));
             match field_name {
                 #(#fields)*
+                #(#flattened_fields)*
                 _ => Err(google_bigquery_v2::prelude::anyhow!("Field {} not found", field_name).into()),
             }
         }
-    }
+    })
 }
 
 //region method implementations
@@ -127,7 +408,7 @@ fn implement_get_client(client_field: &Field) -> TokenStream {
     let client_ident = client_field.field_ident.clone();
     quote::quote! {
         fn get_client(&self) -> &BigqueryClient {
-            google_bigquery_v2::prelude::trace!("get_client() self={:?}", self);
+            google_bigquery_v2::prelude::trace!("get_client() self={}", <Self as google_bigquery_v2::data::BigQueryTable>::compact_debug(self));
             &self.#client_ident
         }
     }
@@ -137,7 +418,7 @@ fn implement_set_client(client_field: &Field) -> TokenStream {
     let client_ident = client_field.field_ident.clone();
     quote::quote! {
         fn set_client(&mut self, client: BigqueryClient) {
-            google_bigquery_v2::prelude::trace!("set_client() self={:?}", self);
+            google_bigquery_v2::prelude::trace!("set_client() self={}", <Self as google_bigquery_v2::data::BigQueryTable>::compact_debug(self));
             self.#client_ident = client;
         }
     }
@@ -167,13 +448,13 @@ fn implement_get_pk_value(pk_field: &Field) -> TokenStream {
     let pk_ident = &pk_field.field_ident;
     quote::quote! {
         fn get_pk_value(&self) -> &(dyn google_bigquery_v2::data::param_conversion::BigDataValueType + Send + Sync) {
-            google_bigquery_v2::prelude::trace!("get_pk_value() self={:?}", self);
+            google_bigquery_v2::prelude::trace!("get_pk_value()");
             &self.#pk_ident
         }
     }
 }
 
-fn implement_get_query_fields(ast: &DeriveInput) -> TokenStream {
+fn implement_get_query_fields(ast: &DeriveInput) -> syn::Result<TokenStream> {
     fn implement_map_insert(f: Field) -> TokenStream {
         let local_name = f.local_name;
         let db_name = f.db_name;
@@ -181,8 +462,8 @@ fn implement_get_query_fields(ast: &DeriveInput) -> TokenStream {
             map.insert(String::from(#local_name),String::from(#db_name));
         }
     }
-    let fields = get_fields_without_client(&ast.data);
-    let pk_field = get_pk_field(&ast);
+    let fields = get_fields_without_client(ast)?;
+    let pk_field = get_pk_field(ast)?;
     let fields: Vec<TokenStream> = fields
         .into_iter()
         .filter(|f| f.field_ident != pk_field.field_ident)
@@ -190,8 +471,21 @@ fn implement_get_query_fields(ast: &DeriveInput) -> TokenStream {
         .collect();
 
     let pk_insert = implement_map_insert(pk_field);
+    let flattened_fields: Vec<TokenStream> = get_flattened_fields(ast)?
+        .into_iter()
+        .map(|f| {
+            let ty = f.ty;
+            let local_prefix = get_flatten_local_prefix(&f.field_ident);
+            let db_prefix = f.flatten.unwrap_or_default();
+            quote::quote! {
+                for (local_name, db_name) in <#ty as google_bigquery_v2::data::BigQueryColumns>::get_column_fields() {
+                    map.insert(format!("{}{}", #local_prefix, local_name), format!("{}{}", #db_prefix, db_name));
+                }
+            }
+        })
+        .collect();
 
-    quote::quote! {
+    Ok(quote::quote! {
         fn get_query_fields(include_pk: bool) -> std::collections::HashMap<String, String> {
             google_bigquery_v2::prelude::trace!("get_query_fields() include_pk={}", include_pk);
             let mut map = std::collections::HashMap::new();
@@ -199,9 +493,10 @@ fn implement_get_query_fields(ast: &DeriveInput) -> TokenStream {
                 #pk_insert
             }
             #(#fields)*
+            #(#flattened_fields)*
             map
         }
-    }
+    })
 }
 
 fn implement_impl_get_table_name(table_name: &String) -> TokenStream {
@@ -213,78 +508,418 @@ fn implement_impl_get_table_name(table_name: &String) -> TokenStream {
     }
 }
 
-fn implement_set_field_value(ast: &DeriveInput) -> TokenStream {
+fn implement_get_dataset_name(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let dataset_name = match get_dataset_name(ast)? {
+        Some(name) => quote::quote! { Some(String::from(#name)) },
+        None => quote::quote! { None },
+    };
+    Ok(quote::quote! {
+        fn get_dataset_name() -> Option<String> {
+            google_bigquery_v2::prelude::trace!("get_dataset_name()");
+            #dataset_name
+        }
+    })
+}
+
+fn implement_get_default_limit(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let default_limit = match get_default_limit(ast)? {
+        Some(limit) => quote::quote! { Some(#limit) },
+        None => quote::quote! { None },
+    };
+    Ok(quote::quote! {
+        fn get_default_limit() -> Option<u32> {
+            google_bigquery_v2::prelude::trace!("get_default_limit()");
+            #default_limit
+        }
+    })
+}
+
+fn implement_set_field_value(ast: &DeriveInput) -> syn::Result<TokenStream> {
     fn write_set_field_value(f: Field) -> TokenStream {
         let field_ident = f.field_ident;
         let local_name = f.local_name;
-        quote::quote! {
-            #local_name => self.#field_ident = Self::from_param(value)?,
+        if let Some(Codec { decode, .. }) = f.with {
+            return quote::quote! {
+                #local_name => self.#field_ident = #decode(value)?,
+            };
+        }
+        match f.record {
+            true => quote::quote! {
+                #local_name => self.#field_ident = google_bigquery_v2::data::BigQueryRecord::from_record_json(value)?,
+            },
+            false => quote::quote! {
+                #local_name => self.#field_ident = Self::from_param(value)?,
+            },
         }
     }
-    let fields = get_fields_without_client(&ast.data);
+    let fields = get_fields_without_client(ast)?;
     let fields: Vec<TokenStream> = fields.into_iter().map(write_set_field_value).collect();
+    let flattened_fields: Vec<TokenStream> = get_flattened_fields(ast)?
+        .into_iter()
+        .map(|f| {
+            let field_ident = f.field_ident;
+            let local_prefix = get_flatten_local_prefix(&field_ident);
+            quote::quote! {
+                name if name.starts_with(#local_prefix) => google_bigquery_v2::data::BigQueryColumns::set_column_value(&mut self.#field_ident, &name[#local_prefix.len()..], value)?,
+            }
+        })
+        .collect();
 
-    quote::quote! {
+    Ok(quote::quote! {
         fn set_field_value(&mut self, field_name: &str, value: &google_bigquery_v2::re_exports::serde_json::Value) -> Result<()>{
-            google_bigquery_v2::prelude::trace!("set_field_value() self={:?} field_name={} value={:?}", self, field_name, value);
+            google_bigquery_v2::prelude::trace!("set_field_value() self={} field_name={}", <Self as google_bigquery_v2::data::BigQueryTable>::compact_debug(self), field_name);
             use google_bigquery_v2::data::param_conversion::ConvertBigQueryParams;
             match field_name {
                 #(#fields)*
+                #(#flattened_fields)*
                 _ => return Err(google_bigquery_v2::data::param_conversion::ConversionError::new(format!("Field '{}' not found", field_name)).into())
             }
             Ok(())
         }
-    }
+    })
 }
-fn implement_get_field_value(ast: &DeriveInput) -> TokenStream {
+fn implement_get_field_value(ast: &DeriveInput) -> syn::Result<TokenStream> {
     fn write_get_field_value(f: Field) -> TokenStream {
         let field_ident = f.field_ident;
         let local_name = f.local_name;
-        quote::quote! {
-            #local_name => Ok(ConvertBigQueryParams::to_param(&self.#field_ident)),
+        if let Some(Codec { encode, .. }) = f.with {
+            return quote::quote! {
+                #local_name => Ok(#encode(&self.#field_ident)),
+            };
+        }
+        match f.record {
+            true => quote::quote! {
+                #local_name => google_bigquery_v2::data::BigQueryRecord::to_record_json(&self.#field_ident),
+            },
+            false => quote::quote! {
+                #local_name => Ok(ConvertBigQueryParams::to_param(&self.#field_ident)),
+            },
         }
     }
-    let fields = get_fields_without_client(&ast.data);
+    let fields = get_fields_without_client(ast)?;
     let fields: Vec<TokenStream> = fields.into_iter().map(write_get_field_value).collect();
+    let flattened_fields: Vec<TokenStream> = get_flattened_fields(ast)?
+        .into_iter()
+        .map(|f| {
+            let field_ident = f.field_ident;
+            let local_prefix = get_flatten_local_prefix(&field_ident);
+            quote::quote! {
+                name if name.starts_with(#local_prefix) => google_bigquery_v2::data::BigQueryColumns::get_column_value(&self.#field_ident, &name[#local_prefix.len()..]),
+            }
+        })
+        .collect();
 
-    quote::quote! {
+    Ok(quote::quote! {
         fn get_field_value(&self, field_name: &str) -> Result<google_bigquery_v2::re_exports::serde_json::Value> {
-            google_bigquery_v2::prelude::trace!("get_field_value() self={:?} field_name={}", self, field_name);
+            google_bigquery_v2::prelude::trace!("get_field_value() self={} field_name={}", <Self as google_bigquery_v2::data::BigQueryTable>::compact_debug(self), field_name);
             use google_bigquery_v2::data::param_conversion::ConvertBigQueryParams;
             match field_name {
                 #(#fields)*
+                #(#flattened_fields)*
                 _ => return Err(google_bigquery_v2::data::param_conversion::ConversionError::new(format!("Field '{}' not found", field_name)).into())
             }
         }
-    }
+    })
 }
 
-fn implement_from_query_result_row(ast: &DeriveInput) -> TokenStream {
+fn implement_from_query_result_row(ast: &DeriveInput) -> syn::Result<TokenStream> {
     fn set_field_value(f: Field) -> TokenStream {
         let field_ident = f.field_ident;
         let db_name = f.db_name;
+        let local_name = f.local_name;
+        let ty = f.ty;
+        let (from_param, expected_type) = match (f.with, f.record) {
+            (
+                Some(Codec {
+                    decode,
+                    column_type,
+                    ..
+                }),
+                _,
+            ) => (quote::quote! { #decode }, quote::quote! { #column_type }),
+            (None, true) => (
+                quote::quote! { google_bigquery_v2::data::BigQueryRecord::from_record_json },
+                quote::quote! { "RECORD" },
+            ),
+            (None, false) => (
+                quote::quote! { Self::from_param },
+                quote::quote! { <#ty as google_bigquery_v2::data::param_conversion::ConvertTypeToBigQueryType>::convert_type_to_bigquery_type() },
+            ),
+        };
         quote::quote! {
-            #field_ident: Self::from_param(&row[#db_name])?,
+            #field_ident: {
+                let value = row.get(#db_name).ok_or_else(|| {
+                    google_bigquery_v2::data::param_conversion::FieldConversionError::missing_column(#db_name, #local_name, #expected_type)
+                })?;
+                #from_param(value).map_err(|e| {
+                    google_bigquery_v2::data::param_conversion::FieldConversionError::new(#db_name, #local_name, #expected_type, value, e)
+                })?
+            },
         }
     }
-    let client_ident = get_client_field(&ast.data).field_ident;
-    let fields = get_fields_without_client(&ast.data);
+    let client_ident = get_client_field(ast)?.field_ident;
+    let fields = get_fields_without_client(ast)?;
     let fields: Vec<TokenStream> = fields.into_iter().map(set_field_value).collect();
-    quote::quote! {
+    // #[db_ignore] fields are not in the row
+    let ignored_fields = get_ignored_fields(ast)?.into_iter().map(|f| {
+        let field_ident = f.field_ident;
+        quote::quote! {
+            #field_ident: Default::default(),
+        }
+    });
+    let flattened_fields: Vec<TokenStream> = get_flattened_fields(ast)?
+        .into_iter()
+        .map(|f| {
+            let field_ident = f.field_ident;
+            let ty = f.ty;
+            let db_prefix = f.flatten.unwrap_or_default();
+            quote::quote! {
+                #field_ident: <#ty as google_bigquery_v2::data::BigQueryColumns>::new_from_columns(row, #db_prefix)?,
+            }
+        })
+        .collect();
+    Ok(quote::quote! {
          fn new_from_query_result_row(
         client: BigqueryClient,
         row: &std::collections::HashMap<String, google_bigquery_v2::re_exports::serde_json::Value>,
     ) -> Result<Self>
         where Self: Sized {
-            google_bigquery_v2::prelude::trace!("new_from_query_result_row() client={:?} row={:?}", client, row);
+            google_bigquery_v2::prelude::trace!("new_from_query_result_row() client={:?} columns={:?}", client, row.keys().collect::<Vec<_>>());
             use google_bigquery_v2::data::param_conversion::ConvertBigQueryParams;
             let result = Self{
                 #client_ident: client,
                 #(#fields)*
+                #(#flattened_fields)*
+                #(#ignored_fields)*
             };
             Ok(result)
          }
-     }
+     })
+}
+
+fn implement_get_sensitive_fields(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let fields = get_fields_without_client(ast)?;
+    let sensitive_fields = fields
+        .into_iter()
+        .filter(|f| f.sensitive)
+        .map(|f| f.local_name);
+    Ok(quote::quote! {
+        fn get_sensitive_fields() -> Vec<String> {
+            google_bigquery_v2::prelude::trace!("get_sensitive_fields()");
+            vec![#(String::from(#sensitive_fields)),*]
+        }
+    })
+}
+
+fn implement_get_field_types(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    fn implement_map_insert(f: Field) -> TokenStream {
+        let local_name = f.local_name;
+        let ty = f.ty;
+        if let Some(Codec { column_type, .. }) = f.with {
+            return quote::quote! {
+                map.insert(String::from(#local_name), String::from(#column_type));
+            };
+        }
+        match f.record {
+            true => quote::quote! {
+                map.insert(String::from(#local_name), String::from("RECORD"));
+            },
+            false => quote::quote! {
+                map.insert(String::from(#local_name), <#ty as google_bigquery_v2::data::param_conversion::ConvertTypeToBigQueryType>::convert_type_to_bigquery_type());
+            },
+        }
+    }
+    let fields = get_fields_without_client(ast)?;
+    let fields: Vec<TokenStream> = fields.into_iter().map(implement_map_insert).collect();
+    let flattened_fields: Vec<TokenStream> = get_flattened_fields(ast)?
+        .into_iter()
+        .map(|f| {
+            let ty = f.ty;
+            let local_prefix = get_flatten_local_prefix(&f.field_ident);
+            quote::quote! {
+                for (local_name, column_type) in <#ty as google_bigquery_v2::data::BigQueryColumns>::get_column_types() {
+                    map.insert(format!("{}{}", #local_prefix, local_name), column_type);
+                }
+            }
+        })
+        .collect();
+    Ok(quote::quote! {
+        fn get_field_types() -> std::collections::HashMap<String, String> {
+            google_bigquery_v2::prelude::trace!("get_field_types()");
+            let mut map = std::collections::HashMap::new();
+            #(#fields)*
+            #(#flattened_fields)*
+            map
+        }
+    })
+}
+
+fn implement_get_required_fields(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let fields = get_fields_without_client(ast)?;
+    let required_fields = fields
+        .into_iter()
+        .filter(|f| f.required)
+        .map(|f| f.local_name);
+    Ok(quote::quote! {
+        fn get_required_fields() -> Vec<String> {
+            google_bigquery_v2::prelude::trace!("get_required_fields()");
+            vec![#(String::from(#required_fields)),*]
+        }
+    })
+}
+
+fn implement_get_version_field_name(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let version_field = match get_version_field(ast)? {
+        Some(field) => {
+            let local_name = field.local_name;
+            quote::quote! { Some(String::from(#local_name)) }
+        }
+        None => quote::quote! { None },
+    };
+    Ok(quote::quote! {
+        fn get_version_field_name() -> Option<String> {
+            google_bigquery_v2::prelude::trace!("get_version_field_name()");
+            #version_field
+        }
+    })
+}
+
+fn implement_get_field_exprs(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let fields = get_fields_without_client(ast)?;
+    let inserts = fields.into_iter().filter_map(|f| {
+        let local_name = f.local_name;
+        f.expr.map(|expr| {
+            quote::quote! {
+                map.insert(String::from(#local_name), String::from(#expr));
+            }
+        })
+    });
+    Ok(quote::quote! {
+        fn get_field_exprs() -> std::collections::HashMap<String, String> {
+            google_bigquery_v2::prelude::trace!("get_field_exprs()");
+            #[allow(unused_mut)]
+            let mut map = std::collections::HashMap::new();
+            #(#inserts)*
+            map
+        }
+    })
+}
+
+fn implement_get_record_columns(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let fields = get_fields_without_client(ast)?;
+    let inserts = fields.into_iter().filter(|f| f.record).map(|f| {
+        let local_name = f.local_name;
+        let ty = f.ty;
+        quote::quote! {
+            map.insert(String::from(#local_name), <#ty as google_bigquery_v2::data::BigQueryRecord>::get_record_columns());
+        }
+    });
+    Ok(quote::quote! {
+        fn get_record_columns() -> std::collections::HashMap<String, Vec<String>> {
+            google_bigquery_v2::prelude::trace!("get_record_columns()");
+            #[allow(unused_mut)]
+            let mut map = std::collections::HashMap::new();
+            #(#inserts)*
+            map
+        }
+    })
+}
+
+fn implement_get_record_schemas(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let fields = get_fields_without_client(ast)?;
+    let inserts = fields.into_iter().filter(|f| f.record).map(|f| {
+        let local_name = f.local_name;
+        let ty = f.ty;
+        quote::quote! {
+            map.insert(String::from(#local_name), <#ty as google_bigquery_v2::data::BigQueryRecord>::get_record_schema());
+        }
+    });
+    Ok(quote::quote! {
+        fn get_record_schemas() -> std::collections::HashMap<String, google_bigquery_v2::data::TableFieldSchema> {
+            google_bigquery_v2::prelude::trace!("get_record_schemas()");
+            #[allow(unused_mut)]
+            let mut map = std::collections::HashMap::new();
+            #(#inserts)*
+            map
+        }
+    })
+}
+
+fn implement_get_partition_by(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let partition_by = match get_single_column_attribute(ast, "db_partition_by")? {
+        Some(column) => quote::quote! { Some(String::from(#column)) },
+        None => quote::quote! { None },
+    };
+    Ok(quote::quote! {
+        fn get_partition_by() -> Option<String> {
+            google_bigquery_v2::prelude::trace!("get_partition_by()");
+            #partition_by
+        }
+    })
+}
+
+fn implement_get_cluster_by(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let columns = get_column_list_attribute(ast, "db_cluster_by")?;
+    Ok(quote::quote! {
+        fn get_cluster_by() -> Vec<String> {
+            google_bigquery_v2::prelude::trace!("get_cluster_by()");
+            vec![#(String::from(#columns)),*]
+        }
+    })
+}
+
+fn implement_get_required_partition_filter(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let column = match get_single_column_attribute(ast, "db_require_partition_filter")? {
+        Some(column) => quote::quote! { Some(String::from(#column)) },
+        None => quote::quote! { None },
+    };
+    Ok(quote::quote! {
+        fn get_required_partition_filter() -> Option<String> {
+            google_bigquery_v2::prelude::trace!("get_required_partition_filter()");
+            #column
+        }
+    })
+}
+
+fn implement_get_undefined_fields(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    fn push_if_undefined(f: Field) -> TokenStream {
+        let field_ident = f.field_ident;
+        let local_name = f.local_name;
+        quote::quote! {
+            if ConvertBigQueryParams::is_undefined(&self.#field_ident) {
+                fields.push(String::from(#local_name));
+            }
+        }
+    }
+    // records and encoded fields are always written as a whole
+    let fields = get_fields_without_client(ast)?;
+    let fields: Vec<TokenStream> = fields
+        .into_iter()
+        .filter(|f| !f.record && f.with.is_none())
+        .map(push_if_undefined)
+        .collect();
+    let flattened_fields: Vec<TokenStream> = get_flattened_fields(ast)?
+        .into_iter()
+        .map(|f| {
+            let field_ident = f.field_ident;
+            let local_prefix = get_flatten_local_prefix(&field_ident);
+            quote::quote! {
+                for column in google_bigquery_v2::data::BigQueryColumns::get_undefined_columns(&self.#field_ident) {
+                    fields.push(format!("{}{}", #local_prefix, column));
+                }
+            }
+        })
+        .collect();
+    Ok(quote::quote! {
+        fn get_undefined_fields(&self) -> Vec<String> {
+            google_bigquery_v2::prelude::trace!("get_undefined_fields() self={}", <Self as google_bigquery_v2::data::BigQueryTable>::compact_debug(self));
+            use google_bigquery_v2::data::param_conversion::ConvertBigQueryParams;
+            let mut fields = Vec::new();
+            #(#fields)*
+            #(#flattened_fields)*
+            fields
+        }
+    })
 }
 
 fn implement_reload(pk_field: &Field) -> TokenStream {
@@ -306,102 +941,473 @@ fn implement_reload(pk_field: &Field) -> TokenStream {
 
 //endregion
 
+//region Derive macro 'BigDataColumnsDerive'
+
+#[proc_macro_derive(BigDataColumnsDerive, attributes(db_name))]
+pub fn big_query_columns_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::DeriveInput);
+    let tokens = impl_big_query_columns_derive(&ast).unwrap_or_else(|e| e.to_compile_error());
+    tokens.into()
+}
+
+fn impl_big_query_columns_derive(ast: &syn::DeriveInput) -> syn::Result<TokenStream> {
+    let columns_ident = &ast.ident;
+    let fields = get_fields(ast)?;
+    let field_inserts = fields.iter().map(|f| {
+        let local_name = &f.local_name;
+        let db_name = &f.db_name;
+        quote::quote! {
+            map.insert(String::from(#local_name), String::from(#db_name));
+        }
+    });
+    let type_inserts = fields.iter().map(|f| {
+        let local_name = &f.local_name;
+        let ty = &f.ty;
+        quote::quote! {
+            map.insert(String::from(#local_name), <#ty as google_bigquery_v2::data::param_conversion::ConvertTypeToBigQueryType>::convert_type_to_bigquery_type());
+        }
+    });
+    let undefined_pushes = fields.iter().map(|f| {
+        let field_ident = &f.field_ident;
+        let local_name = &f.local_name;
+        quote::quote! {
+            if ConvertBigQueryParams::is_undefined(&self.#field_ident) {
+                fields.push(String::from(#local_name));
+            }
+        }
+    });
+    let get_values = fields.iter().map(|f| {
+        let field_ident = &f.field_ident;
+        let local_name = &f.local_name;
+        quote::quote! {
+            #local_name => Ok(ConvertBigQueryParams::to_param(&self.#field_ident)),
+        }
+    });
+    let set_values = fields.iter().map(|f| {
+        let field_ident = &f.field_ident;
+        let local_name = &f.local_name;
+        quote::quote! {
+            #local_name => self.#field_ident = ConvertBigQueryParams::from_param(value)?,
+        }
+    });
+    let get_parameters = fields.iter().map(|f| {
+        let field_ident = &f.field_ident;
+        let local_name = &f.local_name;
+        quote::quote! {
+            #local_name => google_bigquery_v2::data::param(param_name, &self.#field_ident),
+        }
+    });
+    let from_columns = fields.iter().map(|f| {
+        let field_ident = &f.field_ident;
+        let db_name = &f.db_name;
+        let local_name = &f.local_name;
+        let ty = &f.ty;
+        quote::quote! {
+            #field_ident: {
+                let column = format!("{}{}", prefix, #db_name);
+                let expected_type = <#ty as google_bigquery_v2::data::param_conversion::ConvertTypeToBigQueryType>::convert_type_to_bigquery_type();
+                let value = row.get(&column).ok_or_else(|| {
+                    google_bigquery_v2::data::param_conversion::FieldConversionError::missing_column(&column, #local_name, expected_type.clone())
+                })?;
+                ConvertBigQueryParams::from_param(value).map_err(|e| {
+                    google_bigquery_v2::data::param_conversion::FieldConversionError::new(&column, #local_name, expected_type, value, e)
+                })?
+            },
+        }
+    });
+    let column_db_names = fields.iter().map(|f| &f.db_name);
+    Ok(quote::quote! {
+        impl google_bigquery_v2::data::BigQueryColumns for #columns_ident {
+            const COLUMN_DB_NAMES: &'static [&'static str] = &[#(#column_db_names),*];
+            fn get_column_fields() -> std::collections::HashMap<String, String> {
+                google_bigquery_v2::prelude::trace!("get_column_fields()");
+                let mut map = std::collections::HashMap::new();
+                #(#field_inserts)*
+                map
+            }
+            fn get_column_types() -> std::collections::HashMap<String, String> {
+                google_bigquery_v2::prelude::trace!("get_column_types()");
+                let mut map = std::collections::HashMap::new();
+                #(#type_inserts)*
+                map
+            }
+            fn get_undefined_columns(&self) -> Vec<String> {
+                google_bigquery_v2::prelude::trace!("get_undefined_columns()");
+                use google_bigquery_v2::data::param_conversion::ConvertBigQueryParams;
+                let mut fields = Vec::new();
+                #(#undefined_pushes)*
+                fields
+            }
+            fn get_column_value(&self, field_name: &str) -> google_bigquery_v2::prelude::Result<google_bigquery_v2::re_exports::serde_json::Value> {
+                google_bigquery_v2::prelude::trace!("get_column_value() field_name={}", field_name);
+                use google_bigquery_v2::data::param_conversion::ConvertBigQueryParams;
+                match field_name {
+                    #(#get_values)*
+                    _ => Err(google_bigquery_v2::data::param_conversion::ConversionError::new(format!("Field '{}' not found", field_name)).into())
+                }
+            }
+            fn set_column_value(&mut self, field_name: &str, value: &google_bigquery_v2::re_exports::serde_json::Value) -> google_bigquery_v2::prelude::Result<()> {
+                google_bigquery_v2::prelude::trace!("set_column_value() field_name={}", field_name);
+                use google_bigquery_v2::data::param_conversion::ConvertBigQueryParams;
+                match field_name {
+                    #(#set_values)*
+                    _ => return Err(google_bigquery_v2::data::param_conversion::ConversionError::new(format!("Field '{}' not found", field_name)).into())
+                }
+                Ok(())
+            }
+            fn get_column_parameter(&self, field_name: &str, param_name: &str) -> google_bigquery_v2::prelude::Result<Option<google_bigquery_v2::data::QueryParameter>> {
+                google_bigquery_v2::prelude::trace!("get_column_parameter() field_name={}", field_name);
+                let param = match field_name {
+                    #(#get_parameters)*
+                    _ => return Err(google_bigquery_v2::data::param_conversion::ConversionError::new(format!("Field '{}' not found", field_name)).into())
+                };
+                // null values are written as NULL in the query instead of as a parameter
+                Ok(param.parameter_value.is_some().then_some(param))
+            }
+            fn new_from_columns(
+                row: &std::collections::HashMap<String, google_bigquery_v2::re_exports::serde_json::Value>,
+                prefix: &str,
+            ) -> google_bigquery_v2::prelude::Result<Self> {
+                google_bigquery_v2::prelude::trace!("new_from_columns() columns={:?} prefix={}", row.keys().collect::<Vec<_>>(), prefix);
+                use google_bigquery_v2::data::param_conversion::ConvertBigQueryParams;
+                Ok(Self {
+                    #(#from_columns)*
+                })
+            }
+        }
+    })
+}
+
+//endregion
+
 //region Helper functions
 
-fn get_table_name(ast: &DeriveInput) -> String {
-    for attr in get_struct_attributes(ast) {
+fn get_table_name(ast: &DeriveInput) -> syn::Result<String> {
+    for attr in get_struct_attributes(ast)? {
         if attr.name.eq("db_name") {
             let tokens = &attr.value;
-            return tokens.to_string();
+            return Ok(tokens.to_string());
+        }
+    }
+    Ok(ast.ident.to_string())
+}
+
+fn get_dataset_name(ast: &DeriveInput) -> syn::Result<Option<String>> {
+    for attr in get_struct_attributes(ast)? {
+        if attr.name.eq("db_dataset") {
+            return Ok(Some(attr.value));
+        }
+    }
+    Ok(None)
+}
+
+/// Parses a struct attribute like `#[db_cluster_by("a, b")]` into its column
+/// names and checks that every one of them is a column of the struct.
+fn get_column_list_attribute(ast: &DeriveInput, name: &str) -> syn::Result<Vec<String>> {
+    let db_names: Vec<String> = get_fields_without_client(ast)?
+        .into_iter()
+        .map(|f| f.db_name)
+        .collect();
+    for attr in &ast.attrs {
+        if !attr.path().is_ident(name) {
+            continue;
         }
+        let args: syn::LitStr = attr.parse_args()?;
+        let columns: Vec<String> = args
+            .value()
+            .split(',')
+            .map(|column| column.trim().to_string())
+            .collect();
+        for column in &columns {
+            if !db_names.contains(column) {
+                return Err(syn::Error::new_spanned(
+                    &args,
+                    format!("#[{}] names the unknown column `{}`", name, column),
+                ));
+            }
+        }
+        return Ok(columns);
     }
-    ast.ident.to_string()
+    Ok(vec![])
 }
 
-fn get_pk_field(ast: &syn::DeriveInput) -> Field {
-    let mut pk_fields = get_fields_with_attribute(&ast.data, "primary_key");
-    if pk_fields.len() != 1 {
-        panic!("Exactly one primary key field must be specified");
+/// Like [`get_column_list_attribute`], but for attributes that take exactly
+/// one column, like `#[db_partition_by("ts")]`.
+fn get_single_column_attribute(ast: &DeriveInput, name: &str) -> syn::Result<Option<String>> {
+    let mut columns = get_column_list_attribute(ast, name)?;
+    match columns.len() {
+        0 | 1 => Ok(columns.pop()),
+        _ => Err(syn::Error::new_spanned(
+            &ast.ident,
+            format!("#[{}] takes a single column", name),
+        )),
     }
-    let pk = pk_fields.remove(0);
-    pk
 }
 
-fn get_client_field(data: &syn::Data) -> Field {
-    //region client
-    let mut client_fields = get_fields_with_attribute(&data, "client");
-    if client_fields.len() != 1 {
-        panic!("Exactly one client field must be specified");
+fn get_default_limit(ast: &DeriveInput) -> syn::Result<Option<u32>> {
+    for attr in get_struct_attributes(ast)? {
+        if attr.name.eq("db_default_limit") {
+            let limit = attr
+                .value
+                .parse()
+                .map_err(|e| syn::Error::new_spanned(&ast.ident, e))?;
+            return Ok(Some(limit));
+        }
+    }
+    Ok(None)
+}
+
+fn get_pk_field(ast: &syn::DeriveInput) -> syn::Result<Field> {
+    let mut pk_fields = get_fields_with_attribute(ast, "primary_key")?;
+    match pk_fields.len() {
+        0 => Err(syn::Error::new_spanned(
+            &ast.ident,
+            "Exactly one primary key field must be specified (use #[primary_key])",
+        )),
+        1 => Ok(pk_fields.remove(0)),
+        _ => Err(syn::Error::new_spanned(
+            &pk_fields[1].field_ident,
+            "Exactly one primary key field must be specified, found another #[primary_key] here",
+        )),
+    }
+}
+
+fn get_client_field(ast: &syn::DeriveInput) -> syn::Result<Field> {
+    let mut client_fields = get_fields_with_attribute(ast, "client")?;
+    match client_fields.len() {
+        0 => Err(syn::Error::new_spanned(
+            &ast.ident,
+            "Exactly one client field must be specified (use #[client])",
+        )),
+        1 => Ok(client_fields.remove(0)),
+        _ => Err(syn::Error::new_spanned(
+            &client_fields[1].field_ident,
+            "Exactly one client field must be specified, found another #[client] here",
+        )),
+    }
+}
+
+fn check_unique_db_names(ast: &syn::DeriveInput) -> syn::Result<()> {
+    let mut seen: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+    for field in get_fields_without_client(ast)? {
+        if let Some(other) = seen.get(&field.db_name) {
+            return Err(syn::Error::new_spanned(
+                &field.field_ident,
+                format!(
+                    "db_name '{}' is already used by field '{}'",
+                    field.db_name, other
+                ),
+            ));
+        }
+        seen.insert(field.db_name, field.local_name);
     }
-    let client = client_fields.remove(0);
-    //endregion
-    client
+    Ok(())
 }
 
-fn get_struct_attributes(ast: &syn::DeriveInput) -> Vec<Attribute> {
+/// The columns of `#[db_flatten]` fields are only known to the derive of
+/// the flattened struct, so they are checked at compile time in a `const`.
+fn implement_check_flattened_db_names(ast: &syn::DeriveInput) -> syn::Result<TokenStream> {
+    let flattened_fields = get_flattened_fields(ast)?;
+    if flattened_fields.is_empty() {
+        return Ok(TokenStream::new());
+    }
+    let db_names = get_fields_without_client(ast)?
+        .into_iter()
+        .map(|f| f.db_name);
+    let groups = flattened_fields.into_iter().map(|f| {
+        let ty = f.ty;
+        let prefix = f.flatten.unwrap_or_default();
+        quote::quote! {
+            (#prefix, <#ty as google_bigquery_v2::data::BigQueryColumns>::COLUMN_DB_NAMES)
+        }
+    });
+    let message = format!(
+        "a #[db_flatten] column of {} has the same db name as another column",
+        ast.ident
+    );
+    Ok(quote::quote! {
+        const _: () = assert!(
+            !google_bigquery_v2::data::has_duplicate_db_names(&[("", &[#(#db_names),*]), #(#groups),*]),
+            #message
+        );
+    })
+}
+
+fn get_version_field(ast: &syn::DeriveInput) -> syn::Result<Option<Field>> {
+    let mut version_fields = get_fields_with_attribute(ast, "db_version")?;
+    match version_fields.len() {
+        0 => Ok(None),
+        1 => Ok(Some(version_fields.remove(0))),
+        _ => Err(syn::Error::new_spanned(
+            &version_fields[1].field_ident,
+            "At most one version field can be specified, found another #[db_version] here",
+        )),
+    }
+}
+
+fn get_struct_attributes(ast: &syn::DeriveInput) -> syn::Result<Vec<Attribute>> {
     let attrs = &ast.attrs;
     let mut res = vec![];
     for attr in attrs {
-        if attr.path().is_ident("db_name") {
-            let args: syn::LitStr = attr.parse_args().expect("Failed to parse target name");
-            let args = args.value();
+        for name in ["db_name", "db_dataset"] {
+            if attr.path().is_ident(name) {
+                let args: syn::LitStr = attr.parse_args()?;
+                let args = args.value();
+                res.push(Attribute {
+                    name: name.to_string(),
+                    value: args,
+                });
+            }
+        }
+        if attr.path().is_ident("db_default_limit") {
+            let args: syn::LitInt = attr.parse_args()?;
+            args.base10_parse::<u32>()?;
             res.push(Attribute {
-                name: "db_name".to_string(),
-                value: args,
+                name: "db_default_limit".to_string(),
+                value: args.base10_digits().to_string(),
             });
         }
     }
-    res
+    Ok(res)
 }
-fn get_fields_without_client(data: &syn::Data) -> Vec<Field> {
+/// Returns the fields that map to a single column each, so neither the client
+/// nor `#[db_flatten]` fields.
+fn get_fields_without_client(ast: &syn::DeriveInput) -> syn::Result<Vec<Field>> {
     let mut res = vec![];
-    let client_ident = get_client_field(&data).field_ident;
-    for field in get_fields(&data) {
-        if field.field_ident != client_ident {
+    let client_ident = get_client_field(ast)?.field_ident;
+    for field in get_fields(ast)? {
+        if field.field_ident != client_ident && field.flatten.is_none() {
             res.push(field);
         }
     }
-    res
+    Ok(res)
+}
+fn get_flattened_fields(ast: &syn::DeriveInput) -> syn::Result<Vec<Field>> {
+    let fields = get_fields(ast)?;
+    Ok(fields.into_iter().filter(|f| f.flatten.is_some()).collect())
+}
+/// The `BigQueryTable` function that builds the parameter for the field.
+fn get_parameter_fn(field: &Field) -> TokenStream {
+    match field.empty_as_null {
+        true => quote::quote! { get_parameter_empty_as_null },
+        false => quote::quote! { get_parameter },
+    }
+}
+/// The prefix of the field names of a `#[db_flatten]` field in the parent,
+/// like `address.` for `address.street`.
+fn get_flatten_local_prefix(field_ident: &Ident) -> String {
+    format!("{}.", field_ident)
+}
+fn get_fields(ast: &syn::DeriveInput) -> syn::Result<Vec<Field>> {
+    collect_fields(ast, false)
+}
+/// Like [`get_fields`], but also returns the `#[db_ignore]` fields.
+fn get_fields_including_ignored(ast: &syn::DeriveInput) -> syn::Result<Vec<Field>> {
+    collect_fields(ast, true)
 }
-fn get_fields(data: &syn::Data) -> Vec<Field> {
+/// Returns only the `#[db_ignore]` fields.
+fn get_ignored_fields(ast: &syn::DeriveInput) -> syn::Result<Vec<Field>> {
+    let query_fields: Vec<Ident> = get_fields(ast)?
+        .into_iter()
+        .map(|f| f.field_ident)
+        .collect();
+    Ok(get_fields_including_ignored(ast)?
+        .into_iter()
+        .filter(|f| !query_fields.contains(&f.field_ident))
+        .collect())
+}
+/// Whether the type is an `Option` or a `Patch`, which default to no value.
+fn is_optional_type(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(type_path) => type_path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option" || segment.ident == "Patch"),
+        _ => false,
+    }
+}
+fn collect_fields(ast: &syn::DeriveInput, include_ignored: bool) -> syn::Result<Vec<Field>> {
     let mut res = vec![];
 
-    match data {
+    match &ast.data {
         syn::Data::Struct(ref data_struct) => match data_struct.fields {
             syn::Fields::Named(ref fields_named) => {
                 for field in fields_named.named.iter() {
-                    if let Some(parsed_field) = parse_local_field(&field, false) {
+                    if let Some(parsed_field) = parse_local_field(field, include_ignored)? {
                         res.push(parsed_field);
                     }
                 }
             }
-            _ => (),
+            _ => return Err(not_named_fields_error(ast)),
         },
-        _ => panic!("Must be a struct!"),
+        _ => return Err(not_a_struct_error(ast)),
     };
 
-    return res;
+    Ok(res)
+}
+
+fn not_a_struct_error(ast: &syn::DeriveInput) -> syn::Error {
+    syn::Error::new_spanned(
+        &ast.ident,
+        "BigDataTableDerive can only be derived for structs",
+    )
 }
 
-fn parse_local_field(field: &syn::Field, include_ignored: bool) -> Option<Field> {
+fn not_named_fields_error(ast: &syn::DeriveInput) -> syn::Error {
+    syn::Error::new_spanned(
+        &ast.ident,
+        "BigDataTableDerive requires a struct with named fields",
+    )
+}
+
+fn parse_local_field(field: &syn::Field, include_ignored: bool) -> syn::Result<Option<Field>> {
     match &field.ident {
         Some(ident) => {
             let mut name = None;
             let mut required = false;
+            let mut sensitive = false;
+            let mut empty_as_null = false;
+            let mut flatten = None;
+            let mut record = false;
+            let mut with = None;
+            let mut expr = None;
             let attrs = &field.attrs;
             for attribute in attrs {
                 if attribute.path().is_ident("db_ignore") && !include_ignored {
-                    return None; //skip this field completely
+                    return Ok(None); //skip this field completely
                 }
                 if attribute.path().is_ident("db_name") {
-                    let args: syn::LitStr =
-                        attribute.parse_args().expect("Failed to parse target name");
+                    let args: syn::LitStr = attribute.parse_args()?;
                     let args = args.value();
                     name = Some(args);
                 }
                 if attribute.path().is_ident("required") {
                     required = true;
                 }
+                if attribute.path().is_ident("db_sensitive") {
+                    sensitive = true;
+                }
+                if attribute.path().is_ident("db_empty_as_null") {
+                    empty_as_null = true;
+                }
+                if attribute.path().is_ident("db_flatten") {
+                    // the prefix defaults to the field name, like `address_street`
+                    let prefix = match attribute.meta {
+                        syn::Meta::Path(_) => format!("{}_", ident),
+                        _ => attribute.parse_args::<syn::LitStr>()?.value(),
+                    };
+                    flatten = Some(prefix);
+                }
+                if attribute.path().is_ident("db_record") {
+                    record = true;
+                }
+                if attribute.path().is_ident("db_with") {
+                    with = Some(attribute.parse_args::<Codec>()?);
+                }
+                if attribute.path().is_ident("db_expr") {
+                    expr = Some(attribute.parse_args::<syn::LitStr>()?.value());
+                }
             }
 
             let local_name = ident.to_string();
@@ -415,16 +1421,25 @@ fn parse_local_field(field: &syn::Field, include_ignored: bool) -> Option<Field>
                 db_name: name,
                 ty: field.ty.clone(),
                 required,
+                sensitive,
+                empty_as_null,
+                flatten,
+                record,
+                with,
+                expr,
             };
-            return Some(parsed_field);
+            Ok(Some(parsed_field))
         }
-        _ => None,
+        _ => Ok(None),
     }
 }
 
-fn get_fields_with_attribute(data: &syn::Data, attribute_name: &str) -> Vec<Field> {
+fn get_fields_with_attribute(
+    ast: &syn::DeriveInput,
+    attribute_name: &str,
+) -> syn::Result<Vec<Field>> {
     let mut res = vec![];
-    match data {
+    match &ast.data {
         // Only process structs
         syn::Data::Struct(ref data_struct) => {
             // Check the kind of fields the struct contains
@@ -433,29 +1448,30 @@ fn get_fields_with_attribute(data: &syn::Data, attribute_name: &str) -> Vec<Fiel
                 syn::Fields::Named(ref fields_named) => {
                     // Iterate over the fields
                     for field in fields_named.named.iter() {
-                        if let Some(_) = &field.ident {
+                        if field.ident.is_some() {
                             // Get attributes #[..] on each field
                             for attr in field.attrs.iter() {
                                 // Parse the attribute
                                 if attr.path().is_ident(attribute_name) {
-                                    let parsed_field = parse_local_field(&field, true).unwrap();
-                                    res.push(parsed_field);
+                                    if let Some(parsed_field) = parse_local_field(field, true)? {
+                                        res.push(parsed_field);
+                                    }
                                 }
                             }
                         }
                     }
                 }
 
-                // Struct with unnamed fields
-                _ => (),
+                // Tuple and unit structs have no names to map to columns
+                _ => return Err(not_named_fields_error(ast)),
             }
         }
 
-        // Panic when we don't have a struct
-        _ => panic!("Must be a struct"),
+        // Error when we don't have a struct
+        _ => return Err(not_a_struct_error(ast)),
     }
 
-    return res;
+    Ok(res)
 }
 
 //endregion
@@ -1,17 +1,49 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
 
-use google_bigquery2::client::NoToken;
+use chrono::{DateTime, Utc};
+use google_bigquery2::client::{GetToken, NoToken};
 use google_bigquery2::hyper::client::HttpConnector;
 use google_bigquery2::hyper_rustls::HttpsConnector;
 use google_bigquery2::Bigquery;
 use google_bigquery2::{hyper, hyper_rustls, oauth2};
 
+use google_bigquery2::api::{
+    Job, JobConfiguration, JobConfigurationQuery, QueryParameter, QueryRequest,
+};
+
+use crate::data::jobs::{get_child_job_stats, run_job_to_completion, QueryStats};
+use crate::data::param_conversion::BigDataValueType;
+use crate::data::query_builder::{run_query_with_client, BigqueryError};
+use crate::data::transaction::Transaction;
+use crate::prelude::*;
+
+/// The maximum length of a label value.
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Decides whether a failed query is retried, see [`BigqueryClient::retry_if`].
+pub type RetryPredicate = Arc<dyn Fn(&BigqueryError) -> bool + Send + Sync>;
+
 #[derive(Clone)]
 pub struct BigqueryClient {
     client: Bigquery<HttpsConnector<HttpConnector>>,
     project_id: String,
     dataset_id: String,
+    /// the project the jobs run in, if it differs from `project_id`
+    job_project_id: Option<String>,
+    redact_params: bool,
+    allow_http_only: bool,
+    /// create missing tables on the first write, see [`BigqueryClient::auto_create`]
+    auto_create: bool,
+    /// overrides which errors are retried, see [`BigqueryClient::retry_if`]
+    retry_if: Option<RetryPredicate>,
+    /// correlates the jobs with a request of the application, see
+    /// [`BigqueryClient::with_request_id`]
+    request_id: Option<String>,
 }
 
 impl Default for BigqueryClient {
@@ -22,22 +54,18 @@ impl Default for BigqueryClient {
 
 impl BigqueryClient {
     pub fn empty() -> BigqueryClient {
-        let auth: NoToken = NoToken::default();
-        let client = Bigquery::new(
-            hyper::Client::builder().build(
-                hyper_rustls::HttpsConnectorBuilder::new()
-                    .with_native_roots()
-                    .https_or_http()
-                    .enable_http1()
-                    .enable_http2()
-                    .build(),
-            ),
-            auth,
-        );
+        let auth: NoToken = NoToken;
+        let client = Bigquery::new(build_hyper_client(false), auth);
         BigqueryClient {
             client,
             project_id: "".to_string(),
             dataset_id: "".to_string(),
+            redact_params: false,
+            allow_http_only: false,
+            job_project_id: None,
+            auto_create: false,
+            retry_if: None,
+            request_id: None,
         }
     }
 }
@@ -53,9 +81,60 @@ impl BigqueryClient {
             client,
             project_id: project_id.into(),
             dataset_id: dataset_id.into(),
+            redact_params: false,
+            allow_http_only: false,
+            job_project_id: None,
+            auto_create: false,
+            retry_if: None,
+            request_id: None,
         })
     }
 
+    /// Creates a client without a dataset, for queries that only use fully
+    /// qualified tables, like [`run_raw`](crate::data::run_raw) or
+    /// [`query_scalar`](BigqueryClient::query_scalar) against
+    /// `bigquery-public-data.samples.shakespeare`.
+    ///
+    /// Table structs need a dataset, either from `#[db_dataset]` or from
+    /// [`with_dataset`](BigqueryClient::with_dataset).
+    pub async fn new_for_project<S: Into<String>>(
+        project_id: S,
+        service_account_path: Option<S>,
+    ) -> Result<BigqueryClient, Box<dyn Error>> {
+        BigqueryClient::new(
+            project_id.into(),
+            String::new(),
+            service_account_path.map(Into::into),
+        )
+        .await
+    }
+
+    /// Creates a client that authenticates with an access token that was
+    /// obtained elsewhere, e.g. from a workload identity federation exchange.
+    ///
+    /// The token is not refreshed: once `expiry` has passed, requests fail and
+    /// the caller has to create a new client with a fresh token.
+    pub fn new_with_access_token<S: Into<String>>(
+        project_id: S,
+        dataset_id: S,
+        token: String,
+        expiry: Option<DateTime<Utc>>,
+    ) -> BigqueryClient {
+        let auth = AccessToken { token, expiry };
+        let client = Bigquery::new(build_hyper_client(false), auth);
+        BigqueryClient {
+            client,
+            project_id: project_id.into(),
+            dataset_id: dataset_id.into(),
+            redact_params: false,
+            allow_http_only: false,
+            job_project_id: None,
+            auto_create: false,
+            retry_if: None,
+            request_id: None,
+        }
+    }
+
     pub fn get_client(&self) -> &Bigquery<HttpsConnector<HttpConnector>> {
         &self.client
     }
@@ -65,6 +144,241 @@ impl BigqueryClient {
     pub fn get_dataset_id(&self) -> &str {
         &self.dataset_id
     }
+    /// The project the query and load jobs run in (and are billed to).
+    ///
+    /// This is the project id, unless the client was retargeted with
+    /// [`with_project`](BigqueryClient::with_project).
+    pub fn get_job_project_id(&self) -> &str {
+        self.job_project_id.as_deref().unwrap_or(&self.project_id)
+    }
+    /// Returns a clone of this client that reads from and writes to another
+    /// dataset. The authentication is shared, so no new token is needed.
+    pub fn with_dataset<S: Into<String>>(&self, dataset_id: S) -> Self {
+        let mut client = self.clone();
+        client.dataset_id = dataset_id.into();
+        client
+    }
+    /// Like [`with_dataset`](BigqueryClient::with_dataset), but for another
+    /// project. The jobs still run in the original project, since you usually
+    /// can't run jobs in the other one (e.g. `bigquery-public-data`).
+    pub fn with_project<S: Into<String>>(&self, project_id: S) -> Self {
+        let mut client = self.clone();
+        client.job_project_id = Some(self.get_job_project_id().to_string());
+        client.project_id = project_id.into();
+        client
+    }
+    /// Retargets both the project and the dataset, e.g. to read a public
+    /// dataset like `bigquery-public-data.samples` with your own credentials.
+    pub fn with_target<S: Into<String>>(&self, project_id: S, dataset_id: S) -> Self {
+        self.with_project(project_id).with_dataset(dataset_id)
+    }
+    /// When enabled, the values of all query parameters are redacted in logs.
+    pub fn redact_params(mut self, redact_params: bool) -> Self {
+        self.redact_params = redact_params;
+        self
+    }
+    pub fn get_redact_params(&self) -> bool {
+        self.redact_params
+    }
+    /// Rebuilds the connector for plain HTTP endpoints, like a local emulator
+    /// or proxy. HTTP/2 is not offered in this mode, since plaintext endpoints
+    /// usually only speak HTTP/1.1.
+    ///
+    /// Use together with [`with_root_url`](BigqueryClient::with_root_url).
+    pub fn allow_http_only(mut self, allow_http_only: bool) -> Self {
+        self.allow_http_only = allow_http_only;
+        self.client.client = build_hyper_client(allow_http_only);
+        self
+    }
+    pub fn get_allow_http_only(&self) -> bool {
+        self.allow_http_only
+    }
+    /// When enabled, an insert into a table that does not exist creates the
+    /// table from the schema of the struct and is then retried. Updates and
+    /// deletes never create a table, since they would not find a row in an
+    /// empty one. Meant for local development, off by default.
+    pub fn auto_create(mut self, auto_create: bool) -> Self {
+        self.auto_create = auto_create;
+        self
+    }
+    pub fn get_auto_create(&self) -> bool {
+        self.auto_create
+    }
+    /// Sets an id (e.g. of the incoming HTTP request) that is logged with
+    /// every query and added to the jobs as the `request_id` label, so a job
+    /// can be found from the logs of the application and the other way round.
+    ///
+    /// Label values may only contain lowercase letters, digits, `_` and `-`,
+    /// so the label is the id lowercased with other characters replaced by
+    /// `_` and cut to 63 characters.
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+    pub fn get_request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+    /// The job labels for the request id, if one is set.
+    pub(crate) fn get_request_labels(&self) -> Option<HashMap<String, String>> {
+        let request_id = self.request_id.as_ref()?;
+        let value: String = request_id
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    true => c.to_ascii_lowercase(),
+                    false => '_',
+                },
+            )
+            .take(MAX_LABEL_LENGTH)
+            .collect();
+        Some(HashMap::from([(String::from("request_id"), value)]))
+    }
+    /// Replaces the check which failed queries are retried. By default only
+    /// rate limits (429) and server errors (5xx) are retried.
+    pub fn retry_if(mut self, predicate: RetryPredicate) -> Self {
+        self.retry_if = Some(predicate);
+        self
+    }
+    /// Whether a query that failed with `error` is retried.
+    pub fn should_retry(&self, error: &BigqueryError) -> bool {
+        match &self.retry_if {
+            Some(predicate) => predicate(error),
+            None => matches!(error.status, Some(429) | Some(500..=599)),
+        }
+    }
+    /// Releases this handle to the connection pool. The idle connections are
+    /// closed once the last clone of the client is gone.
+    ///
+    /// This is the same as dropping the client, but makes it explicit in
+    /// services that create many short-lived clients (e.g. one per tenant).
+    /// Keep in mind that every table struct holds a clone of its client, so
+    /// the pool lives as long as any of them.
+    pub fn close(self) {
+        trace!("close() self={:?}", self);
+        drop(self);
+    }
+    /// Runs `SELECT 1` to check the credentials and the connection.
+    pub async fn ping(&self) -> Result<()> {
+        trace!("ping() self={:?}", self);
+        if self.project_id.is_empty() {
+            return Err(anyhow!("can not ping: the client has no project id"));
+        }
+        let request = QueryRequest {
+            query: Some(String::from("SELECT 1")),
+            use_legacy_sql: Some(false),
+            ..Default::default()
+        };
+        let (_, query_response) = run_query_with_client(self, request).await?;
+        let value = query_response
+            .rows
+            .and_then(|rows| rows.into_iter().next())
+            .and_then(|row| row.f)
+            .and_then(|cells| cells.into_iter().next())
+            .and_then(|cell| cell.v);
+        match value {
+            Some(serde_json::Value::String(value)) if value == "1" => Ok(()),
+            other => Err(anyhow!("ping returned an unexpected result: {:?}", other)),
+        }
+    }
+    /// Runs a query that returns a single value, like `SELECT MAX(ts) FROM t`,
+    /// and converts the first cell of the first row to `T`.
+    ///
+    /// Returns `None` if the query returns no rows. Use an `Option<T>` for
+    /// values that can be NULL.
+    pub async fn query_scalar<T: BigDataValueType>(
+        &self,
+        sql: &str,
+        params: Vec<QueryParameter>,
+    ) -> Result<Option<T>> {
+        trace!("query_scalar({}, {} params)", sql, params.len());
+        let query_parameters = match params.is_empty() {
+            true => None,
+            false => Some(params),
+        };
+        let request = QueryRequest {
+            query: Some(sql.to_string()),
+            query_parameters,
+            use_legacy_sql: Some(false),
+            ..Default::default()
+        };
+        let (_, query_response) = run_query_with_client(self, request).await?;
+        let row = match query_response.rows.and_then(|rows| rows.into_iter().next()) {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let value = row
+            .f
+            .and_then(|cells| cells.into_iter().next())
+            .and_then(|cell| cell.v)
+            .unwrap_or(serde_json::Value::Null);
+        debug!("query_scalar: {:?}", value);
+        Ok(Some(T::from_param(&value)?))
+    }
+    /// Runs a script of multiple `;`-separated statements as one job and
+    /// returns the statistics of each statement.
+    ///
+    /// Fails with the error of the first failing statement.
+    pub async fn run_script(
+        &self,
+        sql: &str,
+        params: Vec<QueryParameter>,
+    ) -> Result<Vec<QueryStats>> {
+        trace!("run_script({}, {} params)", sql, params.len());
+        let (parameter_mode, query_parameters) = match params.is_empty() {
+            true => (None, None),
+            false => (Some(String::from("NAMED")), Some(params)),
+        };
+        let query = JobConfigurationQuery {
+            query: Some(sql.to_string()),
+            query_parameters,
+            parameter_mode,
+            use_legacy_sql: Some(false),
+            ..Default::default()
+        };
+        let job = Job {
+            configuration: Some(JobConfiguration {
+                query: Some(query),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let job = run_job_to_completion(self, job).await?;
+        let job_id = job
+            .job_reference
+            .and_then(|reference| reference.job_id)
+            .ok_or_else(|| anyhow!("script job has no job id"))?;
+        let stats = get_child_job_stats(self, &job_id).await?;
+        debug!("run_script stats: {:?}", stats);
+        Ok(stats)
+    }
+    /// Runs the queries that `build` adds to the transaction atomically as
+    /// one script. If any statement fails, all of them are rolled back.
+    ///
+    /// Nothing is sent if `build` returns an error.
+    pub async fn transaction<F>(&self, build: F) -> Result<Vec<QueryStats>>
+    where
+        F: FnOnce(&mut Transaction) -> Result<()>,
+    {
+        trace!("transaction()");
+        let mut transaction = Transaction::new();
+        build(&mut transaction)?;
+        if transaction.is_empty() {
+            return Ok(vec![]);
+        }
+        let (script, params) = transaction.build_script();
+        self.run_script(&script, params).await
+    }
+    /// Sends all requests to `root_url` (e.g. `http://localhost:9050/`)
+    /// instead of `https://bigquery.googleapis.com/`.
+    pub fn with_root_url<S: Into<String>>(mut self, root_url: S) -> Self {
+        let mut root_url = root_url.into();
+        if !root_url.ends_with('/') {
+            root_url.push('/');
+        }
+        self.client.base_url(format!("{}bigquery/v2/", root_url));
+        self.client.root_url(root_url);
+        self
+    }
 }
 
 impl Debug for BigqueryClient {
@@ -72,34 +386,69 @@ impl Debug for BigqueryClient {
         f.debug_struct("BigqueryClient")
             .field("project_id", &self.project_id)
             .field("dataset_id", &self.dataset_id)
+            .field("job_project_id", &self.job_project_id)
+            .field("redact_params", &self.redact_params)
+            .field("allow_http_only", &self.allow_http_only)
+            .field("auto_create", &self.auto_create)
+            .field("retry_if", &self.retry_if.is_some())
+            .field("request_id", &self.request_id)
             .finish()
     }
 }
 
+/// A pre-obtained bearer token, see [`BigqueryClient::new_with_access_token`].
+#[derive(Clone)]
+struct AccessToken {
+    token: String,
+    expiry: Option<DateTime<Utc>>,
+}
+
+impl GetToken for AccessToken {
+    fn get_token<'a>(
+        &'a self,
+        _scopes: &'a [&str],
+    ) -> Pin<
+        Box<dyn Future<Output = Result<Option<String>, Box<dyn Error + Send + Sync>>> + Send + 'a>,
+    > {
+        Box::pin(async move {
+            match self.expiry {
+                Some(expiry) if expiry <= Utc::now() => {
+                    Err(format!("the access token expired at {}", expiry).into())
+                }
+                _ => Ok(Some(self.token.clone())),
+            }
+        })
+    }
+}
+
+fn build_hyper_client(http_only: bool) -> hyper::Client<HttpsConnector<HttpConnector>> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1();
+    let connector = match http_only {
+        true => connector.build(),
+        false => connector.enable_http2().build(),
+    };
+    hyper::Client::builder().build(connector)
+}
+
 async fn get_internal_client<S: Into<String>>(
     service_account_path: Option<S>,
 ) -> Result<Bigquery<HttpsConnector<HttpConnector>>, Box<dyn Error>> {
-    let hyper_client = hyper::Client::builder().build(
-        hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .build(),
-    );
+    let hyper_client = build_hyper_client(false);
     let service_account_path = match service_account_path {
         None => "auth/service_account2.json".to_string(),
         Some(s) => s.into(),
     };
     let secret = oauth2::read_service_account_key(&service_account_path)
         .await
-        .expect(
-            format!(
+        .unwrap_or_else(|_| {
+            panic!(
                 "Failed to read service account key from file. {}",
                 service_account_path
             )
-            .as_str(),
-        );
+        });
     let auth = oauth2::ServiceAccountAuthenticator::builder(secret)
         .build()
         .await
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use google_bigquery2::api::{QueryParameter, QueryParameterType, QueryParameterTypeStructTypes};
+use google_bigquery2::api::{QueryParameterValue, TableFieldSchema};
+use serde_json::Value;
+
+use crate::prelude::*;
+
+/// A group of columns that can be flattened into a table with `#[db_flatten]`.
+///
+/// Derive this with `#[derive(BigDataColumnsDerive)]`. The field names are the
+/// names in the struct, the db names are the column names without the prefix
+/// of the flattened field.
+pub trait BigQueryColumns {
+    /// The db names of the columns, used to check at compile time that a
+    /// flattened struct does not repeat a column of the table.
+    const COLUMN_DB_NAMES: &'static [&'static str] = &[];
+    /// Returns a HashMap with the field name as key and the db name as value.
+    fn get_column_fields() -> HashMap<String, String>;
+    /// Returns a HashMap with the field name as key and the BigQuery type as value.
+    fn get_column_types() -> HashMap<String, String>;
+    /// Returns the names of the fields whose value is undefined.
+    fn get_undefined_columns(&self) -> Vec<String>;
+    fn get_column_value(&self, field_name: &str) -> Result<Value>;
+    fn set_column_value(&mut self, field_name: &str, value: &Value) -> Result<()>;
+    /// Returns the parameter for a field, or `None` if the value is null.
+    fn get_column_parameter(
+        &self,
+        field_name: &str,
+        param_name: &str,
+    ) -> Result<Option<QueryParameter>>;
+    /// Creates a new instance from a query result row, where the column names
+    /// are the db names with `prefix` in front.
+    fn new_from_columns(row: &HashMap<String, Value>, prefix: &str) -> Result<Self>
+    where
+        Self: Sized;
+}
+
+/// Whether two columns have the same db name. Every group is a db name
+/// prefix with the column names behind it; the columns of the table itself
+/// have an empty prefix.
+///
+/// Used by the derive in a `const` item, so a `#[db_flatten]` field that
+/// repeats a column fails to compile.
+#[doc(hidden)]
+pub const fn has_duplicate_db_names(groups: &[(&str, &[&str])]) -> bool {
+    let mut group = 0;
+    while group < groups.len() {
+        let mut column = 0;
+        while column < groups[group].1.len() {
+            let mut other_group = group;
+            let mut other_column = column + 1;
+            while other_group < groups.len() {
+                while other_column < groups[other_group].1.len() {
+                    if prefixed_names_equal(
+                        groups[group].0,
+                        groups[group].1[column],
+                        groups[other_group].0,
+                        groups[other_group].1[other_column],
+                    ) {
+                        return true;
+                    }
+                    other_column += 1;
+                }
+                other_group += 1;
+                other_column = 0;
+            }
+            column += 1;
+        }
+        group += 1;
+    }
+    false
+}
+
+/// Whether `prefix + name` equals `other_prefix + other_name`.
+const fn prefixed_names_equal(
+    prefix: &str,
+    name: &str,
+    other_prefix: &str,
+    other_name: &str,
+) -> bool {
+    let len = prefix.len() + name.len();
+    if len != other_prefix.len() + other_name.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < len {
+        if prefixed_byte(prefix, name, i) != prefixed_byte(other_prefix, other_name, i) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn prefixed_byte(prefix: &str, name: &str, i: usize) -> u8 {
+    match i < prefix.len() {
+        true => prefix.as_bytes()[i],
+        false => name.as_bytes()[i - prefix.len()],
+    }
+}
+
+/// The value of a `#[db_record]` field, stored in a single RECORD (STRUCT)
+/// column instead of one column per field like `#[db_flatten]`.
+///
+/// Implemented for every [`BigQueryColumns`] type, for `Option` of one
+/// where `None` is a NULL record, and for `Vec` of one, which is stored in an
+/// `ARRAY<STRUCT<...>>` column.
+pub trait BigQueryRecord: Sized {
+    /// Returns the record as a JSON object with the db names as keys.
+    fn to_record_json(&self) -> Result<Value>;
+    /// Reads the record from a JSON object with the db names as keys.
+    fn from_record_json(value: &Value) -> Result<Self>;
+    /// Returns the STRUCT parameter for the record, or `None` if it is NULL.
+    fn get_record_parameter(&self, param_name: &str) -> Result<Option<QueryParameter>>;
+    /// Returns the db names of the columns of the record, sorted.
+    fn get_record_columns() -> Vec<String>;
+    /// Returns the schema of the RECORD column, with the columns of the
+    /// record as sub-fields. The name is left empty.
+    fn get_record_schema() -> TableFieldSchema;
+}
+
+impl<T: BigQueryColumns> BigQueryRecord for T {
+    fn get_record_columns() -> Vec<String> {
+        let mut columns: Vec<String> = T::get_column_fields().into_values().collect();
+        columns.sort();
+        columns
+    }
+
+    fn get_record_schema() -> TableFieldSchema {
+        let column_types = T::get_column_types();
+        let mut columns: Vec<(String, String)> = T::get_column_fields().into_iter().collect();
+        columns.sort_by(|a, b| a.1.cmp(&b.1));
+        let fields = columns
+            .into_iter()
+            .map(|(field_name, db_name)| TableFieldSchema {
+                name: Some(db_name),
+                type_: column_types.get(&field_name).cloned(),
+                mode: Some(String::from("NULLABLE")),
+                ..Default::default()
+            })
+            .collect();
+        TableFieldSchema {
+            type_: Some(String::from("RECORD")),
+            mode: Some(String::from("NULLABLE")),
+            fields: Some(fields),
+            ..Default::default()
+        }
+    }
+
+    fn to_record_json(&self) -> Result<Value> {
+        let mut map = serde_json::Map::new();
+        for (field_name, db_name) in T::get_column_fields() {
+            map.insert(db_name, self.get_column_value(&field_name)?);
+        }
+        Ok(Value::Object(map))
+    }
+
+    fn from_record_json(value: &Value) -> Result<Self> {
+        let record = match value {
+            Value::Object(record) => record,
+            Value::Null => return Err(anyhow!("the record is NULL")),
+            _ => return Err(anyhow!("expected a record but got {}", value)),
+        };
+        // fields missing in the record are NULL
+        let row: HashMap<String, Value> = T::get_column_fields()
+            .into_values()
+            .map(|db_name| {
+                let value = record.get(&db_name).cloned().unwrap_or(Value::Null);
+                (db_name, value)
+            })
+            .collect();
+        T::new_from_columns(&row, "")
+    }
+
+    fn get_record_parameter(&self, param_name: &str) -> Result<Option<QueryParameter>> {
+        trace!("get_record_parameter({})", param_name);
+        Ok(Some(QueryParameter {
+            name: Some(param_name.to_string()),
+            parameter_type: Some(record_parameter_type::<T>()?),
+            parameter_value: Some(record_parameter_value(self)?),
+        }))
+    }
+}
+
+/// The STRUCT type of a record, with the members sorted by db name.
+fn record_parameter_type<T: BigQueryColumns>() -> Result<QueryParameterType> {
+    let column_types = T::get_column_types();
+    let mut columns: Vec<(String, String)> = T::get_column_fields().into_iter().collect();
+    columns.sort_by(|a, b| a.1.cmp(&b.1));
+    let mut struct_types = vec![];
+    for (field_name, db_name) in columns {
+        let column_type = column_types
+            .get(&field_name)
+            .cloned()
+            .ok_or_else(|| anyhow!("no type for column {}", field_name))?;
+        struct_types.push(QueryParameterTypeStructTypes {
+            name: Some(db_name),
+            type_: Some(QueryParameterType {
+                type_: Some(column_type),
+                ..Default::default()
+            }),
+            description: None,
+        });
+    }
+    Ok(QueryParameterType {
+        type_: Some(String::from("STRUCT")),
+        struct_types: Some(struct_types),
+        ..Default::default()
+    })
+}
+
+fn record_parameter_value<T: BigQueryColumns>(record: &T) -> Result<QueryParameterValue> {
+    let mut struct_values = HashMap::new();
+    for (field_name, db_name) in T::get_column_fields() {
+        // a member without a value is NULL
+        let value = record
+            .get_column_parameter(&field_name, &db_name)?
+            .and_then(|param| param.parameter_value)
+            .unwrap_or_default();
+        struct_values.insert(db_name, value);
+    }
+    Ok(QueryParameterValue {
+        struct_values: Some(struct_values),
+        ..Default::default()
+    })
+}
+
+impl<T: BigQueryColumns> BigQueryRecord for Option<T> {
+    fn get_record_columns() -> Vec<String> {
+        T::get_record_columns()
+    }
+
+    fn get_record_schema() -> TableFieldSchema {
+        T::get_record_schema()
+    }
+
+    fn to_record_json(&self) -> Result<Value> {
+        match self {
+            Some(record) => record.to_record_json(),
+            None => Ok(Value::Null),
+        }
+    }
+
+    fn from_record_json(value: &Value) -> Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            _ => Ok(Some(T::from_record_json(value)?)),
+        }
+    }
+
+    fn get_record_parameter(&self, param_name: &str) -> Result<Option<QueryParameter>> {
+        match self {
+            Some(record) => record.get_record_parameter(param_name),
+            // null values are written as NULL in the query instead of as a parameter
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T: BigQueryColumns> BigQueryRecord for Vec<T> {
+    fn get_record_columns() -> Vec<String> {
+        T::get_record_columns()
+    }
+
+    fn get_record_schema() -> TableFieldSchema {
+        TableFieldSchema {
+            mode: Some(String::from("REPEATED")),
+            ..T::get_record_schema()
+        }
+    }
+
+    fn to_record_json(&self) -> Result<Value> {
+        let records = self
+            .iter()
+            .map(|record| record.to_record_json())
+            .collect::<Result<Vec<Value>>>()?;
+        Ok(Value::Array(records))
+    }
+
+    fn from_record_json(value: &Value) -> Result<Self> {
+        match value {
+            // BigQuery does not store NULL arrays, but they can be selected
+            Value::Null => Ok(vec![]),
+            Value::Array(records) => records.iter().map(T::from_record_json).collect(),
+            _ => Err(anyhow!("expected an array of records but got {}", value)),
+        }
+    }
+
+    fn get_record_parameter(&self, param_name: &str) -> Result<Option<QueryParameter>> {
+        trace!(
+            "get_record_parameter({}) for {} records",
+            param_name,
+            self.len()
+        );
+        let array_values = self
+            .iter()
+            .map(record_parameter_value)
+            .collect::<Result<Vec<QueryParameterValue>>>()?;
+        Ok(Some(QueryParameter {
+            name: Some(param_name.to_string()),
+            parameter_type: Some(QueryParameterType {
+                type_: Some(String::from("ARRAY")),
+                array_type: Some(Some(Box::new(record_parameter_type::<T>()?))),
+                ..Default::default()
+            }),
+            parameter_value: Some(QueryParameterValue {
+                array_values: Some(array_values),
+                ..Default::default()
+            }),
+        }))
+    }
+}
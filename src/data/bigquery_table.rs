@@ -1,21 +1,31 @@
 use std::collections::HashMap;
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
 
 use crate::prelude::*;
 use async_trait::async_trait;
 pub use google_bigquery2::api::QueryParameter;
+use google_bigquery2::api::{
+    Clustering, Job, JobConfiguration, JobConfigurationExtract, JobConfigurationLoad, QueryRequest,
+    QueryResponse, Table, TimePartitioning,
+};
 pub use google_bigquery2::api::{QueryParameterType, QueryParameterValue};
+pub use google_bigquery2::api::{TableFieldSchema, TableSchema};
 use serde_json::Value;
 
 use crate::client::BigqueryClient;
+use crate::data::jobs::{
+    run_job_to_completion, table_reference, ExtractFormat, LoadFormat, LoadJobStats,
+};
 use crate::data::param_conversion::{
     convert_value_to_string, BigDataValueType, ConvertBigQueryParams,
 };
 use crate::data::query_builder::{
-    NoClient, NoStartingData, QueryBuilder, QueryResultType, QueryTypeDelete, QueryTypeInsert,
-    QueryTypeNoType, QueryTypeSelect, QueryTypeUpdate, QueryWasNotBuilt,
+    run_query_with_client, BigqueryError, HasClient, NoClient, NoStartingData, QueryBuilder,
+    QueryResultType, QueryTypeDelete, QueryTypeInsert, QueryTypeNoType, QueryTypeSelect,
+    QueryTypeUpdate, QueryWasNotBuilt,
 };
-use crate::prelude::*;
+use crate::data::raw_query::{rows_from_query_response_or_positional, run_raw};
 
 #[async_trait]
 pub trait BigQueryTableBase {
@@ -24,6 +34,18 @@ pub trait BigQueryTableBase {
     //region get infos
     /// Returns the name of the table in the database.
     fn get_table_name() -> String;
+    /// Returns the dataset set with `#[db_dataset]`, if any.
+    ///
+    /// Tables without it live in the dataset of the client.
+    fn get_dataset_name() -> Option<String>;
+    /// Returns the limit set with `#[db_default_limit]`, if any.
+    ///
+    /// Selects start with this limit, see
+    /// [`set_limit`](crate::data::query_builder::QueryBuilder::set_limit) and
+    /// [`without_limit`](crate::data::query_builder::QueryBuilder::without_limit).
+    /// The lookups of this trait by primary key, like `get_by_pk` and
+    /// `reload_all`, ignore it.
+    fn get_default_limit() -> Option<u32>;
     /// Returns the bigquery-client for the struct.
     fn get_client(&self) -> &BigqueryClient;
     /// Sets the bigquery-client for the struct.
@@ -36,6 +58,41 @@ pub trait BigQueryTableBase {
     fn get_pk_value(&self) -> &(dyn BigDataValueType + Send + Sync);
     /// Returns a HashMap with the field name as key and the db name as value.
     fn get_query_fields(include_pk: bool) -> HashMap<String, String>;
+    /// Returns the names of the fields marked with `#[db_sensitive]`.
+    ///
+    /// Parameters for these fields are redacted when logged.
+    fn get_sensitive_fields() -> Vec<String>;
+    /// Returns the names of the fields whose value is undefined (see [`crate::data::param_conversion::Patch`]).
+    ///
+    /// Update queries do not write these fields.
+    fn get_undefined_fields(&self) -> Vec<String>;
+    /// Returns a HashMap with the field name as key and the BigQuery type as value.
+    fn get_field_types() -> HashMap<String, String>;
+    /// Returns the names of the fields marked with `#[required]`.
+    fn get_required_fields() -> Vec<String>;
+    /// Returns the db names of the columns of every `#[db_record]` field.
+    fn get_record_columns() -> HashMap<String, Vec<String>>;
+    /// Returns the schema of every `#[db_record]` field, see
+    /// [`BigQueryRecord::get_record_schema`](crate::data::BigQueryRecord::get_record_schema).
+    fn get_record_schemas() -> HashMap<String, TableFieldSchema>;
+    /// Returns the `#[db_expr]` fields with their SQL expression.
+    ///
+    /// These fields are computed by the select and never written.
+    fn get_field_exprs() -> HashMap<String, String>;
+    /// Returns the column set with `#[db_partition_by]`, if any.
+    ///
+    /// The table is partitioned by day of this column.
+    fn get_partition_by() -> Option<String>;
+    /// Returns the columns set with `#[db_cluster_by]`.
+    fn get_cluster_by() -> Vec<String>;
+    /// Returns the column set with `#[db_require_partition_filter]`, if any.
+    ///
+    /// Selects without a where clause on this column fail to build.
+    fn get_required_partition_filter() -> Option<String>;
+    /// Returns the name of the field marked with `#[db_version]`, if any.
+    ///
+    /// This field is used for optimistic locking when saving.
+    fn get_version_field_name() -> Option<String>;
     async fn reload(&mut self) -> Result<()>;
     //endregion
 
@@ -74,19 +131,19 @@ pub trait BigQueryTable: BigQueryTableBase {
     }
     fn insert() -> QueryBuilder<Self, QueryTypeInsert, NoClient, QueryWasNotBuilt, NoStartingData>
     where
-        Self: Sized,
+        Self: Sized + BigQueryWritable,
     {
         QueryBuilder::<Self, QueryTypeNoType, NoClient, QueryWasNotBuilt, NoStartingData>::insert()
     }
     fn update() -> QueryBuilder<Self, QueryTypeUpdate, NoClient, QueryWasNotBuilt, NoStartingData>
     where
-        Self: Sized,
+        Self: Sized + BigQueryWritable,
     {
         QueryBuilder::<Self, QueryTypeNoType, NoClient, QueryWasNotBuilt, NoStartingData>::update()
     }
     fn delete() -> QueryBuilder<Self, QueryTypeDelete, NoClient, QueryWasNotBuilt, NoStartingData>
     where
-        Self: Sized,
+        Self: Sized + BigQueryWritable,
     {
         QueryBuilder::<Self, QueryTypeNoType, NoClient, QueryWasNotBuilt, NoStartingData>::delete()
     }
@@ -94,32 +151,22 @@ pub trait BigQueryTable: BigQueryTableBase {
     where
         T: BigDataValueType + Debug,
     {
-        trace!("get_parameter({:?}, {})", value, param_name);
-        let value = value.to_param();
-        let param_type = T::convert_type_to_bigquery_type();
-        let param_type = QueryParameterType {
-            type_: Some(param_type),
-            ..Default::default()
-        };
-        debug!("param_type: {:?}", param_type);
-        debug!("param_value: {:?}", value);
-        let param_value = convert_value_to_string(value);
-        debug!("param_value: {:?}", param_value);
-        let param_value = match param_value {
-            Ok(param_value) => Some(QueryParameterValue {
-                value: Some(param_value),
-                ..Default::default()
-            }),
-            Err(_) => return None,
-        };
-        debug!("param_value: {:?}", param_value);
-
-        let param = QueryParameter {
-            parameter_type: Some(param_type),
-            parameter_value: param_value,
-            name: Some(param_name.clone()),
-        };
-        Some(param)
+        trace!("get_parameter({})", param_name);
+        let param = param(param_name, value);
+        // null values are written as NULL in the query instead of as a parameter
+        param.parameter_value.is_some().then_some(param)
+    }
+    /// Like [`get_parameter`](BigQueryTable::get_parameter), but an empty
+    /// string is written as NULL too. Used for `#[db_empty_as_null]` fields.
+    fn get_parameter_empty_as_null<T>(value: &T, param_name: &String) -> Option<QueryParameter>
+    where
+        T: BigDataValueType + Debug,
+    {
+        trace!("get_parameter_empty_as_null({})", param_name);
+        match value.to_param() {
+            Value::String(s) if s.is_empty() => None,
+            _ => Self::get_parameter(value, param_name),
+        }
     }
     fn get_field_param_name(field_name: &str) -> Result<String> {
         trace!("get_field_param_name({})", field_name);
@@ -131,7 +178,7 @@ pub trait BigQueryTable: BigQueryTableBase {
         let query_fields = Self::get_query_fields(true);
         let db_name = query_fields.get(field_name);
         match db_name {
-            None => Err(anyhow!("Field {} not found.", field_name).into()),
+            None => Err(anyhow!("Field {} not found.", field_name)),
             Some(s) => Ok(s.to_string()),
         }
     }
@@ -143,58 +190,467 @@ pub trait BigQueryTable: BigQueryTableBase {
 
     fn get_table_identifier_from_client(client: &BigqueryClient) -> String {
         trace!("get_table_identifier_from_client({:?})", client);
+        Self::get_table_identifier_with_name(client, &Self::get_table_name())
+    }
+    /// Like [`get_table_identifier_from_client`](BigQueryTable::get_table_identifier_from_client),
+    /// but for another table with the same columns.
+    fn get_table_identifier_with_name(client: &BigqueryClient, table_name: &str) -> String {
         format!(
             "`{}.{}.{}`",
             client.get_project_id(),
-            client.get_dataset_id(),
-            Self::get_table_name()
+            Self::get_dataset_id_from_client(client),
+            table_name
         )
     }
 
+    /// Returns the dataset of the table: the one set with `#[db_dataset]`,
+    /// or the dataset of the client.
+    fn get_dataset_id_from_client(client: &BigqueryClient) -> String {
+        Self::get_dataset_name().unwrap_or_else(|| client.get_dataset_id().to_string())
+    }
+
+    /// Returns the BigQuery schema of the table, derived from the field types.
+    ///
+    /// The columns are ordered by field name, like in select queries.
+    fn get_table_schema() -> TableSchema {
+        trace!("get_table_schema()");
+        let field_types = Self::get_field_types();
+        let required_fields = Self::get_required_fields();
+        let exprs = Self::get_field_exprs();
+        let mut record_schemas = Self::get_record_schemas();
+        let mut fields: Vec<(String, String)> = Self::get_query_fields(true)
+            .into_iter()
+            .filter(|(field_name, _)| !exprs.contains_key(field_name))
+            .collect();
+        fields.sort();
+        let fields = fields
+            .into_iter()
+            .map(|(field_name, db_name)| {
+                let schema =
+                    record_schemas
+                        .remove(&field_name)
+                        .unwrap_or_else(|| TableFieldSchema {
+                            type_: field_types.get(&field_name).cloned(),
+                            mode: Some(String::from("NULLABLE")),
+                            ..Default::default()
+                        });
+                // a repeated column can not be required
+                let required = required_fields.contains(&field_name)
+                    && schema.mode.as_deref() != Some("REPEATED");
+                TableFieldSchema {
+                    name: Some(db_name),
+                    mode: match required {
+                        true => Some(String::from("REQUIRED")),
+                        false => schema.mode.clone(),
+                    },
+                    ..schema
+                }
+            })
+            .collect();
+        TableSchema {
+            fields: Some(fields),
+        }
+    }
+
+    /// Returns the `CREATE TABLE` statement for the table, with the
+    /// `PARTITION BY` and `CLUSTER BY` clauses of `#[db_partition_by]` and
+    /// `#[db_cluster_by]`.
+    ///
+    /// Fails if the partition column is not a DATE, DATETIME or TIMESTAMP.
+    fn get_create_table_ddl(client: &BigqueryClient) -> Result<String> {
+        trace!("get_create_table_ddl({:?})", client);
+        let fields = Self::get_table_schema().fields.unwrap_or_default();
+        let columns: Vec<String> = fields
+            .iter()
+            .map(|field| {
+                let mut column = format!(
+                    "{} {}",
+                    field.name.as_deref().unwrap_or_default(),
+                    get_ddl_column_type(field)
+                );
+                if field.mode.as_deref() == Some("REQUIRED") {
+                    column.push_str(" NOT NULL");
+                }
+                column
+            })
+            .collect();
+        let mut ddl = format!(
+            "CREATE TABLE {} ({})",
+            Self::get_table_identifier_from_client(client),
+            columns.join(", ")
+        );
+        if let Some((partition_by, column_type)) = get_partition_column::<Self>()? {
+            match column_type.as_str() {
+                "DATE" => ddl.push_str(&format!(" PARTITION BY {}", partition_by)),
+                _ => ddl.push_str(&format!(" PARTITION BY DATE({})", partition_by)),
+            }
+        }
+        let cluster_by = Self::get_cluster_by();
+        if !cluster_by.is_empty() {
+            ddl.push_str(&format!(" CLUSTER BY {}", cluster_by.join(", ")));
+        }
+        Ok(ddl)
+    }
+
+    /// Returns the schema as the JSON array that `bq load --schema` and
+    /// Terraform's `google_bigquery_table` take, e.g.
+    /// `[{"name": "id", "type": "INT64", "mode": "REQUIRED"}]`.
+    fn json_schema() -> Value {
+        trace!("json_schema()");
+        let fields = Self::get_table_schema()
+            .fields
+            .unwrap_or_default()
+            .iter()
+            .map(get_json_field_schema)
+            .collect();
+        Value::Array(fields)
+    }
+
+    /// Loads the files at `gcs_uri` (wildcards are allowed) into the table and
+    /// waits for the load job to finish.
+    async fn load_from_gcs(
+        client: BigqueryClient,
+        gcs_uri: &str,
+        format: LoadFormat,
+    ) -> Result<LoadJobStats>
+    where
+        Self: BigQueryWritable,
+    {
+        trace!("load_from_gcs({:?}, {}, {:?})", client, gcs_uri, format);
+        let load = JobConfigurationLoad {
+            destination_table: Some(table_reference(
+                &client,
+                Self::get_dataset_id_from_client(&client),
+                Self::get_table_name(),
+            )),
+            source_uris: Some(vec![gcs_uri.to_string()]),
+            source_format: Some(format.source_format()),
+            skip_leading_rows: format.skip_leading_rows(),
+            schema: Some(Self::get_table_schema()),
+            write_disposition: Some(String::from("WRITE_APPEND")),
+            ..Default::default()
+        };
+        let job = Job {
+            configuration: Some(JobConfiguration {
+                load: Some(load),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let job = run_job_to_completion(&client, job).await?;
+        let stats = LoadJobStats::from_job(&job);
+        debug!("load_from_gcs stats: {:?}", stats);
+        Ok(stats)
+    }
+
+    /// Exports the table to `gcs_uri` and waits for the extract job to finish.
+    ///
+    /// Use a wildcard in the uri (e.g. `gs://bucket/export-*.json`) for tables
+    /// larger than 1 GB.
+    async fn extract_to_gcs(
+        client: BigqueryClient,
+        gcs_uri: &str,
+        format: ExtractFormat,
+    ) -> Result<()> {
+        trace!("extract_to_gcs({:?}, {}, {:?})", client, gcs_uri, format);
+        let extract = JobConfigurationExtract {
+            source_table: Some(table_reference(
+                &client,
+                Self::get_dataset_id_from_client(&client),
+                Self::get_table_name(),
+            )),
+            destination_uris: Some(vec![gcs_uri.to_string()]),
+            destination_format: Some(format.destination_format()),
+            ..Default::default()
+        };
+        let job = Job {
+            configuration: Some(JobConfiguration {
+                extract: Some(extract),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        run_job_to_completion(&client, job).await?;
+        Ok(())
+    }
+
+    /// Removes all rows from the table with `TRUNCATE TABLE`.
+    ///
+    /// This is cheaper than a `DELETE` without a where clause, but it can not
+    /// be undone.
+    async fn truncate(client: BigqueryClient) -> Result<()>
+    where
+        Self: BigQueryWritable,
+    {
+        trace!("truncate({:?})", client);
+        if client.get_project_id().is_empty()
+            || Self::get_dataset_id_from_client(&client).is_empty()
+        {
+            return Err(anyhow!(
+                "can not truncate {}: the client has no project or dataset id",
+                Self::get_table_name()
+            ));
+        }
+        let query = format!(
+            "TRUNCATE TABLE {}",
+            Self::get_table_identifier_from_client(&client)
+        );
+        debug!("truncate query: {}", query);
+        let query_request = QueryRequest {
+            query: Some(query),
+            use_legacy_sql: Some(false),
+            ..Default::default()
+        };
+        run_query_with_client(&client, query_request).await?;
+        Ok(())
+    }
+
+    /// Creates the table with the schema from
+    /// [`get_table_schema`](BigQueryTable::get_table_schema), partitioned and
+    /// clustered like [`get_create_table_ddl`](BigQueryTable::get_create_table_ddl).
+    ///
+    /// Fails if the table already exists.
+    async fn create_table(client: BigqueryClient) -> Result<()>
+    where
+        Self: BigQueryWritable,
+    {
+        trace!("create_table({:?})", client);
+        create_table_with_name::<Self>(&client, &Self::get_table_name()).await
+    }
+
+    /// Fetches the metadata of the table to check that the client can read
+    /// it, before running a query that would fail midway.
+    ///
+    /// Fails with a [`PermissionDeniedError`] if BigQuery answers with 403.
+    async fn check_access(client: &BigqueryClient) -> Result<()> {
+        trace!("check_access({:?})", client);
+        let dataset_id = Self::get_dataset_id_from_client(client);
+        let table_name = Self::get_table_name();
+        let result = client
+            .get_client()
+            .tables()
+            .get(client.get_project_id(), &dataset_id, &table_name)
+            .doit()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => match BigqueryError::from_api_error(&error) {
+                Some(bigquery_error) if bigquery_error.status == Some(403) => {
+                    Err(PermissionDeniedError::new(
+                        &Self::get_table_identifier_from_client(client),
+                        &bigquery_error.message,
+                    )
+                    .into())
+                }
+                _ => Err(error.into()),
+            },
+        }
+    }
+
     async fn get_by_pk<PK>(client: BigqueryClient, pk_value: &PK) -> Result<Self>
     where
         PK: BigDataValueType + Send + Sync + 'static,
         Self: Sized + Debug,
     {
         trace!("get_by_pk({:?}, {:?})", client, pk_value);
+        get_single_by_pk(Self::select().with_client(client), pk_value).await
+    }
+
+    /// Like [`get_by_pk`](BigQueryTable::get_by_pk), but only fetches the
+    /// columns of `fields` (and the primary key). The other fields keep their
+    /// default value.
+    async fn get_by_pk_fields<PK>(
+        client: BigqueryClient,
+        pk_value: &PK,
+        fields: &[&str],
+    ) -> Result<Self>
+    where
+        PK: BigDataValueType + Send + Sync + 'static,
+        Self: Sized + Debug + Default,
+    {
+        trace!(
+            "get_by_pk_fields({:?}, {:?}, {:?})",
+            client,
+            pk_value,
+            fields
+        );
+        let query = Self::select().with_client(client).select_fields(fields)?;
+        get_single_by_pk(query, pk_value).await
+    }
+
+    /// Returns the rows with these primary keys, see
+    /// [`get_by_pks_chunked`](BigQueryTable::get_by_pks_chunked).
+    async fn get_by_pks<PK>(client: BigqueryClient, pk_values: &[PK]) -> Result<Vec<Self>>
+    where
+        PK: BigDataValueType + Clone + Send + Sync + 'static,
+        Self: Sized + Debug + Send,
+    {
+        Self::get_by_pks_chunked(client, pk_values, DEFAULT_IN_CHUNK_SIZE).await
+    }
+
+    /// Returns the rows with these primary keys, with one query per
+    /// `chunk_size` keys, since BigQuery limits the size of a query.
+    ///
+    /// The order of the rows is not guaranteed, neither within nor across
+    /// chunks. Keys without a row are skipped.
+    async fn get_by_pks_chunked<PK>(
+        client: BigqueryClient,
+        pk_values: &[PK],
+        chunk_size: usize,
+    ) -> Result<Vec<Self>>
+    where
+        PK: BigDataValueType + Clone + Send + Sync + 'static,
+        Self: Sized + Debug + Send,
+    {
+        trace!(
+            "get_by_pks_chunked({:?}, {} pks, {})",
+            client,
+            pk_values.len(),
+            chunk_size
+        );
+        if chunk_size == 0 {
+            return Err(anyhow!("the chunk size must be greater than 0"));
+        }
+        check_pk_type::<Self, PK>()?;
         let pk_field_name = Self::get_pk_field_name();
-        let pk_db_name = Self::get_pk_db_name();
-        let result = Self::select()
+        let mut result = vec![];
+        for chunk in pk_values.chunks(chunk_size) {
+            let values: Vec<Option<PK>> = chunk.iter().cloned().map(Some).collect();
+            let rows = Self::select()
+                .with_client(client.clone())
+                .without_limit()
+                .add_where_in(&pk_field_name, &values)?
+                .build_query()?
+                .run()
+                .await?
+                .map_err_with_data("select should return data")?;
+            result.extend(rows);
+        }
+        debug!("get_by_pks_chunked: {} rows", result.len());
+        Ok(result)
+    }
+
+    /// Reloads all entities with one query instead of one [`reload`] per
+    /// entity, matching the rows by primary key.
+    ///
+    /// Returns the indices of the entities whose primary key was not found;
+    /// these are left unchanged.
+    ///
+    /// [`reload`]: BigQueryTableBase::reload
+    async fn reload_all(client: BigqueryClient, entities: &mut [Self]) -> Result<Vec<usize>>
+    where
+        Self: Sized + Clone + Debug + Send,
+    {
+        trace!("reload_all({:?}, {} entities)", client, entities.len());
+        if entities.is_empty() {
+            return Ok(vec![]);
+        }
+        let pk_field_name = Self::get_pk_field_name();
+        let rows = Self::select()
             .with_client(client)
-            .add_where_eq(&pk_field_name, Some(pk_value))?
+            .without_limit()
+            .add_where_pk_in(entities)?
             .build_query()?
             .run()
-            .await?;
-        let mut rows = match result {
-            QueryResultType::WithRowData(data) => data,
-            QueryResultType::WithoutRowData(success) => {
-                return Err(anyhow!(
-                    "something went wrong when getting for {} = {:?};\tresult: {:?}",
-                    pk_field_name,
-                    pk_value,
-                    success
-                )
-                .into());
+            .await?
+            .map_err_with_data("select should return data")?;
+        let mut rows_by_pk = HashMap::new();
+        for row in rows {
+            rows_by_pk.insert(row.get_field_value(&pk_field_name)?.to_string(), row);
+        }
+        let mut missing = vec![];
+        for (i, entity) in entities.iter_mut().enumerate() {
+            let pk = entity.get_field_value(&pk_field_name)?.to_string();
+            match rows_by_pk.get(&pk) {
+                Some(row) => *entity = row.clone(),
+                None => missing.push(i),
             }
-        };
+        }
+        debug!("reload_all: {} entities not found", missing.len());
+        Ok(missing)
+    }
 
-        if rows.len() == 0 {
-            Err(anyhow!("No entry found for {} = {:?}", pk_db_name, pk_value).into())
-        } else if rows.len() > 1 {
-            Err(anyhow!(
-                "More than one entry found for {} = {:?}",
-                pk_db_name,
-                pk_value
-            )
-            .into())
-        } else {
-            Ok(rows.remove(0))
+    /// Compares every field with the row in the database that has the same
+    /// primary key, and returns the fields that differ.
+    ///
+    /// If there is no such row, every field is returned as new, with no old
+    /// value.
+    async fn diff_with_remote(&self, client: BigqueryClient) -> Result<Vec<FieldChange>>
+    where
+        Self: Sized + Clone + Debug + Send + Sync,
+    {
+        trace!("diff_with_remote({})", self.compact_debug());
+        let remote = Self::select()
+            .with_client(client)
+            .without_limit()
+            .add_where_pk_in(std::slice::from_ref(self))?
+            .build_query()?
+            .run()
+            .await?
+            .map_err_with_data("select should return data")?
+            .into_iter()
+            .next();
+        let changes = self.diff_with(remote.as_ref())?;
+        debug!("diff_with_remote: {} changes", changes.len());
+        Ok(changes)
+    }
+
+    /// Returns the fields whose values differ from `old`, sorted by field
+    /// name. If `old` is `None`, every field is returned.
+    fn diff_with(&self, old: Option<&Self>) -> Result<Vec<FieldChange>>
+    where
+        Self: Sized,
+    {
+        let mut fields: Vec<(String, String)> = Self::get_query_fields(true).into_iter().collect();
+        fields.sort();
+        let mut changes = vec![];
+        for (field_name, db_name) in fields {
+            let new_value = self.get_field_value(&field_name)?;
+            let old_value = match old {
+                Some(old) => Some(old.get_field_value(&field_name)?),
+                None => None,
+            };
+            if old_value.as_ref() != Some(&new_value) {
+                changes.push(FieldChange {
+                    field_name,
+                    db_name,
+                    old_value,
+                    new_value,
+                });
+            }
         }
+        Ok(changes)
+    }
+
+    /// Returns the primary keys that are used by more than one row.
+    ///
+    /// BigQuery does not enforce unique primary keys, so this can be used
+    /// to audit a table.
+    async fn find_duplicate_pks<PK>(client: BigqueryClient) -> Result<Vec<PK>>
+    where
+        PK: BigDataValueType + Send + Sync + 'static,
+    {
+        trace!("find_duplicate_pks({:?})", client);
+        check_pk_type::<Self, PK>()?;
+        let pk_db_name = Self::get_pk_db_name();
+        let query = format!(
+            "SELECT {pk} FROM {} GROUP BY {pk} HAVING COUNT(*) > 1",
+            Self::get_table_identifier_from_client(&client),
+            pk = pk_db_name
+        );
+        let rows = run_raw(&client, &query, vec![]).await?;
+        debug!("find_duplicate_pks: {} duplicates", rows.len());
+        rows.iter()
+            .map(|row| {
+                let value = row.get(&pk_db_name).unwrap_or(&Value::Null);
+                PK::from_param(value)
+            })
+            .collect()
     }
 
     async fn upsert(&mut self) -> Result<()>
     where
-        Self: Sized + Clone + Send + Sync + Debug + Default,
+        Self: Sized + Clone + Send + Sync + Debug + Default + BigQueryWritable,
     {
         trace!("upsert()");
 
@@ -202,7 +658,7 @@ pub trait BigQueryTable: BigQueryTableBase {
         match exists {
             Ok(_) => {
                 debug!("Updating entry on db.");
-                self.save().await
+                self.save_versioned().await
             }
             Err(_) => {
                 debug!("Inserting new entry.");
@@ -217,31 +673,191 @@ pub trait BigQueryTable: BigQueryTableBase {
         }
     }
 
+    /// Like [`upsert`](BigQueryTable::upsert), but checks for an existing entry
+    /// by the value of `field_name` instead of the primary key.
+    ///
+    /// If an entry exists, it is updated without touching its primary key.
+    async fn upsert_on(&mut self, field_name: &str) -> Result<()>
+    where
+        Self: Sized + Clone + Send + Sync + Debug + Default + BigQueryWritable,
+    {
+        trace!("upsert_on({})", field_name);
+        Self::get_field_db_name(field_name)?;
+
+        let existing = Self::select()
+            .with_client(self.get_client().clone())
+            .without_limit()
+            .set_data(self.clone())
+            .add_field_where(field_name)?
+            .build_query()?
+            .run()
+            .await?
+            .map_err_with_data("upsert_on existence check should return data.")?;
+        if existing.is_empty() {
+            debug!("Inserting new entry.");
+            Self::insert()
+                .with_client(self.get_client().clone())
+                .set_data(self.clone())
+                .build_query()?
+                .run()
+                .await?
+                .map_err_without_data("upsert_on should not return data.")
+        } else {
+            debug!("Updating {} existing entries on db.", existing.len());
+            Self::update()
+                .with_client(self.get_client().clone())
+                .set_data(self.clone())
+                .update_matching()
+                .add_field_where(field_name)?
+                .build_query()?
+                .run()
+                .await?
+                .map_err_without_data("upsert_on should not return data.")
+        }
+    }
+
     /// proxy for update
+    ///
+    /// If the struct has a `#[db_version]` field, the update only applies if
+    /// the version in the database still matches, and the version in the
+    /// database is incremented. Otherwise a [`ConflictError`] is returned.
+    /// The version of `self` stays the same, use
+    /// [`save_versioned`](BigQueryTable::save_versioned) to save the same
+    /// instance more than once.
     async fn save(&self) -> Result<()>
     where
-        Self: Sized + Clone + Send + Sync + Debug + Default,
+        Self: Sized + Clone + Send + Sync + Debug + Default + BigQueryWritable,
     {
-        trace!("save(): {:?}", self);
-        let result = Self::update()
+        trace!("save(): {}", self.compact_debug());
+        let affected_rows = Self::update()
             .with_client(self.get_client().clone())
             .set_data(self.clone())
             .build_query()?
-            .run()
+            .run_dml()
             .await?;
-        trace!("save() result: {:?}", result);
-        let count = result
-            .expect_with_data("save should return empty data.")
-            .len();
-        if count == 0 {
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "save should return empty data, but returned {} rows.",
-                count
-            )
-            .into())
+        trace!("save() affected rows: {}", affected_rows);
+        if Self::get_version_field_name().is_some() && affected_rows == 0 {
+            return Err(ConflictError {
+                table_name: Self::get_table_name(),
+                pk_value: format!("{:?}", self.get_pk_value()),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Like [`save`](BigQueryTable::save), but also increments the
+    /// `#[db_version]` field of `self` to the version now in the database, so
+    /// the instance can be saved again without a [`ConflictError`].
+    async fn save_versioned(&mut self) -> Result<()>
+    where
+        Self: Sized + Clone + Send + Sync + Debug + Default + BigQueryWritable,
+    {
+        trace!("save_versioned(): {}", self.compact_debug());
+        self.save().await?;
+        if let Some(version_field) = Self::get_version_field_name() {
+            let version = match self.get_field_value(&version_field)? {
+                Value::Null => 0,
+                version => i64::from_param(&version)?,
+            };
+            self.set_field_value(&version_field, &Value::from(version + 1))?;
+        }
+        Ok(())
+    }
+
+    /// Creates an instance for every row of a query response. The cells are
+    /// mapped to the fields by the column names of the response schema, so
+    /// the order of the selected columns does not matter.
+    ///
+    /// If the response has no schema, the cells are mapped by position in
+    /// the column order of a select of all fields.
+    fn new_from_query_response(
+        client: BigqueryClient,
+        query_response: QueryResponse,
+    ) -> Result<Vec<Self>>
+    where
+        Self: Sized,
+    {
+        trace!("new_from_query_response({:?})", client);
+        let mut fields: Vec<(String, String)> = Self::get_query_fields(true).into_iter().collect();
+        fields.sort();
+        let columns: Vec<String> = fields.into_iter().map(|(_, db_name)| db_name).collect();
+        let mut result = vec![];
+        for row in rows_from_query_response_or_positional(query_response, &columns)? {
+            result.push(Self::new_from_query_result_row(client.clone(), &row)?);
+        }
+        Ok(result)
+    }
+
+    /// A short description for logs, like `Infos(Id = 7)`.
+    ///
+    /// The generated trace logs use this instead of the full [`Debug`] output,
+    /// which would print every field and the client on every call.
+    fn compact_debug(&self) -> String {
+        format!(
+            "{}({} = {:?})",
+            Self::get_table_name(),
+            Self::get_pk_db_name(),
+            self.get_pk_value()
+        )
+    }
+
+    /// Returns the instance as a JSON object with the db names as keys.
+    fn to_json(&self) -> Result<Value> {
+        let mut map = serde_json::Map::new();
+        for (field_name, db_name) in Self::get_query_fields(true) {
+            map.insert(db_name, self.get_field_value(&field_name)?);
+        }
+        Ok(Value::Object(map))
+    }
+
+    /// Creates an instance from a JSON object with the db names as keys, the
+    /// counterpart of [`to_json`](BigQueryTable::to_json).
+    ///
+    /// Missing keys keep their default value, unknown keys are an error.
+    fn from_db_json(client: BigqueryClient, obj: &serde_json::Map<String, Value>) -> Result<Self>
+    where
+        Self: Sized + Default,
+    {
+        trace!("from_db_json({:?}, {:?})", client, obj);
+        let field_names: HashMap<String, String> = Self::get_query_fields(true)
+            .into_iter()
+            .map(|(field_name, db_name)| (db_name, field_name))
+            .collect();
+        let mut result = Self::default();
+        result.set_client(client);
+        for (db_name, value) in obj {
+            let field_name = field_names.get(db_name).ok_or_else(|| {
+                anyhow!("column {} not found in {}", db_name, Self::get_table_name())
+            })?;
+            result.set_field_value(field_name, value)?;
+        }
+        Ok(result)
+    }
+
+    /// Like [`new_from_query_result_row`](BigQueryTableBase::new_from_query_result_row),
+    /// but columns that are missing from the row (because they were not
+    /// selected) keep their default value instead of failing.
+    fn new_from_partial_query_result_row(
+        client: BigqueryClient,
+        row: &HashMap<String, Value>,
+    ) -> Result<Self>
+    where
+        Self: Sized + Default,
+    {
+        trace!(
+            "new_from_partial_query_result_row({:?}, columns: {:?})",
+            client,
+            row.keys().collect::<Vec<_>>()
+        );
+        let mut result = Self::default();
+        result.set_client(client);
+        for (field_name, db_name) in Self::get_query_fields(true) {
+            if let Some(value) = row.get(&db_name) {
+                result.set_field_value(&field_name, value)?;
+            }
         }
+        Ok(result)
     }
 
     /// updates the current instance from another instance.
@@ -274,6 +890,390 @@ pub trait BigQueryTable: BigQueryTableBase {
 
 impl<T> BigQueryTable for T where T: BigQueryTableBase {}
 
+/// Marker for tables that can be written to.
+///
+/// The derive implements this for every struct that is not marked with
+/// `#[db_view]`, so writing to a view does not compile.
+pub trait BigQueryWritable {}
+
+/// The number of values per query of
+/// [`get_by_pks`](BigQueryTable::get_by_pks).
+pub const DEFAULT_IN_CHUNK_SIZE: usize = 1000;
+
+//region FieldChange
+/// A field whose local value differs from the database, returned by
+/// [`BigQueryTable::diff_with_remote`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field_name: String,
+    pub db_name: String,
+    /// the value in the database, `None` if the row does not exist
+    pub old_value: Option<Value>,
+    pub new_value: Value,
+}
+
+//endregion
+//region ConflictError
+/// Returned by [`BigQueryTable::save`] when the `#[db_version]` of the entry
+/// does not match the database anymore, because it was modified concurrently.
+#[derive(Debug)]
+pub struct ConflictError {
+    pub table_name: String,
+    pub pk_value: String,
+}
+
+impl Display for ConflictError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Conflict: the entry {} in {} was modified concurrently",
+            self.pk_value, self.table_name
+        )
+    }
+}
+
+impl Error for ConflictError {}
+
+//endregion
+//region PermissionDeniedError
+/// Returned by [`BigQueryTable::check_access`] when the caller may not read
+/// the table.
+#[derive(Debug)]
+pub struct PermissionDeniedError {
+    pub table_name: String,
+    /// the missing IAM permission, like `bigquery.tables.get`, if BigQuery
+    /// named it
+    pub permission: Option<String>,
+    pub message: String,
+}
+
+impl PermissionDeniedError {
+    /// Extracts the permission from messages like
+    /// `Permission bigquery.tables.get denied on table ...`.
+    pub fn new(table_name: &str, message: &str) -> Self {
+        let permission = message
+            .split_once("Permission ")
+            .and_then(|(_, rest)| rest.split_once(" denied"))
+            .map(|(permission, _)| permission.to_string());
+        Self {
+            table_name: table_name.to_string(),
+            permission,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for PermissionDeniedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.permission {
+            Some(permission) => write!(
+                f,
+                "PermissionDenied: {} is missing on {}",
+                permission, self.table_name
+            ),
+            None => write!(
+                f,
+                "PermissionDenied: no access to {}: {}",
+                self.table_name, self.message
+            ),
+        }
+    }
+}
+
+impl Error for PermissionDeniedError {}
+
+//endregion
+
+/// Checks that `PK` has the BigQuery type of the primary key of `T`, since
+/// BigQuery would only reject the wrong type after the round trip.
+pub(crate) fn check_pk_type<T, PK>() -> Result<()>
+where
+    T: BigQueryTable + ?Sized,
+    PK: BigDataValueType,
+{
+    let pk_type = T::get_field_types().remove(&T::get_pk_field_name());
+    let value_type = PK::convert_type_to_bigquery_type();
+    if pk_type.as_ref() != Some(&value_type) {
+        return Err(anyhow!(
+            "Primary key {} of {} has type {:?}, but the requested type is {}",
+            T::get_pk_db_name(),
+            T::get_table_name(),
+            pk_type,
+            value_type
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the db name and type of the `#[db_partition_by]` column, if any.
+///
+/// Fails if the column does not exist or is not a DATE, DATETIME or
+/// TIMESTAMP, since BigQuery can only partition these by day.
+fn get_partition_column<T>() -> Result<Option<(String, String)>>
+where
+    T: BigQueryTable + ?Sized,
+{
+    let partition_by = match T::get_partition_by() {
+        Some(partition_by) => partition_by,
+        None => return Ok(None),
+    };
+    let column_type = T::get_table_schema()
+        .fields
+        .unwrap_or_default()
+        .into_iter()
+        .find(|field| field.name.as_ref() == Some(&partition_by))
+        .and_then(|field| field.type_)
+        .ok_or_else(|| {
+            anyhow!(
+                "partition column {} does not exist in {}",
+                partition_by,
+                T::get_table_name()
+            )
+        })?;
+    match column_type.as_str() {
+        "DATE" | "DATETIME" | "TIMESTAMP" => Ok(Some((partition_by, column_type))),
+        _ => Err(anyhow!(
+            "{} can not be partitioned by {}: the column has type {}, expected DATE, DATETIME or TIMESTAMP",
+            T::get_table_name(),
+            partition_by,
+            column_type
+        )),
+    }
+}
+
+/// Returns the type of the column in a `CREATE TABLE` statement, with
+/// `STRUCT<...>` for records and `ARRAY<...>` for repeated columns.
+fn get_ddl_column_type(field: &TableFieldSchema) -> String {
+    let column_type = match (field.type_.as_deref(), &field.fields) {
+        (Some("RECORD") | Some("STRUCT"), Some(fields)) => {
+            let members: Vec<String> = fields
+                .iter()
+                .map(|member| {
+                    format!(
+                        "{} {}",
+                        member.name.as_deref().unwrap_or_default(),
+                        get_ddl_column_type(member)
+                    )
+                })
+                .collect();
+            format!("STRUCT<{}>", members.join(", "))
+        }
+        (column_type, _) => column_type.unwrap_or_default().to_string(),
+    };
+    match field.mode.as_deref() {
+        Some("REPEATED") => format!("ARRAY<{}>", column_type),
+        _ => column_type,
+    }
+}
+
+/// Returns the field as an entry of [`BigQueryTable::json_schema`], with the
+/// sub-fields of records.
+fn get_json_field_schema(field: &TableFieldSchema) -> Value {
+    let mut json = serde_json::json!({
+        "name": field.name,
+        "type": field.type_,
+        "mode": field.mode,
+    });
+    if let Some(fields) = &field.fields {
+        json["fields"] = Value::Array(fields.iter().map(get_json_field_schema).collect());
+    }
+    json
+}
+
+/// Creates the table `table_name` with the schema of `T`, like
+/// [`BigQueryTable::create_table`] but for another table with the same columns.
+pub(crate) async fn create_table_with_name<T>(
+    client: &BigqueryClient,
+    table_name: &str,
+) -> Result<()>
+where
+    T: BigQueryTable + ?Sized,
+{
+    let project_id = client.get_project_id();
+    let dataset_id = T::get_dataset_id_from_client(client);
+    if project_id.is_empty() || dataset_id.is_empty() {
+        return Err(anyhow!(
+            "can not create {}: the client has no project or dataset id",
+            table_name
+        ));
+    }
+    let table = Table {
+        table_reference: Some(table_reference(
+            client,
+            dataset_id.clone(),
+            table_name.to_string(),
+        )),
+        schema: Some(T::get_table_schema()),
+        time_partitioning: get_partition_column::<T>()?.map(|(field, _)| TimePartitioning {
+            field: Some(field),
+            type_: Some(String::from("DAY")),
+            ..Default::default()
+        }),
+        clustering: match T::get_cluster_by() {
+            fields if fields.is_empty() => None,
+            fields => Some(Clustering {
+                fields: Some(fields),
+            }),
+        },
+        ..Default::default()
+    };
+    client
+        .get_client()
+        .tables()
+        .insert(table, project_id, &dataset_id)
+        .doit()
+        .await?;
+    info!("created table {}.{}.{}", project_id, dataset_id, table_name);
+    Ok(())
+}
+
+/// Runs the select for the row with the primary key and makes sure there is
+/// exactly one.
+async fn get_single_by_pk<T, PK>(
+    query: QueryBuilder<T, QueryTypeSelect, HasClient, QueryWasNotBuilt, NoStartingData>,
+    pk_value: &PK,
+) -> Result<T>
+where
+    T: BigQueryTable + Debug,
+    PK: BigDataValueType + Send + Sync + 'static,
+{
+    let pk_field_name = T::get_pk_field_name();
+    let pk_db_name = T::get_pk_db_name();
+    check_pk_type::<T, PK>()?;
+    // without the default limit, to see if there are duplicates
+    let result = query
+        .without_limit()
+        .add_where_eq(&pk_field_name, Some(pk_value))?
+        .build_query()?
+        .run()
+        .await?;
+    let mut rows = match result {
+        QueryResultType::WithRowData(data) => data,
+        QueryResultType::WithoutRowData(success) => {
+            return Err(anyhow!(
+                "something went wrong when getting for {} = {:?};\tresult: {:?}",
+                pk_field_name,
+                pk_value,
+                success
+            ));
+        }
+    };
+
+    if rows.is_empty() {
+        Err(anyhow!(
+            "No entry found for {} = {:?}",
+            pk_db_name,
+            pk_value
+        ))
+    } else if rows.len() > 1 {
+        Err(anyhow!(
+            "More than one entry found for {} = {:?}",
+            pk_db_name,
+            pk_value
+        ))
+    } else {
+        Ok(rows.remove(0))
+    }
+}
+
+/// Builds a named query parameter for the value, e.g. `param("min_age", &18i64)`.
+///
+/// The type is derived from `T`. If the value is null the parameter has no
+/// `parameter_value`.
+pub fn param<T>(name: &str, value: &T) -> QueryParameter
+where
+    T: BigDataValueType + Debug,
+{
+    trace!("param({})", name);
+    let param_type = QueryParameterType {
+        type_: Some(T::convert_type_to_bigquery_type()),
+        ..Default::default()
+    };
+    let parameter_value = value.to_param_string().map(|value| QueryParameterValue {
+        value: Some(value),
+        ..Default::default()
+    });
+    QueryParameter {
+        parameter_type: Some(param_type),
+        parameter_value,
+        name: Some(name.to_string()),
+    }
+}
+
+/// Builds the parameter of a `#[db_with]` field from its encoded value.
+///
+/// Returns `None` for NULL, like [`BigQueryTable::get_parameter`].
+pub fn encoded_param(
+    name: &str,
+    column_type: &str,
+    value: &Value,
+) -> Result<Option<QueryParameter>> {
+    trace!("encoded_param({}, {})", name, column_type);
+    if value.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(QueryParameter {
+        parameter_type: Some(QueryParameterType {
+            type_: Some(column_type.to_string()),
+            ..Default::default()
+        }),
+        parameter_value: Some(QueryParameterValue {
+            value: Some(convert_value_to_string(value.clone())?),
+            ..Default::default()
+        }),
+        name: Some(name.to_string()),
+    }))
+}
+
+/// Builds a named query parameter with a NULL value of the BigQuery type of
+/// `T`, e.g. `null_param::<i64>("limit")`.
+pub fn null_param<T>(name: &str) -> QueryParameter
+where
+    T: BigDataValueType,
+{
+    trace!("null_param({})", name);
+    QueryParameter {
+        parameter_type: Some(QueryParameterType {
+            type_: Some(T::convert_type_to_bigquery_type()),
+            ..Default::default()
+        }),
+        parameter_value: None,
+        name: Some(name.to_string()),
+    }
+}
+
+/// Creates an `ARRAY` query parameter with the given values, for use with
+/// `IN UNNEST(@name)`.
+pub fn array_param<T>(name: &str, values: &[T]) -> QueryParameter
+where
+    T: BigDataValueType + Debug,
+{
+    trace!("array_param({}, {} values)", name, values.len());
+    let param_type = QueryParameterType {
+        type_: Some(String::from("ARRAY")),
+        array_type: Some(Some(Box::new(QueryParameterType {
+            type_: Some(T::convert_type_to_bigquery_type()),
+            ..Default::default()
+        }))),
+        ..Default::default()
+    };
+    let array_values = values
+        .iter()
+        .map(|value| QueryParameterValue {
+            value: value.to_param_string(),
+            ..Default::default()
+        })
+        .collect();
+    QueryParameter {
+        parameter_type: Some(param_type),
+        parameter_value: Some(QueryParameterValue {
+            array_values: Some(array_values),
+            ..Default::default()
+        }),
+        name: Some(name.to_string()),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum OrderDirection {
     Ascending,
@@ -0,0 +1,28 @@
+use std::fmt::Debug;
+
+use crate::data::query_builder::{QueryBuilder, QueryWasNotBuilt};
+use crate::prelude::*;
+
+/// A named, reusable set of where clauses, applied with
+/// [`QueryBuilder::filter`].
+///
+/// ```ignore
+/// struct ActiveOnly;
+///
+/// impl Filter<DbUsers> for ActiveOnly {
+///     fn apply<QueryType: Debug, Client: Debug, StartingData: Debug>(
+///         self,
+///         builder: QueryBuilder<DbUsers, QueryType, Client, QueryWasNotBuilt, StartingData>,
+///     ) -> Result<QueryBuilder<DbUsers, QueryType, Client, QueryWasNotBuilt, StartingData>> {
+///         builder.add_where_eq(name_of!(active in DbUsers), Some(&true))
+///     }
+/// }
+///
+/// let users = DbUsers::select().with_client(client).filter(ActiveOnly)?;
+/// ```
+pub trait Filter<Table: BigQueryTable + Debug> {
+    fn apply<QueryType: Debug, Client: Debug, StartingData: Debug>(
+        self,
+        builder: QueryBuilder<Table, QueryType, Client, QueryWasNotBuilt, StartingData>,
+    ) -> Result<QueryBuilder<Table, QueryType, Client, QueryWasNotBuilt, StartingData>>;
+}
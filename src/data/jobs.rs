@@ -0,0 +1,448 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
+use std::time::Duration;
+
+use chrono::Utc;
+use google_bigquery2::api::{
+    GetQueryResultsResponse, Job, JobConfiguration, JobConfigurationQuery, JobReference,
+    JobStatistics, QueryRequest, QueryResponse, TableReference,
+};
+
+use crate::client::BigqueryClient;
+use crate::data::query_builder::BigqueryError;
+use crate::prelude::*;
+
+/// How often a running job is polled for completion.
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+//region LoadFormat
+/// Format of the source files of a load job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadFormat {
+    NewlineDelimitedJson,
+    /// CSV files with the columns in the same order as the table schema.
+    Csv {
+        /// number of header rows to skip
+        skip_leading_rows: i32,
+    },
+}
+
+impl LoadFormat {
+    pub(crate) fn source_format(&self) -> String {
+        match self {
+            LoadFormat::NewlineDelimitedJson => String::from("NEWLINE_DELIMITED_JSON"),
+            LoadFormat::Csv { .. } => String::from("CSV"),
+        }
+    }
+    pub(crate) fn skip_leading_rows(&self) -> Option<i32> {
+        match self {
+            LoadFormat::NewlineDelimitedJson => None,
+            LoadFormat::Csv { skip_leading_rows } => Some(*skip_leading_rows),
+        }
+    }
+}
+
+//endregion
+//region ExtractFormat
+/// Format of the files written by an extract job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtractFormat {
+    Avro,
+    Csv,
+    NewlineDelimitedJson,
+}
+
+impl ExtractFormat {
+    pub(crate) fn destination_format(&self) -> String {
+        match self {
+            ExtractFormat::Avro => String::from("AVRO"),
+            ExtractFormat::Csv => String::from("CSV"),
+            ExtractFormat::NewlineDelimitedJson => String::from("NEWLINE_DELIMITED_JSON"),
+        }
+    }
+}
+
+//endregion
+//region LoadJobStats
+/// Statistics of a finished load job.
+#[derive(Debug, Clone, Default)]
+pub struct LoadJobStats {
+    pub input_files: i64,
+    pub input_file_bytes: i64,
+    pub output_rows: i64,
+    pub bad_records: i64,
+}
+
+impl LoadJobStats {
+    pub(crate) fn from_job(job: &Job) -> Self {
+        let load = job
+            .statistics
+            .as_ref()
+            .and_then(|statistics| statistics.load.as_ref());
+        match load {
+            None => LoadJobStats::default(),
+            Some(load) => LoadJobStats {
+                input_files: load.input_files.unwrap_or(0),
+                input_file_bytes: load.input_file_bytes.unwrap_or(0),
+                output_rows: load.output_rows.unwrap_or(0),
+                bad_records: load.bad_records.unwrap_or(0),
+            },
+        }
+    }
+}
+
+//endregion
+//region QueryStats
+/// Statistics of one statement of a script.
+#[derive(Debug, Clone, Default)]
+pub struct QueryStats {
+    pub job_id: String,
+    /// the kind of statement, like `INSERT` or `SELECT`
+    pub statement_type: Option<String>,
+    pub num_dml_affected_rows: i64,
+    pub total_bytes_processed: i64,
+}
+
+impl QueryStats {
+    fn from_statistics(job_id: String, statistics: Option<&JobStatistics>) -> Self {
+        let query = statistics.and_then(|statistics| statistics.query.as_ref());
+        match query {
+            None => QueryStats {
+                job_id,
+                ..Default::default()
+            },
+            Some(query) => QueryStats {
+                job_id,
+                statement_type: query.statement_type.clone(),
+                num_dml_affected_rows: query.num_dml_affected_rows.unwrap_or(0),
+                total_bytes_processed: query.total_bytes_processed.unwrap_or(0),
+            },
+        }
+    }
+}
+
+//endregion
+//region QueryCancelledError
+/// Returned by `run_cancellable` when the query was cancelled before it
+/// finished.
+#[derive(Debug)]
+pub struct QueryCancelledError {
+    /// the id of the cancelled job, `None` if it was cancelled before the
+    /// job was started
+    pub job_id: Option<String>,
+}
+
+impl Display for QueryCancelledError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.job_id {
+            Some(job_id) => write!(f, "the query job {} was cancelled", job_id),
+            None => write!(f, "the query was cancelled before it was started"),
+        }
+    }
+}
+
+impl Error for QueryCancelledError {}
+
+//endregion
+
+pub(crate) fn table_reference(
+    client: &BigqueryClient,
+    dataset_id: String,
+    table_name: String,
+) -> TableReference {
+    TableReference {
+        project_id: Some(client.get_project_id().to_string()),
+        dataset_id: Some(dataset_id),
+        table_id: Some(table_name),
+    }
+}
+
+/// Inserts the job and polls it until it is done.
+///
+/// Returns an error if the job finished with an error.
+pub(crate) async fn run_job_to_completion(client: &BigqueryClient, mut job: Job) -> Result<Job> {
+    trace!("run_job_to_completion({:?})", job);
+    let project_id = client.get_job_project_id();
+    if let Some(configuration) = job.configuration.as_mut() {
+        if configuration.labels.is_none() {
+            configuration.labels = client.get_request_labels();
+        }
+    }
+    let (_, mut job) = client
+        .get_client()
+        .jobs()
+        .insert(job, project_id)
+        .doit_without_upload()
+        .await?;
+    let JobReference {
+        job_id, location, ..
+    } = job.job_reference.clone().unwrap_or_default();
+    let job_id = job_id.ok_or_else(|| anyhow!("inserted job has no job id"))?;
+    debug!(
+        request_id = client.get_request_id(),
+        "inserted job: {}", job_id
+    );
+
+    while !is_job_done(&job) {
+        tokio::time::sleep(JOB_POLL_INTERVAL).await;
+        let mut call = client.get_client().jobs().get(project_id, &job_id);
+        if let Some(location) = &location {
+            call = call.location(location);
+        }
+        let (_, updated_job) = call.doit().await?;
+        job = updated_job;
+    }
+    debug!("job {} is done", job_id);
+    check_job_status(&job, &job_id)?;
+    Ok(job)
+}
+
+/// Returns an error if the finished job failed.
+fn check_job_status(job: &Job, job_id: &str) -> Result<()> {
+    let status = job.status.clone().unwrap_or_default();
+    if let Some(error) = status.error_result {
+        let message = error.message.clone().unwrap_or_default();
+        return Err(BigqueryError::new(
+            &format!("job {} failed: {}", job_id, message),
+            status.errors,
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Runs the query as a job with an id chosen up front, so it can be
+/// cancelled when `cancel` completes before the job is done.
+///
+/// Returns a [`QueryCancelledError`] when cancelled.
+pub(crate) async fn run_query_job_cancellable<C>(
+    client: &BigqueryClient,
+    request: QueryRequest,
+    cancel: C,
+) -> Result<QueryResponse>
+where
+    C: Future<Output = ()>,
+{
+    trace!("run_query_job_cancellable({:?})", request.query);
+    let mut cancel = pin!(cancel);
+    if until_cancelled(std::future::ready(()), cancel.as_mut())
+        .await
+        .is_none()
+    {
+        return Err(QueryCancelledError { job_id: None }.into());
+    }
+    let project_id = client.get_job_project_id();
+    let job_id = new_job_id();
+    let (parameter_mode, query_parameters) = match request.query_parameters {
+        Some(params) if !params.is_empty() => (Some(String::from("NAMED")), Some(params)),
+        _ => (None, None),
+    };
+    let job = Job {
+        job_reference: Some(JobReference {
+            project_id: Some(project_id.to_string()),
+            job_id: Some(job_id.clone()),
+            location: request.location,
+        }),
+        configuration: Some(JobConfiguration {
+            query: Some(JobConfigurationQuery {
+                query: request.query,
+                query_parameters,
+                parameter_mode,
+                use_legacy_sql: request.use_legacy_sql,
+                ..Default::default()
+            }),
+            labels: request.labels.or_else(|| client.get_request_labels()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let insert = client
+        .get_client()
+        .jobs()
+        .insert(job, project_id)
+        .doit_without_upload();
+    let mut job = match until_cancelled(insert, cancel.as_mut()).await {
+        Some(result) => result?.1,
+        None => return Err(cancel_job(client, job_id).await),
+    };
+    let location = job
+        .job_reference
+        .as_ref()
+        .and_then(|reference| reference.location.clone());
+    debug!(
+        request_id = client.get_request_id(),
+        "inserted cancellable job: {}", job_id
+    );
+    while !is_job_done(&job) {
+        let poll = async {
+            tokio::time::sleep(JOB_POLL_INTERVAL).await;
+            let mut call = client.get_client().jobs().get(project_id, &job_id);
+            if let Some(location) = &location {
+                call = call.location(location);
+            }
+            call.doit().await
+        };
+        job = match until_cancelled(poll, cancel.as_mut()).await {
+            Some(result) => result?.1,
+            None => return Err(cancel_job(client, job_id).await),
+        };
+    }
+    check_job_status(&job, &job_id)?;
+    let mut call = client
+        .get_client()
+        .jobs()
+        .get_query_results(project_id, &job_id);
+    if let Some(location) = &location {
+        call = call.location(location);
+    }
+    let (_, results) = call.doit().await?;
+    Ok(query_response_from_results(results))
+}
+
+/// Fetches the page `page_token` of the results of a finished query job, see
+/// [`QueryResponse::page_token`].
+pub(crate) async fn get_query_results_page(
+    client: &BigqueryClient,
+    job_reference: &JobReference,
+    page_token: &str,
+) -> Result<QueryResponse> {
+    trace!("get_query_results_page({:?})", job_reference);
+    let job_id = job_reference
+        .job_id
+        .as_deref()
+        .ok_or_else(|| anyhow!("the query response has no job id"))?;
+    let project_id = job_reference
+        .project_id
+        .as_deref()
+        .unwrap_or_else(|| client.get_job_project_id());
+    let mut call = client
+        .get_client()
+        .jobs()
+        .get_query_results(project_id, job_id)
+        .page_token(page_token);
+    if let Some(location) = &job_reference.location {
+        call = call.location(location);
+    }
+    let (_, results) = call.doit().await?;
+    Ok(query_response_from_results(results))
+}
+
+fn query_response_from_results(results: GetQueryResultsResponse) -> QueryResponse {
+    QueryResponse {
+        cache_hit: results.cache_hit,
+        errors: results.errors,
+        job_complete: results.job_complete,
+        job_reference: results.job_reference,
+        kind: results.kind,
+        num_dml_affected_rows: results.num_dml_affected_rows,
+        page_token: results.page_token,
+        rows: results.rows,
+        schema: results.schema,
+        total_bytes_processed: results.total_bytes_processed,
+        total_rows: results.total_rows,
+        ..Default::default()
+    }
+}
+
+/// Polls `future` until it completes, or returns `None` as soon as `cancel`
+/// completes. `cancel` is polled first.
+async fn until_cancelled<T, F, C>(future: F, mut cancel: Pin<&mut C>) -> Option<T>
+where
+    F: Future<Output = T>,
+    C: Future<Output = ()>,
+{
+    let mut future = pin!(future);
+    std::future::poll_fn(|cx| {
+        if cancel.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        future.as_mut().poll(cx).map(Some)
+    })
+    .await
+}
+
+/// Asks BigQuery to cancel the job and returns the error for the caller.
+/// Cancelling is best effort, the job might already be done.
+async fn cancel_job(client: &BigqueryClient, job_id: String) -> anyhow::Error {
+    debug!("cancelling job {}", job_id);
+    let result = client
+        .get_client()
+        .jobs()
+        .cancel(client.get_job_project_id(), &job_id)
+        .doit()
+        .await;
+    if let Err(error) = result {
+        warn!("could not cancel job {}: {}", job_id, error);
+    }
+    QueryCancelledError {
+        job_id: Some(job_id),
+    }
+    .into()
+}
+
+/// A job id that is unique for this process, since the id of a cancellable
+/// job has to be known before it is inserted.
+fn new_job_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "google_bigquery_v2_{}_{}",
+        Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Returns the statistics of the statements of a finished script job, in the
+/// order they ran.
+pub(crate) async fn get_child_job_stats(
+    client: &BigqueryClient,
+    parent_job_id: &str,
+) -> Result<Vec<QueryStats>> {
+    trace!("get_child_job_stats({})", parent_job_id);
+    let project_id = client.get_job_project_id();
+    let mut child_jobs = vec![];
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut call = client
+            .get_client()
+            .jobs()
+            .list(project_id)
+            .parent_job_id(parent_job_id)
+            .projection("full");
+        if let Some(page_token) = &page_token {
+            call = call.page_token(page_token);
+        }
+        let (_, job_list) = call.doit().await?;
+        child_jobs.extend(job_list.jobs.unwrap_or_default());
+        page_token = job_list.next_page_token;
+        if page_token.is_none() {
+            break;
+        }
+    }
+    child_jobs.sort_by_key(|job| {
+        job.statistics
+            .as_ref()
+            .and_then(|statistics| statistics.creation_time)
+    });
+    let stats = child_jobs
+        .into_iter()
+        .map(|job| {
+            let job_id = job
+                .job_reference
+                .and_then(|reference| reference.job_id)
+                .unwrap_or_default();
+            QueryStats::from_statistics(job_id, job.statistics.as_ref())
+        })
+        .collect();
+    Ok(stats)
+}
+
+fn is_job_done(job: &Job) -> bool {
+    job.status
+        .as_ref()
+        .and_then(|status| status.state.as_deref())
+        == Some("DONE")
+}
@@ -1,6 +1,14 @@
+pub use bigquery_columns::*;
 pub use bigquery_table::*;
+pub use raw_query::*;
 // pub use bigquery_builder::*;
 
+mod bigquery_columns;
 mod bigquery_table;
+pub mod filter;
+pub mod jobs;
 pub mod param_conversion;
 pub mod query_builder;
+mod raw_query;
+pub mod transaction;
+pub mod writer;
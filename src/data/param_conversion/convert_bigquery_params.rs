@@ -1,8 +1,10 @@
 use std::fmt::Debug;
+use std::str::FromStr;
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::{NaiveDateTime, SubsecRound, Utc};
 use serde_json::{value, Value};
 
+use crate::data::param_conversion::ConversionError;
 use crate::prelude::*;
 
 pub trait ConvertBigQueryParams {
@@ -10,37 +12,71 @@ pub trait ConvertBigQueryParams {
     where
         Self: Sized;
     fn to_param(&self) -> Value;
+    /// Returns the value of a query parameter, or `None` for NULL.
+    fn to_param_string(&self) -> Option<String> {
+        convert_value_to_string(self.to_param()).ok()
+    }
+    /// Returns true if the value should not be written at all, like [`Patch::Undefined`].
+    ///
+    /// [`Patch::Undefined`]: crate::data::param_conversion::Patch::Undefined
+    fn is_undefined(&self) -> bool {
+        false
+    }
 }
 
 impl ConvertBigQueryParams for i64 {
     fn from_param(value: &Value) -> Result<Self> {
-        let string: String = serde_json::from_value(value.clone())?;
-        Ok(string.parse()?)
+        number_from_param(value)
     }
     fn to_param(&self) -> Value {
         serde_json::to_value(self).unwrap()
     }
+    /// INT64 parameters are bound as their decimal string.
+    fn to_param_string(&self) -> Option<String> {
+        Some(self.to_string())
+    }
 }
 
 impl ConvertBigQueryParams for i32 {
     fn from_param(value: &Value) -> Result<Self> {
-        let string: String = serde_json::from_value(value.clone())?;
-        Ok(string.parse()?)
+        number_from_param(value)
     }
     fn to_param(&self) -> Value {
         serde_json::to_value(self).unwrap()
     }
+    /// INT64 parameters are bound as their decimal string.
+    fn to_param_string(&self) -> Option<String> {
+        Some(self.to_string())
+    }
+}
+
+/// Numbers usually arrive as strings, but some API surfaces return them as
+/// plain JSON numbers, so both are accepted.
+fn number_from_param<T>(value: &Value) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let string = match value {
+        Value::Number(num) => num.to_string(),
+        _ => serde_json::from_value(value.clone())?,
+    };
+    Ok(string.parse()?)
 }
 
 impl ConvertBigQueryParams for bool {
+    /// Accepts `true`/`false`, `t`/`f`, `yes`/`no` (in any case) and `1`/`0`,
+    /// as strings or as JSON booleans and numbers.
     fn from_param(value: &Value) -> Result<Self> {
-        let value: String = serde_json::from_value(value.clone())?;
-        match value.as_str() {
-            "TRUE" => Ok(true),
-            "true" => Ok(true),
-            "FALSE" => Ok(false),
-            "false" => Ok(false),
-            invalid => Err(anyhow!("Invalid value for bool: '{}'", invalid)),
+        let value = match value {
+            Value::Bool(value) => return Ok(*value),
+            Value::Number(number) => number.to_string(),
+            _ => serde_json::from_value(value.clone())?,
+        };
+        match value.to_ascii_lowercase().as_str() {
+            "true" | "t" | "yes" | "1" => Ok(true),
+            "false" | "f" | "no" | "0" => Ok(false),
+            _ => Err(anyhow!("Invalid value for bool: '{}'", value)),
         }
     }
     fn to_param(&self) -> Value {
@@ -61,9 +97,25 @@ impl ConvertBigQueryParams for String {
     }
 }
 
+/// `&str` is write-only: it can be bound as a query parameter (e.g. in a where
+/// clause), but it can not be read back from a query result, since there is
+/// nothing to borrow from. Use `String` for struct fields.
+impl ConvertBigQueryParams for &str {
+    fn from_param(_value: &Value) -> Result<Self> {
+        Err(
+            ConversionError::new("&str can not be read from a query result, use String instead")
+                .into(),
+        )
+    }
+    fn to_param(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
 impl ConvertBigQueryParams for f64 {
+    /// Also accepts scientific notation and `NaN`/`Infinity` in the string form.
     fn from_param(value: &Value) -> Result<Self> {
-        Ok(serde_json::from_value(value.clone())?)
+        number_from_param(value)
     }
     fn to_param(&self) -> Value {
         serde_json::to_value(self).unwrap()
@@ -72,31 +124,41 @@ impl ConvertBigQueryParams for f64 {
 
 impl ConvertBigQueryParams for chrono::DateTime<Utc> {
     fn from_param(value: &Value) -> Result<Self> {
-        trace!(
-            "ConvertValueToBigqueryParamValue::from_param DateTime<Utc> -> in:  {:?}",
-            value
-        );
+        trace!("ConvertValueToBigqueryParamValue::from_param DateTime<Utc>");
         let value: String = serde_json::from_value(value.clone())?;
         let value = value.replace("T", " ").replace("Z", "");
-        let value = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S")?;
-        let time = chrono::DateTime::<Utc>::from_utc(value, Utc);
-        trace!(
-            "ConvertValueToBigqueryParamValue::from_param DateTime<Utc> -> out: {:?}",
-            time
-        );
+        // TIMESTAMPs have microsecond precision, so keep the optional fraction
+        let value = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S%.f")?;
+        let time = chrono::DateTime::<Utc>::from_naive_utc_and_offset(value, Utc);
         Ok(time)
     }
     fn to_param(&self) -> Value {
-        trace!(
-            "ConvertValueToBigqueryParamValue::to_param DateTime<Utc> -> in:  {:?}",
-            self
-        );
-        let value: String = self.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        trace!("ConvertValueToBigqueryParamValue::to_param DateTime<Utc>");
+        // BigQuery only keeps microseconds and rejects more fractional digits
+        let value: String = self
+            .trunc_subsecs(6)
+            .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true);
         let value: String = value.replace("Z", "").replace("T", " ");
-        trace!(
-            "ConvertValueToBigqueryParamValue::to_param DateTime<Utc> -> out: {:?}",
-            value
-        );
+        serde_json::to_value(value).unwrap()
+    }
+}
+
+/// DATETIME values have no timezone. Query results use a `T` between date and
+/// time, parameters a space.
+impl ConvertBigQueryParams for NaiveDateTime {
+    fn from_param(value: &Value) -> Result<Self> {
+        trace!("ConvertValueToBigqueryParamValue::from_param NaiveDateTime");
+        let value: String = serde_json::from_value(value.clone())?;
+        let value = value.replace('T', " ");
+        let time = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S%.f")?;
+        Ok(time)
+    }
+    fn to_param(&self) -> Value {
+        trace!("ConvertValueToBigqueryParamValue::to_param NaiveDateTime");
+        let value = self
+            .trunc_subsecs(6)
+            .format("%Y-%m-%d %H:%M:%S%.f")
+            .to_string();
         serde_json::to_value(value).unwrap()
     }
 }
@@ -106,10 +168,7 @@ impl<T: ConvertBigQueryParams + Debug> ConvertBigQueryParams for Option<T> {
     where
         Self: Sized,
     {
-        trace!(
-            "ConvertValueToBigqueryParamValue::from_param Option<T>: {:?}",
-            value
-        );
+        trace!("ConvertValueToBigqueryParamValue::from_param Option<T>");
         match value {
             Value::Null => Ok(None),
             _ => Ok(Some(T::from_param(value)?)),
@@ -117,23 +176,62 @@ impl<T: ConvertBigQueryParams + Debug> ConvertBigQueryParams for Option<T> {
     }
 
     fn to_param(&self) -> Value {
-        trace!(
-            "ConvertValueToBigqueryParamValue::to_param Option<T>: {:?}",
-            self
-        );
+        trace!("ConvertValueToBigqueryParamValue::to_param Option<T>");
         match self {
             Some(value) => value.to_param(),
             None => Value::Null,
         }
     }
+
+    fn to_param_string(&self) -> Option<String> {
+        self.as_ref().and_then(T::to_param_string)
+    }
+}
+
+/// The values of an ARRAY column. BigQuery never returns NULL elements, but
+/// in raw responses every element is wrapped in its own `{"v": ...}`; both
+/// the wrapped and the unwrapped form are accepted. A NULL or empty cell is
+/// an empty vec.
+impl<T: ConvertBigQueryParams + Debug> ConvertBigQueryParams for Vec<T> {
+    fn from_param(value: &Value) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        trace!("ConvertValueToBigqueryParamValue::from_param Vec<T>");
+        let value = unwrap_cell(value);
+        let elements = match value {
+            Value::Null => return Ok(vec![]),
+            Value::Object(object) if object.is_empty() => return Ok(vec![]),
+            Value::Array(elements) => elements,
+            _ => {
+                return Err(
+                    ConversionError::new(format!("expected an array, got {:?}", value)).into(),
+                )
+            }
+        };
+        elements
+            .iter()
+            .map(|element| T::from_param(unwrap_cell(element)))
+            .collect()
+    }
+
+    fn to_param(&self) -> Value {
+        trace!("ConvertValueToBigqueryParamValue::to_param Vec<T>");
+        Value::Array(self.iter().map(T::to_param).collect())
+    }
+}
+
+/// Returns the `...` of a `{"v": ...}` cell, or the value itself.
+fn unwrap_cell(value: &Value) -> &Value {
+    match value {
+        Value::Object(object) if object.len() == 1 => object.get("v").unwrap_or(value),
+        _ => value,
+    }
 }
 
 pub fn convert_value_to_string(value: Value) -> Result<String> {
-    trace!(
-        "ConvertValueToBigqueryParamValue::convert_value_to_string: {:?}",
-        value
-    );
-    return if value.is_string() {
+    trace!("ConvertValueToBigqueryParamValue::convert_value_to_string");
+    if value.is_string() {
         trace!("ConvertValueToBigqueryParamValue::convert_value_type_to_bigquery_type: String");
         Ok(value::from_value(value)?)
     } else {
@@ -148,5 +246,5 @@ pub fn convert_value_to_string(value: Value) -> Result<String> {
                 Ok(values_string)
             }
         }
-    };
+    }
 }
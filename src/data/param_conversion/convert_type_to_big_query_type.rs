@@ -1,10 +1,5 @@
-use crate::prelude::*;
 use std::fmt::{Debug, Display};
 
-use serde_json::Value;
-
-use crate::data::param_conversion::ConvertBigQueryParams;
-
 pub trait ConvertTypeToBigQueryType {
     fn convert_type_to_bigquery_type() -> String
     where
@@ -62,6 +57,12 @@ where
     }
 }
 
+impl ConvertTypeToBigQueryType for chrono::NaiveDateTime {
+    fn convert_type_to_bigquery_type() -> String {
+        "DATETIME".to_string()
+    }
+}
+
 impl<T: ConvertTypeToBigQueryType + Debug> ConvertTypeToBigQueryType for Option<T> {
     fn convert_type_to_bigquery_type() -> String {
         T::convert_type_to_bigquery_type()
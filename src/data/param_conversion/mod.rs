@@ -1,11 +1,15 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 
+use serde_json::Value;
+
 pub use convert_bigquery_params::{convert_value_to_string, ConvertBigQueryParams};
 pub use convert_type_to_big_query_type::ConvertTypeToBigQueryType;
+pub use patch::Patch;
 
 mod convert_bigquery_params;
 mod convert_type_to_big_query_type;
+mod patch;
 
 pub trait BigDataValueType:
     ConvertTypeToBigQueryType + ConvertBigQueryParams + Debug + Send + Sync
@@ -46,3 +50,68 @@ impl ConversionError {
 }
 
 //endregion
+//region FieldConversionError
+/// A column of a query result that could not be converted into the type of
+/// its field, e.g. because the schema of the table changed.
+///
+/// Returned by `new_from_query_result_row`, get it with
+/// `error.downcast_ref::<FieldConversionError>()`.
+#[derive(Debug, Clone)]
+pub struct FieldConversionError {
+    /// the column name in the query result
+    pub column: String,
+    /// the name of the field in the struct
+    pub field: String,
+    /// the BigQuery type of the field
+    pub expected_bq_type: String,
+    /// the value BigQuery returned
+    pub got_value: Value,
+    /// why the conversion failed
+    pub message: String,
+}
+
+impl Display for FieldConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to parse column '{}' (field {}): {}",
+            self.column, self.field, self.message
+        )
+    }
+}
+
+impl Error for FieldConversionError {}
+
+impl FieldConversionError {
+    pub fn new(
+        column: impl Into<String>,
+        field: impl Into<String>,
+        expected_bq_type: impl Into<String>,
+        got_value: &Value,
+        error: impl Display,
+    ) -> Self {
+        FieldConversionError {
+            column: column.into(),
+            field: field.into(),
+            expected_bq_type: expected_bq_type.into(),
+            got_value: got_value.clone(),
+            message: error.to_string(),
+        }
+    }
+    /// The error for a column that is not in the query result at all.
+    pub fn missing_column(
+        column: impl Into<String>,
+        field: impl Into<String>,
+        expected_bq_type: impl Into<String>,
+    ) -> Self {
+        FieldConversionError::new(
+            column,
+            field,
+            expected_bq_type,
+            &Value::Null,
+            "column missing from result",
+        )
+    }
+}
+
+//endregion
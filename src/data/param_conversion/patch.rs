@@ -0,0 +1,81 @@
+use std::fmt::Debug;
+
+use serde_json::Value;
+
+use crate::data::param_conversion::{ConvertBigQueryParams, ConvertTypeToBigQueryType};
+use crate::prelude::*;
+
+/// A field value with PATCH semantics for updates.
+///
+/// Unlike `Option<T>`, this distinguishes between a field that should not be
+/// touched ([`Patch::Undefined`]) and one that should be set to NULL
+/// ([`Patch::Null`]). Update queries leave `Undefined` columns out of the SET.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Patch<T> {
+    /// The column is not written.
+    #[default]
+    Undefined,
+    /// The column is set to NULL.
+    Null,
+    /// The column is set to the value.
+    Value(T),
+}
+
+impl<T> Patch<T> {
+    pub fn is_undefined(&self) -> bool {
+        matches!(self, Patch::Undefined)
+    }
+    pub fn as_option(&self) -> Option<&T> {
+        match self {
+            Patch::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl<T> From<Option<T>> for Patch<T> {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => Patch::Value(value),
+            None => Patch::Null,
+        }
+    }
+}
+
+impl<T: ConvertBigQueryParams + Debug> ConvertBigQueryParams for Patch<T> {
+    fn from_param(value: &Value) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        trace!("ConvertValueToBigqueryParamValue::from_param Patch<T>");
+        match value {
+            Value::Null => Ok(Patch::Null),
+            _ => Ok(Patch::Value(T::from_param(value)?)),
+        }
+    }
+
+    fn to_param(&self) -> Value {
+        trace!("ConvertValueToBigqueryParamValue::to_param Patch<T>");
+        match self {
+            Patch::Value(value) => value.to_param(),
+            Patch::Null | Patch::Undefined => Value::Null,
+        }
+    }
+
+    fn to_param_string(&self) -> Option<String> {
+        match self {
+            Patch::Value(value) => value.to_param_string(),
+            Patch::Null | Patch::Undefined => None,
+        }
+    }
+
+    fn is_undefined(&self) -> bool {
+        Patch::is_undefined(self)
+    }
+}
+
+impl<T: ConvertTypeToBigQueryType + Debug> ConvertTypeToBigQueryType for Patch<T> {
+    fn convert_type_to_bigquery_type() -> String {
+        T::convert_type_to_bigquery_type()
+    }
+}
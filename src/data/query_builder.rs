@@ -1,35 +1,107 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::io::Write;
 use std::marker::PhantomData;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use crate::prelude::*;
-use google_bigquery2::api::{ErrorProto, QueryParameter, QueryRequest};
+use chrono::{DateTime, Utc};
+use google_bigquery2::api::{
+    ErrorProto, QueryParameter, QueryParameterType, QueryParameterValue, QueryRequest,
+    QueryResponse,
+};
 use google_bigquery2::hyper::{Body, Response};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 
-use crate::data::param_conversion::BigDataValueType;
-use crate::prelude::*;
+use crate::data::filter::Filter;
+use crate::data::jobs::{get_query_results_page, run_query_job_cancellable};
+use crate::data::param_conversion::{BigDataValueType, FieldConversionError};
+use crate::data::raw_query::{
+    deserialize_rows, rows_from_query_response, rows_from_query_response_or_positional,
+};
+use crate::data::{
+    array_param, check_pk_type, create_table_with_name, null_param, param, BigQueryWritable,
+};
+use crate::utils::{check_table_identifier_part, new_random_id, safe_identifier};
 
 //region BigqueryError
 #[derive(Debug, Clone)]
 pub struct BigqueryError {
     pub message: String,
     pub errors: Option<Vec<ErrorProto>>,
+    /// the HTTP status code, if the error came from a response
+    pub status: Option<u16>,
+    /// the SQL of the failed query
+    pub query: Option<String>,
+    /// the params of the failed query, with sensitive values redacted
+    pub params: Option<Vec<QueryParameter>>,
 }
 
 impl BigqueryError {
-    fn new(message: &str, errors: Option<Vec<ErrorProto>>) -> Self {
+    pub fn new(message: &str, errors: Option<Vec<ErrorProto>>) -> Self {
         Self {
             message: message.to_string(),
             errors,
+            status: None,
+            query: None,
+            params: None,
+        }
+    }
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+    pub fn with_query(mut self, query: &str) -> Self {
+        self.query = Some(query.to_string());
+        self
+    }
+    /// Whether any of the errors has the given reason, e.g. `rateLimitExceeded`.
+    pub fn has_reason(&self, reason: &str) -> bool {
+        self.errors
+            .iter()
+            .flatten()
+            .any(|error| error.reason.as_deref() == Some(reason))
+    }
+    /// Extracts the status and the error details of a failed API call.
+    ///
+    /// Returns `None` for errors without a response, like connection errors.
+    pub(crate) fn from_api_error(error: &google_bigquery2::Error) -> Option<Self> {
+        match error {
+            google_bigquery2::Error::BadRequest(body) => {
+                let body = body.get("error")?;
+                let message = body
+                    .get("message")
+                    .and_then(|m| m.as_str())
+                    .unwrap_or_default();
+                let errors = body
+                    .get("errors")
+                    .and_then(|errors| serde_json::from_value(errors.clone()).ok());
+                let error = BigqueryError::new(message, errors);
+                Some(match body.get("code").and_then(|code| code.as_u64()) {
+                    Some(code) => error.with_status(code as u16),
+                    None => error,
+                })
+            }
+            google_bigquery2::Error::Failure(response) => {
+                let status = response.status();
+                Some(BigqueryError::new(&status.to_string(), None).with_status(status.as_u16()))
+            }
+            _ => None,
         }
     }
 }
 
 impl Display for BigqueryError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "BigqueryError: {}", self.message)
+        write!(f, "BigqueryError: {}", self.message)?;
+        if let Some(query) = &self.query {
+            write!(f, "\nquery: {}", query)?;
+        }
+        Ok(())
     }
 }
 
@@ -58,21 +130,21 @@ impl<T: Debug> QueryResultType<T> {
         match self {
             QueryResultType::WithoutRowData(result) => result,
             QueryResultType::WithRowData(data) => {
-                if data.len() == 0 {
+                if data.is_empty() {
                     return Ok(());
                 }
-                return Err(anyhow!(
+                Err(anyhow!(
                     "map_err_without_data message:'{}' data: {:?}",
                     message.into(),
                     data
-                ));
+                ))
             }
         }
     }
     pub fn expect_with_data(self, message: impl Into<String>) -> Vec<T> {
         self.map_err_with_data(message).unwrap()
     }
-    pub fn expect_without_data(self, message: impl Into<String>) -> () {
+    pub fn expect_without_data(self, message: impl Into<String>) {
         self.map_err_without_data(message).unwrap()
     }
     pub fn is_with_row_data(&self) -> bool {
@@ -89,6 +161,82 @@ impl<T: Debug> QueryResultType<T> {
     }
 }
 //endregion
+//region Comparison
+/// The operator of a where clause comparing two columns, see
+/// [`QueryBuilder::add_where_field_cmp`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    /// `=`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+}
+
+impl Comparison {
+    fn to_query_str(self) -> &'static str {
+        match self {
+            Comparison::Eq => "=",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+//endregion
+//region MaxRows
+/// What `run()` does when a query returns more rows than allowed by `max_rows`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxRowsBehavior {
+    /// Fail with an error.
+    Error,
+    /// Keep the first rows up to the limit and drop the rest.
+    Truncate,
+}
+
+/// Guardrail on the number of rows `run()` materializes.
+///
+/// This is independent of the SQL `LIMIT` and is checked against the rows
+/// actually returned by BigQuery.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxRows {
+    pub limit: usize,
+    pub behavior: MaxRowsBehavior,
+}
+
+impl MaxRows {
+    pub fn new(limit: usize, behavior: MaxRowsBehavior) -> Self {
+        Self { limit, behavior }
+    }
+
+    fn check_total(&self, total_rows: u64) -> Result<()> {
+        if self.behavior == MaxRowsBehavior::Error && total_rows > self.limit as u64 {
+            return Err(anyhow!(
+                "Query returned {} rows, which exceeds max_rows of {}",
+                total_rows,
+                self.limit
+            ));
+        }
+        Ok(())
+    }
+
+    /// Applies the limit to the given rows according to the configured behavior.
+    pub fn apply<T>(&self, mut rows: Vec<T>) -> Result<Vec<T>> {
+        self.check_total(rows.len() as u64)?;
+        rows.truncate(self.limit);
+        Ok(rows)
+    }
+}
+//endregion
 //region typestate structs
 
 #[derive(Debug, Default, Clone)]
@@ -119,7 +267,9 @@ impl HasNoQueryType for QueryTypeNoType {}
 #[derive(Debug, Clone)]
 pub struct QueryTypeInsert;
 
-impl HasQueryType for QueryTypeInsert {}
+impl HasQueryType for QueryTypeInsert {
+    const IS_INSERT: bool = true;
+}
 
 //endregion
 //region select
@@ -147,13 +297,47 @@ impl HasQueryType for QueryTypeDelete {}
 
 //endregion
 
-pub trait HasQueryType {}
+pub trait HasQueryType {
+    /// Whether the query inserts rows, which is the only kind of write that
+    /// creates a missing table with [`BigqueryClient::auto_create`].
+    const IS_INSERT: bool = false;
+}
 
 pub trait HasNoQueryType {}
 //endregion
 
 //region QueryBuilder
+type RowParser<Table> = fn(BigqueryClient, &HashMap<String, Value>) -> Result<Table>;
+
+/// The `GROUP BY` of a select, see [`QueryBuilder::group_by`] and
+/// [`QueryBuilder::aggregate_children`].
 #[derive(Debug, Clone)]
+struct GroupBy {
+    /// the selected columns and aggregates, in order
+    fields: Vec<String>,
+    /// the db names (or aliases) of the grouped columns
+    columns: Vec<String>,
+    /// the aliases of the aggregates
+    aliases: Vec<String>,
+    /// whether the rows still match `Table`, so they can be parsed by `run`
+    parses_as_table: bool,
+}
+
+impl GroupBy {
+    /// The selected columns and the `GROUP BY` clause.
+    fn to_query_strings(&self) -> (String, String) {
+        let group_by = match self.columns.is_empty() {
+            true => String::new(),
+            false => format!(" GROUP BY {}", self.columns.join(", ")),
+        };
+        (self.fields.join(", "), group_by)
+    }
+    fn has_alias(&self, name: &str) -> bool {
+        self.aliases.iter().any(|alias| alias == name)
+    }
+}
+
+#[derive(Clone)]
 pub struct QueryBuilder<Table, QueryType, Client, QueryBuilt, StartingData> {
     client: Client,
     query: String,
@@ -161,6 +345,29 @@ pub struct QueryBuilder<Table, QueryType, Client, QueryBuilt, StartingData> {
     where_clauses: Vec<String>,
     order_by: Vec<(String, OrderDirection)>,
     limit: Option<u32>,
+    parameterize_limit: bool,
+    /// rows to skip (`OFFSET`), only used together with a limit
+    offset: Option<u32>,
+    /// read the table as of this time (`FOR SYSTEM_TIME AS OF`)
+    as_of: Option<DateTime<Utc>>,
+    max_rows: Option<MaxRows>,
+    update_matching: bool,
+    /// write `DEFAULT` instead of `NULL` for unset values of an insert
+    use_defaults: bool,
+    /// names of positional params that hold values of `#[db_sensitive]` fields
+    sensitive_params: Vec<String>,
+    /// the selected fields, sorted once per query instead of on every use
+    sorted_fields: OnceLock<Vec<(String, String)>>,
+    /// parses the rows of a select with only some columns (see `select_fields`)
+    row_parser: Option<RowParser<Table>>,
+    /// overrides the table name of `Table` (see `with_table_name`)
+    table_name: Option<String>,
+    /// temporary functions that are created in front of the query
+    udfs: Vec<UdfResource>,
+    /// replaces the selected columns, see `select_star_except`
+    projection: Option<String>,
+    /// the grouped columns and the aggregates, see [`QueryBuilder::group_by`]
+    group_by: Option<GroupBy>,
 
     starting_data: StartingData,
 
@@ -169,6 +376,30 @@ pub struct QueryBuilder<Table, QueryType, Client, QueryBuilt, StartingData> {
     table: PhantomData<Table>,
 }
 
+/// Only the names of the params are printed and the starting data is left
+/// out, so logging a builder never leaks the values of `#[db_sensitive]` fields.
+impl<Table, QueryType, Client, QueryBuilt, StartingData> Debug
+    for QueryBuilder<Table, QueryType, Client, QueryBuilt, StartingData>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let param_names: Vec<&str> = self
+            .params
+            .iter()
+            .filter_map(|param| param.name.as_deref())
+            .collect();
+        f.debug_struct("QueryBuilder")
+            .field("table", &std::any::type_name::<Table>())
+            .field("query", &self.query)
+            .field("params", &param_names)
+            .field("where_clauses", &self.where_clauses)
+            .field("order_by", &self.order_by)
+            .field("limit", &self.limit)
+            .field("offset", &self.offset)
+            .field("table_name", &self.table_name)
+            .finish_non_exhaustive()
+    }
+}
+
 //region default implementation for QueryBuilder
 impl<Table, QueryType, Client: Default, QueryBuilt, StartingData: Default> Default
     for QueryBuilder<Table, QueryType, Client, QueryBuilt, StartingData>
@@ -181,6 +412,19 @@ impl<Table, QueryType, Client: Default, QueryBuilt, StartingData: Default> Defau
             where_clauses: Vec::new(),
             order_by: Vec::new(),
             limit: None,
+            parameterize_limit: false,
+            offset: None,
+            as_of: None,
+            max_rows: None,
+            update_matching: false,
+            use_defaults: false,
+            sensitive_params: Vec::new(),
+            sorted_fields: OnceLock::new(),
+            row_parser: None,
+            table_name: None,
+            udfs: Vec::new(),
+            projection: None,
+            group_by: None,
             starting_data: Default::default(),
             query_type: PhantomData,
             query_built: PhantomData,
@@ -189,31 +433,135 @@ impl<Table, QueryType, Client: Default, QueryBuilt, StartingData: Default> Defau
     }
 }
 
+//endregion
+//region UdfResource
+/// A temporary user-defined function that is created for a single query,
+/// see [`QueryBuilder::with_udf`].
+///
+/// The query API only accepts UDF resources for legacy SQL, so the function
+/// is sent as a `CREATE TEMP FUNCTION` statement in front of the query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdfResource {
+    definition: String,
+}
+
+impl UdfResource {
+    /// A UDF from inline code, which is a complete `CREATE TEMP FUNCTION`
+    /// statement, e.g.
+    /// `CREATE TEMP FUNCTION add_one(x INT64) AS (x + 1)`.
+    pub fn inline(code: impl Into<String>) -> Self {
+        let code = code.into();
+        Self {
+            definition: code.trim().trim_end_matches(';').to_string(),
+        }
+    }
+
+    /// A JavaScript UDF with the signature `name(args) RETURNS type` whose
+    /// body can use the libraries at the Google Cloud Storage URIs
+    /// (`gs://bucket/path.js`).
+    pub fn javascript(signature: &str, body: &str, library_uris: &[&str]) -> Self {
+        let mut definition = format!("CREATE TEMP FUNCTION {} LANGUAGE js", signature);
+        if !library_uris.is_empty() {
+            let libraries = library_uris
+                .iter()
+                .map(|uri| format!("\"{}\"", uri))
+                .collect::<Vec<String>>()
+                .join(", ");
+            definition.push_str(&format!(" OPTIONS (library=[{}])", libraries));
+        }
+        definition.push_str(&format!(" AS r\"\"\"{}\"\"\"", body));
+        Self { definition }
+    }
+
+    /// The `CREATE TEMP FUNCTION` statement, without a trailing `;`.
+    pub fn definition(&self) -> &str {
+        &self.definition
+    }
+}
 //endregion
 //region general QueryBuilder
 //region functions for all queries
 impl<Table: BigQueryTable, UnknownQueryType, Client, QueryBuilt, StartingData>
     QueryBuilder<Table, UnknownQueryType, Client, QueryBuilt, StartingData>
 {
-    fn get_sorted_selected_fields(&self) -> Vec<(String, String)> {
-        trace!("get_sorted_selected_fields()");
-        let mut fields: Vec<(String, String)> = Table::get_query_fields(true).into_iter().collect();
-        debug!("fields: {:?}", fields);
-        fields.sort();
-        fields
+    /// Returns the (field name, db name) pairs of all fields, sorted by field
+    /// name. This order is used for the columns of every generated query.
+    fn get_sorted_selected_fields(&self) -> &[(String, String)] {
+        self.sorted_fields.get_or_init(|| {
+            trace!("get_sorted_selected_fields()");
+            let mut fields: Vec<(String, String)> =
+                Table::get_query_fields(true).into_iter().collect();
+            debug!("fields: {:?}", fields);
+            fields.sort();
+            fields
+        })
+    }
+
+    /// The table name of the query, see [`QueryBuilder::with_table_name`].
+    fn get_table_name(&self) -> String {
+        match &self.table_name {
+            Some(table_name) => table_name.clone(),
+            None => Table::get_table_name(),
+        }
     }
 
+    /// The selected columns, with `#[db_expr]` fields as `<expr> AS <db_name>`.
     fn get_fields_string(&self) -> String {
         trace!("get_fields_string()");
-        let mut fields = self.get_sorted_selected_fields();
+        let exprs = Table::get_field_exprs();
+        let fields = self.get_sorted_selected_fields();
         fields
+            .iter()
+            .map(|(field, db_name)| match exprs.get(field) {
+                Some(expr) => format!("{} AS {}", expr, db_name),
+                None => db_name.clone(),
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Like [`Self::get_sorted_selected_fields`], but without the read-only
+    /// `#[db_expr]` fields.
+    fn get_sorted_written_fields(&self) -> Vec<(String, String)> {
+        let exprs = Table::get_field_exprs();
+        self.get_sorted_selected_fields()
+            .iter()
+            .filter(|(field, _)| !exprs.contains_key(field))
+            .cloned()
+            .collect()
+    }
+
+    /// The columns that are written by inserts.
+    fn get_written_fields_string(&self) -> String {
+        trace!("get_written_fields_string()");
+        self.get_sorted_written_fields()
             .into_iter()
-            .map(|f| f.1)
+            .map(|(_, db_name)| db_name)
             .collect::<Vec<String>>()
             .join(", ")
     }
+
+    /// The names of the params that hold values of `#[db_sensitive]` fields:
+    /// the where params of sensitive columns and the field params of the
+    /// sensitive fields of `Table`.
+    fn get_sensitive_param_names(&self) -> Vec<String> {
+        let mut sensitive_params = self.sensitive_params.clone();
+        for field in Table::get_sensitive_fields() {
+            if let Ok(param_name) = Table::get_field_param_name(&field) {
+                sensitive_params.push(param_name);
+            }
+        }
+        sensitive_params
+    }
 }
 
+impl<Table: BigQueryTable, UnknownQueryType, QueryBuilt, StartingData>
+    QueryBuilder<Table, UnknownQueryType, HasClient, QueryBuilt, StartingData>
+{
+    fn get_table_identifier(&self) -> String {
+        Table::get_table_identifier_with_name(&self.client.0, &self.get_table_name())
+    }
+}
 //endregion
 //region functions for not built queries
 //region with Starting data
@@ -224,7 +572,7 @@ impl<Table: BigQueryTable + Default, UnknownQueryType, Client>
         trace!("add_field_where(field: {})", field);
 
         let field_db_name = Table::get_field_db_name(field)?;
-        let param = Table::get_parameter_from_field(&self.starting_data.0, &field)?;
+        let param = Table::get_parameter_from_field(&self.starting_data.0, field)?;
         let mut params = self.params;
 
         let mut wheres = self.where_clauses;
@@ -247,11 +595,15 @@ impl<Table: BigQueryTable + Default, UnknownQueryType, Client>
         })
     }
 
-    fn add_params_for_table_query_fields(&mut self) -> Result<()> {
-        trace!("add_params_for_table_query_fields()");
-        let local_fields = Table::get_query_fields(true);
+    fn add_params_for_table_query_fields(&mut self, include_pk: bool) -> Result<()> {
+        trace!("add_params_for_table_query_fields({})", include_pk);
+        let local_fields = Table::get_query_fields(include_pk);
+        let exprs = Table::get_field_exprs();
         let starting_data = &self.starting_data.0;
         for (local_field_name, _) in local_fields {
+            if exprs.contains_key(&local_field_name) {
+                continue;
+            }
             let para = Table::get_parameter_from_field(starting_data, &local_field_name)?;
             if let Some(para) = para {
                 let mut has_param = false;
@@ -279,12 +631,13 @@ impl<Table: BigQueryTable + Debug, UnknownQueryType: Debug, Client: Debug, Start
     where
         T: BigDataValueType + Debug,
     {
-        trace!("add_where_eq({:?}, {:?})", column, value);
+        trace!("add_where_eq({:?})", column);
+        let is_sensitive = Table::get_sensitive_fields().iter().any(|f| f == column);
         let column = Table::get_field_db_name(column)?;
         let mut wheres = self.where_clauses;
 
         if let Some(value) = value {
-            let param_name = format!("__PARAM_{}", self.params.len());
+            let param_name = next_where_param_name(&self.params);
             let param = Table::get_parameter(value, &param_name);
             if let Some(param) = param {
                 let mut required_params = self.params;
@@ -292,9 +645,14 @@ impl<Table: BigQueryTable + Debug, UnknownQueryType: Debug, Client: Debug, Start
 
                 wheres.push(format!("{} = @{}", column, param_name));
 
+                let mut sensitive_params = self.sensitive_params;
+                if is_sensitive {
+                    sensitive_params.push(param_name);
+                }
                 return Ok(Self {
                     where_clauses: wheres,
                     params: required_params,
+                    sensitive_params,
                     ..self
                 });
             }
@@ -307,13 +665,442 @@ impl<Table: BigQueryTable + Debug, UnknownQueryType: Debug, Client: Debug, Start
         })
     }
 
+    /// Like [`Self::add_where_eq`], but binds the parameter as `bigquery_type`
+    /// instead of the type of `T`, e.g. a `DateTime<Utc>` as `TIMESTAMP` or
+    /// `DATETIME` to match the type of the column.
+    pub fn add_where_eq_typed<T>(
+        self,
+        column: &str,
+        value: Option<&T>,
+        bigquery_type: &str,
+    ) -> Result<Self>
+    where
+        T: BigDataValueType + Debug,
+    {
+        trace!("add_where_eq_typed({:?}, {:?})", column, bigquery_type);
+        let param_count = self.params.len();
+        let mut query = self.add_where_eq(column, value)?;
+        if query.params.len() > param_count {
+            if let Some(parameter_type) = query
+                .params
+                .last_mut()
+                .and_then(|param| param.parameter_type.as_mut())
+            {
+                parameter_type.type_ = Some(bigquery_type.to_string());
+            }
+        }
+        Ok(query)
+    }
+
+    /// Adds `LOWER(column) = LOWER(@param)`, a case-insensitive
+    /// [`Self::add_where_eq`] for lookups like emails or user names.
+    pub fn add_where_ieq(self, column: &str, value: &str) -> Result<Self> {
+        trace!("add_where_ieq({:?})", column);
+        let is_sensitive = Table::get_sensitive_fields().iter().any(|f| f == column);
+        let column = Table::get_field_db_name(column)?;
+        let param_name = next_where_param_name(&self.params);
+        let mut wheres = self.where_clauses;
+        wheres.push(format!("LOWER({}) = LOWER(@{})", column, param_name));
+        let mut params = self.params;
+        params.push(param(&param_name, &value));
+        let mut sensitive_params = self.sensitive_params;
+        if is_sensitive {
+            sensitive_params.push(param_name);
+        }
+        Ok(Self {
+            where_clauses: wheres,
+            params,
+            sensitive_params,
+            ..self
+        })
+    }
+
+    /// Adds `column = @param` where the parameter is a NULL of the BigQuery
+    /// type of `T`, unlike [`Self::add_where_eq`] which writes `column is NULL`.
+    ///
+    /// Note that `=` with NULL never matches a row; this is meant for
+    /// functions and scripts that distinguish typed NULLs.
+    pub fn add_where_typed_null<T>(self, column: &str) -> Result<Self>
+    where
+        T: BigDataValueType,
+    {
+        trace!("add_where_typed_null({:?})", column);
+        let column = Table::get_field_db_name(column)?;
+        let param_name = next_where_param_name(&self.params);
+        let mut wheres = self.where_clauses;
+        wheres.push(format!("{} = @{}", column, param_name));
+        let mut params = self.params;
+        params.push(null_param::<T>(&param_name));
+        Ok(Self {
+            where_clauses: wheres,
+            params,
+            ..self
+        })
+    }
+
+    /// Adds a where clause that matches any of the values.
+    ///
+    /// `None` values can not be matched with `IN`, so if any are present the
+    /// clause becomes `(column IN UNNEST(@param) OR column is NULL)`.
+    ///
+    /// All values go into one query, which BigQuery rejects when it gets too
+    /// large. To look up many primary keys use
+    /// [`get_by_pks`](crate::data::BigQueryTable::get_by_pks), which splits
+    /// them into chunks.
+    pub fn add_where_in<T>(self, column: &str, values: &[Option<T>]) -> Result<Self>
+    where
+        T: BigDataValueType + Clone + Debug,
+    {
+        trace!("add_where_in({:?}, {} values)", column, values.len());
+        let is_sensitive = Table::get_sensitive_fields().iter().any(|f| f == column);
+        let column = Table::get_field_db_name(column)?;
+        let some_values: Vec<T> = values.iter().flatten().cloned().collect();
+        let has_null = some_values.len() < values.len();
+        let mut wheres = self.where_clauses;
+        let mut params = self.params;
+        let mut sensitive_params = self.sensitive_params;
+
+        let in_clause = match some_values.is_empty() {
+            true => None,
+            false => {
+                let param_name = next_where_param_name(&params);
+                params.push(array_param(&param_name, &some_values));
+                let in_clause = format!("{} IN UNNEST(@{})", column, param_name);
+                if is_sensitive {
+                    sensitive_params.push(param_name);
+                }
+                Some(in_clause)
+            }
+        };
+        let where_clause = match (in_clause, has_null) {
+            (Some(in_clause), false) => in_clause,
+            (Some(in_clause), true) => format!("({} OR {} is NULL)", in_clause, column),
+            (None, true) => format!("{} is NULL", column),
+            // an empty IN matches nothing
+            (None, false) => String::from("FALSE"),
+        };
+        wheres.push(where_clause);
+        Ok(Self {
+            where_clauses: wheres,
+            params,
+            sensitive_params,
+            ..self
+        })
+    }
+
+    /// Adds `pk IN UNNEST(@param)` for the primary keys of the entities.
+    pub(crate) fn add_where_pk_in(self, entities: &[Table]) -> Result<Self> {
+        trace!("add_where_pk_in({} entities)", entities.len());
+        let pk_field_name = Table::get_pk_field_name();
+        let column = Table::get_pk_db_name();
+        let mut pk_type = None;
+        let mut array_values = vec![];
+        for entity in entities {
+            let pk_param = Table::get_parameter_from_field(entity, &pk_field_name)?;
+            // a NULL primary key can not match anything
+            if let Some(QueryParameter {
+                parameter_type,
+                parameter_value: Some(value),
+                ..
+            }) = pk_param
+            {
+                pk_type = parameter_type;
+                array_values.push(value);
+            }
+        }
+        let mut wheres = self.where_clauses;
+        let mut params = self.params;
+        match pk_type {
+            None => wheres.push(String::from("FALSE")),
+            Some(pk_type) => {
+                let param_name = next_where_param_name(&params);
+                wheres.push(format!("{} IN UNNEST(@{})", column, param_name));
+                params.push(QueryParameter {
+                    name: Some(param_name),
+                    parameter_type: Some(QueryParameterType {
+                        type_: Some(String::from("ARRAY")),
+                        array_type: Some(Some(Box::new(pk_type))),
+                        ..Default::default()
+                    }),
+                    parameter_value: Some(QueryParameterValue {
+                        array_values: Some(array_values),
+                        ..Default::default()
+                    }),
+                });
+            }
+        }
+        Ok(Self {
+            where_clauses: wheres,
+            params,
+            ..self
+        })
+    }
+
+    /// Adds a where clause comparing two columns with each other (`left = right`).
+    ///
+    /// No parameters are added, both sides are resolved to their db names.
+    pub fn add_where_eq_field(self, left: &str, right: &str) -> Result<Self> {
+        trace!("add_where_eq_field({:?}, {:?})", left, right);
+        self.add_where_field_cmp(left, Comparison::Eq, right)
+    }
+
+    /// Adds a where clause comparing two columns with each other using `op`
+    /// (e.g. `left < right`).
+    ///
+    /// No parameters are added, both sides are resolved to their db names.
+    pub fn add_where_field_cmp(self, left: &str, op: Comparison, right: &str) -> Result<Self> {
+        trace!("add_where_field_cmp({:?}, {:?}, {:?})", left, op, right);
+        let left = Table::get_field_db_name(left)?;
+        let right = Table::get_field_db_name(right)?;
+        let mut wheres = self.where_clauses;
+        wheres.push(format!("{} {} {}", left, op.to_query_str(), right));
+        Ok(Self {
+            where_clauses: wheres,
+            ..self
+        })
+    }
+
+    /// Restricts a wildcard table (a table name ending in `*`, like
+    /// `events_*`) to the shards with a suffix between `lo` and `hi`
+    /// (inclusive) using the `_TABLE_SUFFIX` pseudo column.
+    pub fn wildcard_suffix_between(self, lo: &str, hi: &str) -> Result<Self> {
+        trace!("wildcard_suffix_between({:?}, {:?})", lo, hi);
+        let table_name = self.get_table_name();
+        if !table_name.ends_with('*') {
+            return Err(anyhow!(
+                "wildcard_suffix_between requires a wildcard table, but '{}' does not end with '*'",
+                table_name
+            ));
+        }
+        let mut params = self.params;
+        let lo_name = next_where_param_name(&params);
+        params.push(param(&lo_name, &lo.to_string()));
+        let hi_name = next_where_param_name(&params);
+        params.push(param(&hi_name, &hi.to_string()));
+        let mut wheres = self.where_clauses;
+        wheres.push(format!(
+            "_TABLE_SUFFIX BETWEEN @{} AND @{}",
+            lo_name, hi_name
+        ));
+        Ok(Self {
+            where_clauses: wheres,
+            params,
+            ..self
+        })
+    }
+
+    /// Removes all where clauses and their parameters, so the builder can be
+    /// reused for another query. Order by and limit are kept.
+    pub fn clear_filters(self) -> Self {
+        trace!("clear_filters()");
+        Self {
+            where_clauses: vec![],
+            params: vec![],
+            sensitive_params: vec![],
+            ..self
+        }
+    }
+
+    /// Applies a reusable [`Filter`], e.g. `builder.filter(ActiveOnly)?`.
+    pub fn filter<F: Filter<Table>>(self, filter: F) -> Result<Self> {
+        trace!("filter({})", std::any::type_name::<F>());
+        filter.apply(self)
+    }
+
+    /// Returns the where clauses, their parameters and the names of the
+    /// sensitive parameters, to merge them into another builder with
+    /// [`Self::merge_filters`].
+    pub fn into_filters(self) -> (Vec<String>, Vec<QueryParameter>, Vec<String>) {
+        let sensitive_params = self.get_sensitive_param_names();
+        (self.where_clauses, self.params, sensitive_params)
+    }
+
+    /// Appends the where clauses of another builder (see [`Self::into_filters`]),
+    /// so they are ANDed with the existing ones.
+    ///
+    /// The other parameters are renamed where needed, so they never clash
+    /// with the parameters of this builder. The values of the parameters in
+    /// `other_sensitive_params` are redacted like those of `#[db_sensitive]`
+    /// fields.
+    pub fn merge_filters(
+        self,
+        other_where: Vec<String>,
+        other_params: Vec<QueryParameter>,
+        other_sensitive_params: Vec<String>,
+    ) -> Result<Self> {
+        trace!(
+            "merge_filters({:?}, {} params)",
+            other_where,
+            other_params.len()
+        );
+        let mut params = self.params;
+        let mut sensitive_params = self.sensitive_params;
+        let mut renames = HashMap::new();
+        for mut param in other_params {
+            let name = param
+                .name
+                .clone()
+                .ok_or_else(|| anyhow!("query parameter without a name"))?;
+            // the reserved params are added again when this builder is built,
+            // so they are always renamed
+            let reserved = RESERVED_PARAMS.contains(&name.as_str());
+            let mut new_name = match name.starts_with("__WHERE_") {
+                true => next_where_param_name(&params),
+                false => name.clone(),
+            };
+            let mut i = 0;
+            while reserved && i == 0 || params.iter().any(|p| p.name.as_ref() == Some(&new_name)) {
+                new_name = format!("{}_{}", name, i);
+                i += 1;
+            }
+            if other_sensitive_params.contains(&name) {
+                sensitive_params.push(new_name.clone());
+            }
+            if new_name != name {
+                renames.insert(name, new_name.clone());
+            }
+            param.name = Some(new_name);
+            params.push(param);
+        }
+        let mut where_clauses = self.where_clauses;
+        where_clauses.extend(
+            other_where
+                .iter()
+                .map(|clause| rename_params(clause, &renames)),
+        );
+        Ok(Self {
+            where_clauses,
+            params,
+            sensitive_params,
+            ..self
+        })
+    }
+
+    /// Adds `column IN (<subquery>)`, e.g. with a select of the ids of
+    /// another table. The subquery has to select a single column, see
+    /// [`select_column`](QueryBuilder::select_column).
+    ///
+    /// The parameters of the subquery are merged like in [`Self::merge_filters`]
+    /// and its temporary functions (see [`Self::with_udf`]) are created for
+    /// this query too.
+    pub fn add_where_in_subquery<Other, OtherClient, OtherStartingData>(
+        self,
+        column: &str,
+        subquery: QueryBuilder<
+            Other,
+            QueryTypeSelect,
+            OtherClient,
+            QueryWasBuilt,
+            OtherStartingData,
+        >,
+    ) -> Result<Self>
+    where
+        Other: BigQueryTable,
+    {
+        trace!(
+            "add_where_in_subquery({}, {})",
+            column,
+            subquery.get_query_string()
+        );
+        let column = Table::get_field_db_name(column)?;
+        let clause = format!("{} IN ({})", column, subquery.get_query_string());
+        let sensitive_params = subquery.get_sensitive_param_names();
+        let mut query = self.merge_filters(vec![clause], subquery.params, sensitive_params)?;
+        for udf in subquery.udfs {
+            if !query.udfs.contains(&udf) {
+                query.udfs.push(udf);
+            }
+        }
+        Ok(query)
+    }
+
+    /// Queries the table `table_name` instead of the table of `Table`, e.g.
+    /// one shard of a sharded table like `events_20230101`. The table must
+    /// have the columns of `Table`.
+    ///
+    /// Fails if the name contains anything but letters, digits, `_` and `-`.
+    pub fn with_table_name(self, table_name: impl Into<String>) -> Result<Self> {
+        let table_name = table_name.into();
+        trace!("with_table_name({})", table_name);
+        check_table_identifier_part(&table_name)?;
+        Ok(Self {
+            table_name: Some(table_name),
+            ..self
+        })
+    }
+
+    /// Creates the temporary function `udf` for this query, so it can be
+    /// called in where clauses added with [`Self::merge_filters`].
+    pub fn with_udf(self, udf: UdfResource) -> Self {
+        trace!("with_udf({:?})", udf);
+        let mut udfs = self.udfs;
+        udfs.push(udf);
+        Self { udfs, ..self }
+    }
+
+    /// Sets the maximum number of rows.
+    ///
+    /// A limit of 0 returns no rows and is logged as a warning, use
+    /// [`Self::try_set_limit`] to reject it instead.
     pub fn set_limit(self, limit: u32) -> Self {
         trace!("set_limit({:?})", limit);
+        if limit == 0 {
+            warn!(
+                "set_limit(0) on {}: the query will return no rows",
+                Table::get_table_name()
+            );
+        }
         Self {
             limit: Some(limit),
             ..self
         }
     }
+
+    /// Same as [`Self::set_limit`] but returns an error for a limit of 0.
+    pub fn try_set_limit(self, limit: u32) -> Result<Self> {
+        trace!("try_set_limit({:?})", limit);
+        if limit == 0 {
+            return Err(anyhow!(
+                "a limit of 0 on {} would return no rows",
+                Table::get_table_name()
+            ));
+        }
+        Ok(self.set_limit(limit))
+    }
+
+    /// Removes the limit, including the `#[db_default_limit]` of the table.
+    pub fn without_limit(self) -> Self {
+        trace!("without_limit()");
+        Self {
+            limit: None,
+            ..self
+        }
+    }
+
+    /// Binds the limit as a query parameter (`LIMIT @__LIMIT`) instead of
+    /// inlining it, so queries with different limits share the same text.
+    pub fn parameterize_limit(self) -> Self {
+        trace!("parameterize_limit()");
+        Self {
+            parameterize_limit: true,
+            ..self
+        }
+    }
+
+    /// Makes `run()` fail if the query returns more than `max_rows` rows.
+    pub fn max_rows(self, max_rows: usize) -> Self {
+        self.max_rows_with_behavior(max_rows, MaxRowsBehavior::Error)
+    }
+
+    /// Limits the rows `run()` returns, see [`MaxRowsBehavior`] for what happens
+    /// when the limit is exceeded.
+    pub fn max_rows_with_behavior(self, max_rows: usize, behavior: MaxRowsBehavior) -> Self {
+        trace!("max_rows_with_behavior({:?}, {:?})", max_rows, behavior);
+        Self {
+            max_rows: Some(MaxRows::new(max_rows, behavior)),
+            ..self
+        }
+    }
     //endregion
 
     //region build query
@@ -333,7 +1120,14 @@ impl<Table: BigQueryTable + Debug, UnknownQueryType: Debug, Client: Debug, Start
             order_by_string.push_str(" ORDER BY ");
             let mut order_by = vec![];
             for (column, direction) in &self.order_by {
-                let column = Table::get_field_db_name(&column)?;
+                let is_alias = self
+                    .group_by
+                    .as_ref()
+                    .is_some_and(|group_by| group_by.has_alias(column));
+                let column = match is_alias {
+                    true => column.clone(),
+                    false => Table::get_field_db_name(column)?,
+                };
                 order_by.push(format!("{} {}", column, direction.to_query_str()));
             }
 
@@ -341,14 +1135,26 @@ impl<Table: BigQueryTable + Debug, UnknownQueryType: Debug, Client: Debug, Start
         }
         Ok(order_by_string)
     }
-    fn build_limit_string(&self) -> String {
+    fn build_limit_string(&mut self) -> Result<String> {
         trace!("build_limit_string: {:?}", self);
         let mut limit_string = String::new();
         if let Some(limit) = self.limit {
             limit_string.push_str(" LIMIT ");
-            limit_string.push_str(&limit.to_string());
+            if self.parameterize_limit {
+                let param_name = LIMIT_PARAM;
+                check_param_is_free(&self.params, param_name)?;
+                self.params.push(param(param_name, &(limit as i64)));
+                limit_string.push('@');
+                limit_string.push_str(param_name);
+            } else {
+                limit_string.push_str(&limit.to_string());
+            }
+            if let Some(offset) = self.offset {
+                limit_string.push_str(" OFFSET ");
+                limit_string.push_str(&offset.to_string());
+            }
         }
-        limit_string
+        Ok(limit_string)
     }
     //endregion
 }
@@ -363,7 +1169,7 @@ impl<Table: BigQueryTable + Default + Debug, QueryType: HasQueryType, Client: De
         self,
         data: Table,
     ) -> QueryBuilder<Table, QueryType, Client, QueryWasNotBuilt, HasStartingData<Table>> {
-        trace!("set_data({:?})", data);
+        trace!("set_data({})", data.compact_debug());
         QueryBuilder {
             starting_data: HasStartingData(data),
             query_built: PhantomData,
@@ -371,6 +1177,19 @@ impl<Table: BigQueryTable + Default + Debug, QueryType: HasQueryType, Client: De
             where_clauses: self.where_clauses,
             order_by: self.order_by,
             limit: self.limit,
+            parameterize_limit: self.parameterize_limit,
+            offset: self.offset,
+            as_of: self.as_of,
+            max_rows: self.max_rows,
+            update_matching: self.update_matching,
+            use_defaults: self.use_defaults,
+            sensitive_params: self.sensitive_params,
+            sorted_fields: self.sorted_fields,
+            row_parser: self.row_parser,
+            table_name: self.table_name,
+            udfs: self.udfs,
+            projection: self.projection,
+            group_by: self.group_by,
             query_type: PhantomData,
             table: PhantomData,
             client: self.client,
@@ -388,10 +1207,13 @@ impl<Table: BigQueryTable, Client: Default, StartingData: Default>
     {
         trace!("select()");
         QueryBuilder {
+            limit: Table::get_default_limit(),
             ..Default::default()
         }
     }
     pub fn insert() -> QueryBuilder<Table, QueryTypeInsert, NoClient, QueryWasNotBuilt, StartingData>
+    where
+        Table: BigQueryWritable,
     {
         trace!("insert()");
         QueryBuilder {
@@ -399,6 +1221,8 @@ impl<Table: BigQueryTable, Client: Default, StartingData: Default>
         }
     }
     pub fn update() -> QueryBuilder<Table, QueryTypeUpdate, NoClient, QueryWasNotBuilt, StartingData>
+    where
+        Table: BigQueryWritable,
     {
         trace!("update()");
         QueryBuilder {
@@ -406,6 +1230,8 @@ impl<Table: BigQueryTable, Client: Default, StartingData: Default>
         }
     }
     pub fn delete() -> QueryBuilder<Table, QueryTypeDelete, NoClient, QueryWasNotBuilt, StartingData>
+    where
+        Table: BigQueryWritable,
     {
         trace!("delete()");
         QueryBuilder {
@@ -425,17 +1251,30 @@ impl<Table: BigQueryTable + Default + Debug>
         QueryBuilder<Table, QueryTypeDelete, HasClient, QueryWasBuilt, HasStartingData<Table>>,
     > {
         trace!("build_query: delete: {:?}", self);
-        let table_identifier = Table::get_table_identifier_from_client(&self.client.0);
+        let table_identifier = self.get_table_identifier();
         self = self.add_field_where(&Table::get_pk_field_name())?;
         let where_clause = &self.build_where_string();
 
-        let query = format!("DELETE FROM {} {}", table_identifier, where_clause);
+        let query = format!("DELETE FROM {}{}", table_identifier, where_clause);
         Ok(QueryBuilder {
             query,
             params: self.params,
             where_clauses: self.where_clauses,
             order_by: self.order_by,
             limit: self.limit,
+            parameterize_limit: self.parameterize_limit,
+            offset: self.offset,
+            as_of: self.as_of,
+            max_rows: self.max_rows,
+            update_matching: self.update_matching,
+            use_defaults: self.use_defaults,
+            sensitive_params: self.sensitive_params,
+            sorted_fields: self.sorted_fields,
+            row_parser: self.row_parser,
+            table_name: self.table_name,
+            udfs: self.udfs,
+            projection: self.projection,
+            group_by: self.group_by,
             client: self.client,
             table: self.table,
             starting_data: self.starting_data,
@@ -455,11 +1294,15 @@ impl<Table: BigQueryTable + Default + Debug>
         QueryBuilder<Table, QueryTypeInsert, HasClient, QueryWasBuilt, HasStartingData<Table>>,
     > {
         trace!("build_query: insert: {:?}", self);
-        let table_identifier = Table::get_table_identifier_from_client(&self.client.0);
-        let params = &self.params;
-        warn!("params are not used in insert query: {:?}", params);
-        self.add_params_for_table_query_fields()?;
-        let fields = self.get_fields_string();
+        let table_identifier = self.get_table_identifier();
+        if !self.where_clauses.is_empty() {
+            warn!(
+                "where clauses are ignored in insert queries: {:?}",
+                self.where_clauses
+            );
+        }
+        self.add_params_for_table_query_fields(true)?;
+        let fields = self.get_written_fields_string();
         let values = self.get_values_params_string()?;
 
         let query = format!(
@@ -472,6 +1315,19 @@ impl<Table: BigQueryTable + Default + Debug>
             where_clauses: self.where_clauses,
             order_by: self.order_by,
             limit: self.limit,
+            parameterize_limit: self.parameterize_limit,
+            offset: self.offset,
+            as_of: self.as_of,
+            max_rows: self.max_rows,
+            update_matching: self.update_matching,
+            use_defaults: self.use_defaults,
+            sensitive_params: self.sensitive_params,
+            sorted_fields: self.sorted_fields,
+            row_parser: self.row_parser,
+            table_name: self.table_name,
+            udfs: self.udfs,
+            projection: self.projection,
+            group_by: self.group_by,
             client: self.client,
             table: self.table,
             starting_data: self.starting_data,
@@ -483,10 +1339,17 @@ impl<Table: BigQueryTable + Default + Debug>
     fn get_values_params_string(&self) -> Result<String> {
         trace!("get_values_params_string\tself: {:?}", self);
         let values: Vec<Option<String>> = self.get_value_parameter_names()?;
+        // only undefined values fall back to the column default, explicit
+        // nulls stay NULL
+        let undefined_fields = self.starting_data.0.get_undefined_fields();
         Ok(values
             .iter()
-            .map(|v| match v {
+            .zip(self.get_sorted_written_fields())
+            .map(|(v, (field, _))| match v {
                 Some(v) => format!("@{}", v),
+                None if self.use_defaults && undefined_fields.contains(&field) => {
+                    String::from("DEFAULT")
+                }
                 None => String::from("NULL"),
             })
             .collect::<Vec<String>>()
@@ -498,7 +1361,7 @@ impl<Table: BigQueryTable + Default + Debug>
     /// the query, not a parameter.
     fn get_value_parameter_names(&self) -> Result<Vec<Option<String>>> {
         trace!("get_value_parameter_names\tself: {:?}", self);
-        let mut values = self.get_sorted_selected_fields();
+        let values = self.get_sorted_written_fields();
         let existing_params: Vec<String> = self
             .params
             .iter()
@@ -515,7 +1378,7 @@ impl<Table: BigQueryTable + Default + Debug>
             values
         );
         let res = values
-            .iter_mut()
+            .iter()
             .map(|(field, _)| match Table::get_field_param_name(field) {
                 Ok(param_name) => {
                     if existing_params.contains(&param_name) {
@@ -531,6 +1394,101 @@ impl<Table: BigQueryTable + Default + Debug>
     }
 }
 
+impl<Table: BigQueryTable + Debug>
+    QueryBuilder<Table, QueryTypeInsert, HasClient, QueryWasNotBuilt, NoStartingData>
+{
+    /// Builds `INSERT INTO <table> (<columns>) SELECT ...` with the rows of
+    /// `select`, so the data never leaves BigQuery.
+    ///
+    /// The columns are matched by position: `select` has to return the
+    /// columns of `Table` in the order of their field names, like a select of
+    /// `Table` itself or of a struct with the same fields does.
+    pub fn build_query_from_select<Source, SourceClient, SourceStartingData>(
+        self,
+        select: QueryBuilder<
+            Source,
+            QueryTypeSelect,
+            SourceClient,
+            QueryWasBuilt,
+            SourceStartingData,
+        >,
+    ) -> Result<QueryBuilder<Table, QueryTypeInsert, HasClient, QueryWasBuilt, NoStartingData>>
+    where
+        Source: BigQueryTable,
+    {
+        trace!("build_query_from_select({})", select.get_query_string());
+        if !self.where_clauses.is_empty() {
+            warn!(
+                "where clauses are ignored in insert queries: {:?}",
+                self.where_clauses
+            );
+        }
+        let fields = self.get_sorted_written_fields();
+        // the #[db_expr] columns of the select are computed, not written
+        let source_exprs = Source::get_field_exprs();
+        let expr_columns: Vec<String> = match select.projection {
+            Some(_) => vec![],
+            None => select
+                .get_sorted_selected_fields()
+                .iter()
+                .filter(|(field, _)| source_exprs.contains_key(field))
+                .map(|(_, db_name)| db_name.clone())
+                .collect(),
+        };
+        let selected_count = select.get_sorted_selected_fields().len() - expr_columns.len();
+        if select.projection.is_none() && selected_count != fields.len() {
+            return Err(anyhow!(
+                "the select of {} returns {} columns, but {} has {}",
+                Source::get_table_name(),
+                selected_count,
+                Table::get_table_name(),
+                fields.len()
+            ));
+        }
+        let select_string = match expr_columns.is_empty() {
+            true => select.get_query_string().to_string(),
+            false => format!(
+                "SELECT * EXCEPT({}) FROM ({})",
+                expr_columns.join(", "),
+                select.get_query_string()
+            ),
+        };
+        let query = format!(
+            "INSERT INTO {} ({}) {}",
+            self.get_table_identifier(),
+            self.get_written_fields_string(),
+            select_string
+        );
+        let mut udfs = self.udfs;
+        udfs.extend(select.udfs);
+        Ok(QueryBuilder {
+            query,
+            params: select.params,
+            where_clauses: self.where_clauses,
+            order_by: self.order_by,
+            limit: self.limit,
+            parameterize_limit: self.parameterize_limit,
+            offset: self.offset,
+            as_of: self.as_of,
+            max_rows: self.max_rows,
+            update_matching: self.update_matching,
+            use_defaults: self.use_defaults,
+            sensitive_params: select.sensitive_params,
+            sorted_fields: self.sorted_fields,
+            row_parser: self.row_parser,
+            table_name: self.table_name,
+            udfs,
+            projection: self.projection,
+            group_by: self.group_by,
+            client: self.client,
+            table: self.table,
+            starting_data: self.starting_data,
+            query_type: self.query_type,
+            query_built: PhantomData,
+        })
+    }
+}
+
 //endregion
 //region QueryTypeUpdate
 impl<Table: BigQueryTable + Default + Debug>
@@ -542,30 +1500,49 @@ impl<Table: BigQueryTable + Default + Debug>
         QueryBuilder<Table, QueryTypeUpdate, HasClient, QueryWasBuilt, HasStartingData<Table>>,
     > {
         trace!("build_query: update: {:?}", self);
-        let table_identifier = Table::get_table_identifier_from_client(&self.client.0);
-        if self.where_clauses.is_empty() {
+        let table_identifier = self.get_table_identifier();
+        if self.update_matching {
+            if self.where_clauses.is_empty() {
+                return Err(anyhow!(
+                    "update_matching requires at least one where clause to select the rows"
+                ));
+            }
+        } else if self.where_clauses.is_empty() {
             trace!("no where clause, adding pk field to where clause");
             self = self.add_field_where(&Table::get_pk_field_name())?;
+            if let Some(version_field) = Table::get_version_field_name() {
+                trace!("adding version field to where clause");
+                self = self.add_field_where(&version_field)?;
+            }
         }
         let where_clause = self.build_where_string();
-        let params_str = format!("{:?}", &self.params);
-        self.add_params_for_table_query_fields()?;
+        self.add_params_for_table_query_fields(!self.update_matching)?;
         let fields_str = self.build_update_fields_string()?;
 
         let query = format!(
-            "update {} set {} {}",
+            "update {} set {}{}",
             table_identifier, fields_str, where_clause
         );
-        warn!(
-            "params are not used in update query: {:?}, query: {}",
-            params_str, query
-        );
+        trace!("build_query: update query: {}", query);
         Ok(QueryBuilder {
             query,
             params: self.params,
             where_clauses: self.where_clauses,
             order_by: self.order_by,
             limit: self.limit,
+            parameterize_limit: self.parameterize_limit,
+            offset: self.offset,
+            as_of: self.as_of,
+            max_rows: self.max_rows,
+            update_matching: self.update_matching,
+            use_defaults: self.use_defaults,
+            sensitive_params: self.sensitive_params,
+            sorted_fields: self.sorted_fields,
+            row_parser: self.row_parser,
+            table_name: self.table_name,
+            udfs: self.udfs,
+            projection: self.projection,
+            group_by: self.group_by,
             client: self.client,
             table: self.table,
             starting_data: self.starting_data,
@@ -576,10 +1553,17 @@ impl<Table: BigQueryTable + Default + Debug>
 
     fn build_update_fields_string(&mut self) -> Result<String> {
         trace!("build_update_fields_string");
+        let version_db_name = match Table::get_version_field_name() {
+            Some(version_field) => Some(Table::get_field_db_name(&version_field)?),
+            None => None,
+        };
         let result = self
             .get_value_parameter_names()?
             .into_iter()
             .map(|(f, p)| match p {
+                _ if Some(&f) == version_db_name.as_ref() => {
+                    format!("{0} = IFNULL({0}, 0) + 1", f)
+                }
                 Some(p) => format!("{} = @{}", f, p),
                 None => format!("{} = NULL", f),
             })
@@ -590,7 +1574,16 @@ impl<Table: BigQueryTable + Default + Debug>
     }
 
     fn get_value_parameter_names(&self) -> Result<Vec<(String, Option<String>)>> {
-        let mut values = self.get_sorted_selected_fields();
+        let mut values = self.get_sorted_written_fields();
+        if self.update_matching {
+            let pk_field_name = Table::get_pk_field_name();
+            values.retain(|(field, _)| *field != pk_field_name);
+        }
+        let undefined_fields = self.starting_data.0.get_undefined_fields();
+        values.retain(|(field, _)| !undefined_fields.contains(field));
+        if values.is_empty() {
+            return Err(anyhow!("update query has no fields to set"));
+        }
         let existing_params: Vec<String> = self
             .params
             .iter()
@@ -610,12 +1603,55 @@ impl<Table: BigQueryTable + Default + Debug>
     }
 }
 
+impl<Table: BigQueryTable, Client, StartingData>
+    QueryBuilder<Table, QueryTypeInsert, Client, QueryWasNotBuilt, StartingData>
+{
+    /// Writes `DEFAULT` instead of `NULL` for undefined fields (see
+    /// [`crate::data::param_conversion::Patch`]), so the default value of the
+    /// column in the schema is used. Explicit nulls are still written as `NULL`.
+    pub fn use_defaults(self) -> Self {
+        trace!("use_defaults()");
+        Self {
+            use_defaults: true,
+            ..self
+        }
+    }
+}
+
+impl<Table: BigQueryTable, Client, StartingData>
+    QueryBuilder<Table, QueryTypeUpdate, Client, QueryWasNotBuilt, StartingData>
+{
+    /// Updates all rows matching the added where clauses instead of the row
+    /// with the primary key of the data.
+    ///
+    /// The primary key is neither added to the where clause nor set, and at
+    /// least one where clause is required.
+    pub fn update_matching(self) -> Self {
+        trace!("update_matching()");
+        Self {
+            update_matching: true,
+            ..self
+        }
+    }
+}
+
 //endregion
 //region QueryTypeSelect
 //region client not needed
 impl<Table: BigQueryTable + Debug, Client: Debug, StartingData: Debug>
     QueryBuilder<Table, QueryTypeSelect, Client, QueryWasNotBuilt, StartingData>
 {
+    /// Reads the table as it was at `time` (`FOR SYSTEM_TIME AS OF`).
+    ///
+    /// BigQuery only keeps the history of the time travel window (7 days by default).
+    pub fn as_of(self, time: DateTime<Utc>) -> Self {
+        trace!("as_of({:?})", time);
+        Self {
+            as_of: Some(time),
+            ..self
+        }
+    }
+
     pub fn add_order_by(
         mut self,
         column_name: impl Into<String>,
@@ -624,6 +1660,254 @@ impl<Table: BigQueryTable + Debug, Client: Debug, StartingData: Debug>
         self.order_by.push((column_name.into(), direction));
         self
     }
+
+    /// Appends the primary key as the last `ORDER BY` column (ascending),
+    /// unless it is already ordered by, so rows with equal sort values always
+    /// come back in the same order.
+    pub fn stable_order(mut self) -> Self {
+        trace!("stable_order()");
+        let pk_field_name = Table::get_pk_field_name();
+        if !self
+            .order_by
+            .iter()
+            .any(|(column, _)| *column == pk_field_name)
+        {
+            self.order_by
+                .push((pk_field_name, OrderDirection::Ascending));
+        }
+        self
+    }
+
+    /// Only selects the columns of these fields (and the primary key), the
+    /// other fields of the returned rows keep their default value.
+    pub fn select_fields(self, fields: &[&str]) -> Result<Self>
+    where
+        Table: Default,
+    {
+        trace!("select_fields({:?})", fields);
+        let pk_field_name = Table::get_pk_field_name();
+        for field in fields {
+            Table::get_field_db_name(field)?;
+        }
+        let mut selected: Vec<(String, String)> = Table::get_query_fields(true)
+            .into_iter()
+            .filter(|(field, _)| *field == pk_field_name || fields.contains(&field.as_str()))
+            .collect();
+        selected.sort();
+        Ok(Self {
+            sorted_fields: OnceLock::from(selected),
+            row_parser: Some(Table::new_from_partial_query_result_row),
+            ..self
+        })
+    }
+
+    /// Selects the rows of page `page` (starting at 0) with `page_size` rows
+    /// per page, using `LIMIT` and `OFFSET`.
+    ///
+    /// Calls [`Self::stable_order`] so the pages don't overlap.
+    ///
+    /// Fails if the offset of the page does not fit in a `u32`.
+    pub fn paged(self, page: u32, page_size: u32) -> Result<Self> {
+        trace!("paged({}, {})", page, page_size);
+        let offset = page.checked_mul(page_size).ok_or_else(|| {
+            anyhow!(
+                "the offset of page {} with {} rows per page is too large",
+                page,
+                page_size
+            )
+        })?;
+        Ok(Self {
+            limit: Some(page_size),
+            offset: Some(offset),
+            ..self
+        }
+        .stable_order())
+    }
+
+    /// Groups the rows of the table by all other columns and collects the
+    /// columns of the `#[db_record]` field `children_field` (a `Vec` of a
+    /// [`BigQueryColumns`](crate::data::BigQueryColumns) struct) into it:
+    ///
+    /// `SELECT parent_id, ARRAY_AGG(STRUCT(a, b)) AS children FROM ... GROUP BY parent_id`
+    ///
+    /// `Table` describes the grouped rows, so its table has the parent
+    /// columns and the child columns side by side.
+    ///
+    /// This is a [`Self::group_by`] whose rows still match `Table`, so they
+    /// can be read with `run`. It replaces an earlier `group_by`.
+    pub fn aggregate_children(self, children_field: &str) -> Result<Self> {
+        trace!("aggregate_children({})", children_field);
+        Table::get_field_db_name(children_field)?;
+        let children_columns = match Table::get_record_columns().remove(children_field) {
+            Some(columns) => columns,
+            None => {
+                return Err(anyhow!(
+                    "{} is not a #[db_record] field of {}",
+                    children_field,
+                    Table::get_table_name()
+                ))
+            }
+        };
+        let exprs = Table::get_field_exprs();
+        let mut fields = vec![];
+        let mut columns = vec![];
+        let mut aliases = vec![];
+        for (field, db_name) in self.get_sorted_selected_fields() {
+            if field == children_field {
+                fields.push(format!(
+                    "ARRAY_AGG(STRUCT({})) AS {}",
+                    children_columns.join(", "),
+                    db_name
+                ));
+                aliases.push(db_name.clone());
+                continue;
+            }
+            columns.push(db_name.clone());
+            fields.push(match exprs.get(field) {
+                Some(expr) => format!("{} AS {}", expr, db_name),
+                None => db_name.clone(),
+            });
+        }
+        Ok(Self {
+            group_by: Some(GroupBy {
+                fields,
+                columns,
+                aliases,
+                parses_as_table: true,
+            }),
+            ..self
+        })
+    }
+
+    /// Groups the rows by the columns of `fields` and selects these columns
+    /// and the `aggregates`, pairs of an expression and its alias like
+    /// `("COUNT(*)", "cnt")`.
+    ///
+    /// The aliases can be used in [`Self::add_order_by`]. The rows don't
+    /// match `Table` anymore, get them with
+    /// [`run_raw`](QueryBuilder::run_raw) instead of `run`.
+    pub fn group_by(self, fields: &[&str], aggregates: &[(&str, &str)]) -> Result<Self> {
+        trace!("group_by({:?}, {:?})", fields, aggregates);
+        let columns = fields
+            .iter()
+            .map(|field| Table::get_field_db_name(field))
+            .collect::<Result<Vec<String>>>()?;
+        if columns.is_empty() && aggregates.is_empty() {
+            return Err(anyhow!("group_by needs at least one field or aggregate"));
+        }
+        let mut fields = columns.clone();
+        let mut aliases = vec![];
+        for (expr, alias) in aggregates {
+            safe_identifier(alias)?;
+            fields.push(format!("{} AS {}", expr, alias));
+            aliases.push(alias.to_string());
+        }
+        Ok(Self {
+            group_by: Some(GroupBy {
+                fields,
+                columns,
+                aliases,
+                parses_as_table: false,
+            }),
+            ..self
+        })
+    }
+
+    /// Only selects the column of this field, e.g. for a subquery of
+    /// [`add_where_in_subquery`](QueryBuilder::add_where_in_subquery).
+    ///
+    /// Get the rows with [`run_raw`](QueryBuilder::run_raw).
+    pub fn select_column(self, field: &str) -> Result<Self> {
+        trace!("select_column({})", field);
+        let db_name = Table::get_field_db_name(field)?;
+        Ok(Self {
+            projection: Some(db_name),
+            ..self
+        })
+    }
+
+    /// Selects `* EXCEPT (...)` without the columns of these fields.
+    ///
+    /// The rows don't match `Table` anymore, get them with
+    /// [`run_raw`](QueryBuilder::run_raw) instead of `run`.
+    pub fn select_star_except(self, fields: &[&str]) -> Result<Self> {
+        trace!("select_star_except({:?})", fields);
+        let db_names = fields
+            .iter()
+            .map(|field| Table::get_field_db_name(field))
+            .collect::<Result<Vec<String>>>()?;
+        if db_names.is_empty() {
+            return Err(anyhow!("select_star_except needs at least one field"));
+        }
+        Ok(Self {
+            projection: Some(format!("* EXCEPT ({})", db_names.join(", "))),
+            ..self
+        })
+    }
+
+    /// Selects `* REPLACE (...)`, where every pair is an expression and the
+    /// field whose column it replaces, e.g. `("UPPER(info1)", "info1")`.
+    ///
+    /// Like [`Self::select_star_except`], get the rows with
+    /// [`run_raw`](QueryBuilder::run_raw).
+    pub fn select_star_replace(self, replacements: &[(&str, &str)]) -> Result<Self> {
+        trace!("select_star_replace({:?})", replacements);
+        let replacements = replacements
+            .iter()
+            .map(|(expression, field)| {
+                let db_name = Table::get_field_db_name(field)?;
+                Ok(format!("{} AS {}", expression, db_name))
+            })
+            .collect::<Result<Vec<String>>>()?;
+        if replacements.is_empty() {
+            return Err(anyhow!(
+                "select_star_replace needs at least one replacement"
+            ));
+        }
+        Ok(Self {
+            projection: Some(format!("* REPLACE ({})", replacements.join(", "))),
+            ..self
+        })
+    }
+}
+
+impl<Table: BigQueryTable + Default + Debug, Client: Debug>
+    QueryBuilder<Table, QueryTypeSelect, Client, QueryWasNotBuilt, HasStartingData<Table>>
+{
+    /// Adds an equality where clause for every non-null field of the starting data.
+    ///
+    /// Null fields are skipped, use [`Self::add_where_by_example_including_nulls`]
+    /// to also match them with `is NULL`.
+    pub fn add_where_by_example(self) -> Result<Self> {
+        trace!("add_where_by_example()");
+        self.add_where_by_example_internal(false)
+    }
+
+    /// Same as [`Self::add_where_by_example`] but also adds an `is NULL` clause
+    /// for every null field of the starting data.
+    pub fn add_where_by_example_including_nulls(self) -> Result<Self> {
+        trace!("add_where_by_example_including_nulls()");
+        self.add_where_by_example_internal(true)
+    }
+
+    fn add_where_by_example_internal(mut self, include_nulls: bool) -> Result<Self> {
+        // all fields, not only the selected ones
+        // #[db_expr] fields are computed, so they have no stored value to match
+        let exprs = Table::get_field_exprs();
+        let mut fields: Vec<String> = Table::get_query_fields(true)
+            .into_keys()
+            .filter(|field| !exprs.contains_key(field))
+            .collect();
+        fields.sort();
+        for field in fields {
+            let param = Table::get_parameter_from_field(&self.starting_data.0, &field)?;
+            let has_value = matches!(param, Some(param) if param.parameter_value.is_some());
+            if has_value || include_nulls {
+                self = self.add_field_where(&field)?;
+            }
+        }
+        Ok(self)
+    }
 }
 
 //endregion
@@ -632,24 +1916,70 @@ impl<Table: BigQueryTable + Debug, StartingData: Debug>
     QueryBuilder<Table, QueryTypeSelect, HasClient, QueryWasNotBuilt, StartingData>
 {
     pub fn build_query(
-        self,
+        mut self,
     ) -> Result<QueryBuilder<Table, QueryTypeSelect, HasClient, QueryWasBuilt, StartingData>> {
         trace!("build_query: select: {:?}", self);
+        if let Some(column) = Table::get_required_partition_filter() {
+            let has_filter = self
+                .where_clauses
+                .iter()
+                .any(|clause| references_column(clause, &column));
+            if !has_filter {
+                return Err(anyhow!(
+                    "{} requires a where clause on the partition column {}",
+                    Table::get_table_name(),
+                    column
+                ));
+            }
+        }
 
-        let table_identifier = Table::get_table_identifier_from_client(&self.client.0);
-        let fields_str = self.get_fields_string();
+        let mut table_identifier = self.get_table_identifier();
+        if let Some(as_of) = self.as_of {
+            let param_name = AS_OF_PARAM;
+            check_param_is_free(&self.params, param_name)?;
+            let mut as_of_param = param(param_name, &as_of);
+            as_of_param.parameter_type = Some(QueryParameterType {
+                type_: Some(String::from("TIMESTAMP")),
+                ..Default::default()
+            });
+            self.params.push(as_of_param);
+            table_identifier.push_str(&format!(" FOR SYSTEM_TIME AS OF @{}", param_name));
+        }
+        let limit_clause = self.build_limit_string()?;
+        let (fields_str, group_by_clause) = match (&self.projection, &self.group_by) {
+            (Some(projection), _) => (projection.clone(), String::new()),
+            (None, Some(group_by)) => group_by.to_query_strings(),
+            (None, None) => (self.get_fields_string(), String::new()),
+        };
         let where_clause = self.build_where_string();
         let order_by_clause = self.build_order_by_string()?;
-        let limit_clause = self.build_limit_string();
         let query = format!(
-            "SELECT {} FROM {}{}{}{}",
-            fields_str, table_identifier, where_clause, order_by_clause, limit_clause
+            "SELECT {} FROM {}{}{}{}{}",
+            fields_str,
+            table_identifier,
+            where_clause,
+            group_by_clause,
+            order_by_clause,
+            limit_clause
         );
         Ok(QueryBuilder {
             query,
             where_clauses: self.where_clauses,
             order_by: self.order_by,
             limit: self.limit,
+            parameterize_limit: self.parameterize_limit,
+            offset: self.offset,
+            as_of: self.as_of,
+            max_rows: self.max_rows,
+            update_matching: self.update_matching,
+            use_defaults: self.use_defaults,
+            sensitive_params: self.sensitive_params,
+            sorted_fields: self.sorted_fields,
+            row_parser: self.row_parser,
+            table_name: self.table_name,
+            udfs: self.udfs,
+            projection: self.projection,
+            group_by: self.group_by,
             client: self.client,
             params: self.params,
             table: self.table,
@@ -660,6 +1990,43 @@ impl<Table: BigQueryTable + Debug, StartingData: Debug>
     }
 }
 
+impl<Table: BigQueryTable + Default + Debug, StartingData: Debug>
+    QueryBuilder<Table, QueryTypeSelect, HasClient, QueryWasNotBuilt, StartingData>
+{
+    /// Runs the query selecting only the primary key column and returns the
+    /// primary keys of the matching rows.
+    ///
+    /// `PK` must have the BigQuery type of the primary key.
+    pub async fn pks<PK>(self) -> Result<Vec<PK>>
+    where
+        PK: BigDataValueType + 'static,
+    {
+        trace!("pks()");
+        let pk_field_name = Table::get_pk_field_name();
+        let pk_db_name = Table::get_pk_db_name();
+        check_pk_type::<Table, PK>()?;
+        let value_type = PK::convert_type_to_bigquery_type();
+        let query = self.select_fields(&[])?.build_query()?;
+        let query_request = query.build_query_request();
+        let (_, query_response) = run_query_with_client(&query.client.0, query_request).await?;
+        let rows = rows_from_query_response(query_response)?;
+        debug!("pks: {} rows", rows.len());
+        let pks = rows
+            .iter()
+            .map(|row| {
+                let value = row.get(&pk_db_name).unwrap_or(&Value::Null);
+                PK::from_param(value).map_err(|e| {
+                    FieldConversionError::new(&pk_db_name, &pk_field_name, &value_type, value, e)
+                        .into()
+                })
+            })
+            .collect::<Result<Vec<PK>>>()?;
+        if let Some(max_rows) = &query.max_rows {
+            return max_rows.apply(pks);
+        }
+        Ok(pks)
+    }
+}
 //endregion
 //endregion
 //region with_client
@@ -679,12 +2046,71 @@ impl<Table: BigQueryTable, QueryType, StartingData>
             where_clauses: self.where_clauses,
             order_by: self.order_by,
             limit: self.limit,
+            parameterize_limit: self.parameterize_limit,
+            offset: self.offset,
+            as_of: self.as_of,
+            max_rows: self.max_rows,
+            update_matching: self.update_matching,
+            use_defaults: self.use_defaults,
+            sensitive_params: self.sensitive_params,
+            sorted_fields: self.sorted_fields,
+            row_parser: self.row_parser,
+            table_name: self.table_name,
+            udfs: self.udfs,
+            projection: self.projection,
+            group_by: self.group_by,
             params: self.params,
             starting_data: self.starting_data,
         }
     }
 }
 
+impl<Table, QueryType, QueryBuilt, StartingData>
+    QueryBuilder<Table, QueryType, HasClient, QueryBuilt, StartingData>
+{
+    /// Sets the request id for this query only, see
+    /// [`BigqueryClient::with_request_id`].
+    pub fn with_request_id(self, request_id: &str) -> Self {
+        Self {
+            client: HasClient(self.client.0.with_request_id(request_id)),
+            ..self
+        }
+    }
+}
+
+//endregion
+//region redaction
+impl<Table: BigQueryTable, QueryType, QueryBuilt, StartingData>
+    QueryBuilder<Table, QueryType, HasClient, QueryBuilt, StartingData>
+{
+    /// Returns the params with the values of sensitive params replaced by `<redacted>`.
+    ///
+    /// A param is sensitive if it belongs to a `#[db_sensitive]` field or if
+    /// the client has `redact_params` enabled.
+    pub fn get_redacted_params(&self) -> Vec<QueryParameter> {
+        let redact_all = self.client.0.get_redact_params();
+        let sensitive_params = self.get_sensitive_param_names();
+        self.params
+            .iter()
+            .cloned()
+            .map(|mut param| {
+                let is_sensitive = param
+                    .name
+                    .as_ref()
+                    .is_some_and(|name| sensitive_params.contains(name));
+                if redact_all || is_sensitive {
+                    if let Some(value) = param.parameter_value.as_mut() {
+                        value.value = Some(String::from("<redacted>"));
+                        value.array_values = None;
+                        value.struct_values = None;
+                    }
+                }
+                param
+            })
+            .collect()
+    }
+}
+
 //endregion
 //region un_build & get query string
 impl<Table: BigQueryTable, QueryType, Client, StartingData>
@@ -701,6 +2127,19 @@ impl<Table: BigQueryTable, QueryType, Client, StartingData>
             where_clauses: self.where_clauses,
             order_by: self.order_by,
             limit: self.limit,
+            parameterize_limit: self.parameterize_limit,
+            offset: self.offset,
+            as_of: self.as_of,
+            max_rows: self.max_rows,
+            update_matching: self.update_matching,
+            use_defaults: self.use_defaults,
+            sensitive_params: self.sensitive_params,
+            sorted_fields: self.sorted_fields,
+            row_parser: self.row_parser,
+            table_name: self.table_name,
+            udfs: self.udfs,
+            projection: self.projection,
+            group_by: self.group_by,
             params: self.params,
             starting_data: self.starting_data,
             query_built: PhantomData,
@@ -709,6 +2148,36 @@ impl<Table: BigQueryTable, QueryType, Client, StartingData>
     pub fn get_query_string(&self) -> &str {
         &self.query
     }
+
+    /// Puts the definitions of the temporary functions in front of the query.
+    fn with_udf_definitions(&self, query: &str) -> String {
+        let mut result = String::new();
+        for udf in &self.udfs {
+            result.push_str(udf.definition());
+            result.push_str(";\n");
+        }
+        result.push_str(query);
+        result
+    }
+    /// Returns all parameters that are bound to the query, with their values.
+    ///
+    /// Use [`get_redacted_params`](QueryBuilder::get_redacted_params) for logging.
+    pub fn params(&self) -> &[QueryParameter] {
+        &self.params
+    }
+}
+
+impl<Table: BigQueryTable, Client, StartingData>
+    QueryBuilder<Table, QueryTypeSelect, Client, QueryWasBuilt, StartingData>
+{
+    /// Returns true if the select has neither a where clause nor a limit, so
+    /// it reads every row of the table.
+    ///
+    /// Note that BigQuery bills the scanned columns even with a limit, so
+    /// this only guards against unbounded results, not against the cost.
+    pub fn is_full_scan(&self) -> bool {
+        self.where_clauses.is_empty() && self.limit.is_none()
+    }
 }
 
 //endregion
@@ -716,73 +2185,512 @@ impl<Table: BigQueryTable, QueryType, Client, StartingData>
 impl<Table: BigQueryTable, QueryType: HasQueryType, StartingData>
     QueryBuilder<Table, QueryType, HasClient, QueryWasBuilt, StartingData>
 {
-    pub async fn run(self) -> Result<QueryResultType<Table>> {
-        trace!("run query: {}", self.query);
+    /// Builds the request that is sent to BigQuery when running the query.
+    pub fn build_query_request(&self) -> QueryRequest {
+        let redacted_params = self.get_redacted_params();
+        let request_id = self.client.0.get_request_id();
         debug!(
-            "Running query with params: {}\t params: {:?}",
-            self.query, self.params
+            request_id,
+            "Running query with params: {}\t params: {:?}", self.query, redacted_params
         );
-        let sorted_fields = self.get_sorted_selected_fields();
-        let query = Some(self.query);
         let query_parameters = match self.params.is_empty() {
             true => None,
-            false => Some(self.params),
+            false => Some(self.params.clone()),
         };
         let query_request = QueryRequest {
-            query,
+            query: Some(self.with_udf_definitions(&self.query)),
             query_parameters,
             use_legacy_sql: Some(false),
+            labels: self.client.0.get_request_labels(),
             ..Default::default()
         };
-        let client = self.client.0;
-        debug!("query_request: {:?}", query_request);
-        let (_, query_response) = run_query_with_client(&client, query_request).await?;
-        // if let Some(errors) = query_response.errors {
-        //     return Err(BigqueryError::new("Query returned errors", Some(errors)).into());
-        // }
+        debug!(
+            "query_request: {:?}",
+            QueryRequest {
+                query_parameters: Some(redacted_params),
+                ..query_request.clone()
+            }
+        );
+        query_request
+    }
+
+    /// Runs a DML query (insert, update or delete) and returns the number
+    /// of affected rows.
+    pub async fn run_dml(self) -> Result<i64> {
+        trace!("run_dml query: {}", self.query);
+        let query_request = self.build_query_request();
+        let table_name = self.get_table_name();
+        let (_, query_response) =
+            Self::run_query_request(&self.client.0, &table_name, query_request).await?;
+        let affected_rows = query_response.num_dml_affected_rows.unwrap_or(0);
+        debug!("affected rows: {}", affected_rows);
+        Ok(affected_rows)
+    }
+
+    pub async fn run(self) -> Result<QueryResultType<Table>> {
+        let (result, _) = self.run_with_job_id().await?;
+        Ok(result)
+    }
+
+    /// Like [`Self::run`], but also returns the id of the BigQuery job, to
+    /// find it in the BigQuery console or Cloud Logging.
+    pub async fn run_with_job_id(self) -> Result<(QueryResultType<Table>, Option<String>)> {
+        trace!("run query: {}", self.query);
+        self.check_can_parse_rows()?;
+        let query_request = self.build_query_request();
+        let table_name = self.get_table_name();
+        let (_, query_response) =
+            Self::run_query_request(&self.client.0, &table_name, query_request)
+                .await
+                .map_err(|error| self.with_redacted_params(error))?;
+        let job_id = query_response
+            .job_reference
+            .as_ref()
+            .and_then(|job_reference| job_reference.job_id.clone());
+        Ok((self.parse_query_response(query_response)?, job_id))
+    }
+
+    /// Runs the request. An insert into a table that does not exist creates
+    /// the table first if the client has `auto_create` enabled.
+    async fn run_query_request(
+        client: &BigqueryClient,
+        table_name: &str,
+        query_request: QueryRequest,
+    ) -> Result<(Response<Body>, QueryResponse)> {
+        match run_query_with_client(client, query_request.clone()).await {
+            Err(error)
+                if QueryType::IS_INSERT
+                    && client.get_auto_create()
+                    && is_table_not_found::<Table>(&error, client, table_name) =>
+            {
+                warn!(
+                    "table {} does not exist, creating it (auto_create)",
+                    table_name
+                );
+                create_table_with_name::<Table>(client, table_name).await?;
+                run_query_with_client(client, query_request).await
+            }
+            result => result,
+        }
+    }
+
+    /// Adds the redacted params to a [`BigqueryError`] of this query.
+    fn with_redacted_params(&self, mut error: anyhow::Error) -> anyhow::Error {
+        if let Some(bigquery_error) = error.downcast_mut::<BigqueryError>() {
+            bigquery_error.params = Some(self.get_redacted_params());
+        }
+        error
+    }
+
+    /// Like [`Self::run`], but stops waiting for the query as soon as
+    /// `cancel` completes and asks BigQuery to cancel the job.
+    ///
+    /// `cancel` can be any future, for example
+    /// `CancellationToken::cancelled()` or a timeout. On cancellation a
+    /// [`QueryCancelledError`](crate::data::jobs::QueryCancelledError) is
+    /// returned.
+    pub async fn run_cancellable<C>(self, cancel: C) -> Result<QueryResultType<Table>>
+    where
+        C: Future<Output = ()>,
+    {
+        trace!("run_cancellable query: {}", self.query);
+        self.check_can_parse_rows()?;
+        let query_request = self.build_query_request();
+        let query_response =
+            run_query_job_cancellable(&self.client.0, query_request, cancel).await?;
+        self.parse_query_response(query_response)
+    }
+
+    fn check_can_parse_rows(&self) -> Result<()> {
+        if let Some(projection) = &self.projection {
+            return Err(anyhow!(
+                "the rows of SELECT {} can not be parsed as {}, use run_raw",
+                projection,
+                Table::get_table_name()
+            ));
+        }
+        match &self.group_by {
+            Some(group_by) if !group_by.parses_as_table => Err(anyhow!(
+                "the rows of a group_by can not be parsed as {}, use run_raw",
+                Table::get_table_name()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses the rows of one page of the response as `Table`.
+    fn parse_rows(&self, query_response: QueryResponse) -> Result<Vec<Table>> {
+        let client = &self.client.0;
+        match self.row_parser {
+            None => Table::new_from_query_response(client.clone(), query_response),
+            Some(parse_row) => {
+                // the cells are mapped by position if the response has no schema
+                let selected_columns: Vec<String> = self
+                    .get_sorted_selected_fields()
+                    .iter()
+                    .map(|(_, db_name)| db_name.clone())
+                    .collect();
+                rows_from_query_response_or_positional(query_response, &selected_columns)?
+                    .iter()
+                    .map(|row| parse_row(client.clone(), row))
+                    .collect()
+            }
+        }
+    }
+
+    fn parse_query_response(self, query_response: QueryResponse) -> Result<QueryResultType<Table>> {
         debug!(
             "total rows returned: {}",
             query_response.total_rows.unwrap_or(0)
         );
-        //TODO: pagination is not implemented
-        let mut result: Vec<Table> = vec![];
-        for row in query_response.rows.unwrap_or_default() {
-            let mut row_result: HashMap<String, Value> = HashMap::new();
-            for (i, field) in row.f.unwrap_or_default().into_iter().enumerate() {
-                let field_db_name = sorted_fields[i].1.clone();
-                let field_value = field.v.unwrap_or(Value::Null);
-                row_result.insert(field_db_name, field_value);
-            }
-            let row_result = Table::new_from_query_result_row(client.clone(), &row_result)?;
-            result.push(row_result);
+        if let Some(max_rows) = &self.max_rows {
+            max_rows.check_total(query_response.total_rows.unwrap_or(0))?;
         }
+        //TODO: pagination is not implemented
+        let mut result = self.parse_rows(query_response)?;
         debug!("total rows parsed: {}", result.len());
+        if let Some(max_rows) = &self.max_rows {
+            result = max_rows.apply(result)?;
+        }
 
         Ok(QueryResultType::WithRowData(result))
     }
 }
+
+impl<Table: BigQueryTable + Default + Debug>
+    QueryBuilder<Table, QueryTypeInsert, HasClient, QueryWasBuilt, HasStartingData<Table>>
+{
+    /// Builds the request for [`Self::insert_returning`]: a script with the
+    /// insert followed by a select of the inserted row.
+    ///
+    /// The row is selected by its primary key. If the primary key is NULL
+    /// (because the server generates it), it is matched on all other
+    /// non-null fields instead.
+    pub fn build_insert_returning_request(&self) -> Result<QueryRequest> {
+        trace!("build_insert_returning_request()");
+        let param_names: Vec<&str> = self
+            .params
+            .iter()
+            .filter_map(|p| p.name.as_deref())
+            .collect();
+        let pk_field_name = Table::get_pk_field_name();
+        let match_fields = match param_names
+            .contains(&Table::get_field_param_name(&pk_field_name)?.as_str())
+        {
+            true => vec![pk_field_name],
+            false => {
+                let field_types = Table::get_field_types();
+                let mut fields: Vec<String> = Table::get_query_fields(true)
+                    .into_keys()
+                    // records can not be compared with `=`
+                    .filter(|field| field_types.get(field).map(String::as_str) != Some("RECORD"))
+                    .collect();
+                fields.sort();
+                fields
+            }
+        };
+        let mut where_clauses = vec![];
+        for field in match_fields {
+            let param_name = Table::get_field_param_name(&field)?;
+            if param_names.contains(&param_name.as_str()) {
+                let db_name = Table::get_field_db_name(&field)?;
+                where_clauses.push(format!("{} = @{}", db_name, param_name));
+            }
+        }
+        if where_clauses.is_empty() {
+            return Err(anyhow!(
+                "can not select the inserted row of {}: all fields are NULL",
+                self.get_table_name()
+            ));
+        }
+        let table_identifier = self.get_table_identifier();
+        let query = format!(
+            "{};\nSELECT {} FROM {} WHERE {};",
+            self.query,
+            self.get_fields_string(),
+            table_identifier,
+            where_clauses.join(" AND ")
+        );
+        Ok(QueryRequest {
+            query: Some(self.with_udf_definitions(&query)),
+            query_parameters: Some(self.params.clone()),
+            use_legacy_sql: Some(false),
+            ..Default::default()
+        })
+    }
+
+    /// Runs the insert and returns the row as it was persisted, including
+    /// the values BigQuery filled in.
+    ///
+    /// BigQuery has no `RETURNING`, so the insert and the select run as one
+    /// script, see [`Self::build_insert_returning_request`].
+    pub async fn insert_returning(self) -> Result<Table> {
+        trace!("insert_returning()");
+        let query_request = self.build_insert_returning_request()?;
+        let client = self.client.0;
+        let (_, query_response) = run_query_with_client(&client, query_request).await?;
+        let mut rows = Table::new_from_query_response(client, query_response)?;
+        match rows.len() {
+            1 => Ok(rows.remove(0)),
+            0 => Err(anyhow!("the inserted row was not found")),
+            n => Err(anyhow!(
+                "{} rows match the inserted row, the matched fields are not unique",
+                n
+            )),
+        }
+    }
+}
+//endregion
+//region export
+impl<Table: BigQueryTable + Debug, StartingData>
+    QueryBuilder<Table, QueryTypeSelect, HasClient, QueryWasBuilt, StartingData>
+{
+    /// Runs the query and writes every row as one JSON object per line
+    /// (keyed by the db names) to the writer.
+    ///
+    /// The results are fetched page by page and each page is written as it
+    /// arrives, so only one page is kept in memory.
+    ///
+    /// Returns the number of rows written.
+    pub async fn export_ndjson<W: Write>(self, mut writer: W) -> Result<u64> {
+        trace!("export_ndjson()");
+        self.check_can_parse_rows()?;
+        let query_request = self.build_query_request();
+        let (_, mut query_response) = run_query_with_client(&self.client.0, query_request)
+            .await
+            .map_err(|error| self.with_redacted_params(error))?;
+        if let Some(max_rows) = &self.max_rows {
+            max_rows.check_total(query_response.total_rows.unwrap_or(0))?;
+        }
+        let limit = self.max_rows.map(|max_rows| max_rows.limit as u64);
+        let mut count = 0;
+        loop {
+            let page_token = query_response.page_token.take();
+            let job_reference = query_response.job_reference.clone();
+            for row in self.parse_rows(query_response)? {
+                if limit.is_some_and(|limit| count >= limit) {
+                    break;
+                }
+                serde_json::to_writer(&mut writer, &row.to_json()?)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            let page_token = match page_token {
+                Some(page_token) if limit.is_none_or(|limit| count < limit) => page_token,
+                _ => break,
+            };
+            let job_reference =
+                job_reference.ok_or_else(|| anyhow!("the query response has no job reference"))?;
+            trace!(
+                "export_ndjson: {} rows written, fetching the next page",
+                count
+            );
+            query_response =
+                get_query_results_page(&self.client.0, &job_reference, &page_token).await?;
+        }
+        writer.flush()?;
+        debug!("export_ndjson: wrote {} rows", count);
+        Ok(count)
+    }
+
+    /// Runs the query and returns every row keyed by its column names,
+    /// like [`run_raw`](crate::data::run_raw).
+    pub async fn run_raw(self) -> Result<Vec<HashMap<String, Value>>> {
+        trace!("run_raw query: {}", self.query);
+        let query_request = self.build_query_request();
+        let (_, query_response) = run_query_with_client(&self.client.0, query_request).await?;
+        let rows = rows_from_query_response(query_response)?;
+        debug!("run_raw: {} rows", rows.len());
+        match &self.max_rows {
+            Some(max_rows) => max_rows.apply(rows),
+            None => Ok(rows),
+        }
+    }
+
+    /// Runs the query and maps every row (keyed by its column names, see
+    /// [`Self::run_raw`]) with `f`, instead of parsing it as `Table`.
+    pub async fn run_map<R, F>(self, f: F) -> Result<Vec<R>>
+    where
+        F: Fn(&HashMap<String, Value>) -> Result<R>,
+    {
+        trace!("run_map()");
+        self.run_raw().await?.iter().map(f).collect()
+    }
+
+    /// Runs the query and deserializes every row (keyed by its column names)
+    /// into `R`, see [`deserialize_rows`].
+    ///
+    /// Useful for projections and aggregates that don't map to `Table`.
+    pub async fn run_into<R: DeserializeOwned>(self) -> Result<Vec<R>> {
+        trace!("run_into query: {}", self.query);
+        let query_request = self.build_query_request();
+        let (_, query_response) = run_query_with_client(&self.client.0, query_request).await?;
+        let rows = deserialize_rows(query_response)?;
+        debug!("run_into: {} rows", rows.len());
+        match &self.max_rows {
+            Some(max_rows) => max_rows.apply(rows),
+            None => Ok(rows),
+        }
+    }
+}
 //endregion
 //endregion
 
 //region extra helper functions
-async fn run_query_with_client(
+/// Where clauses with values use positional `__WHERE_n` parameters, so they
+/// never clash with the `__PARAM_<db_name>` parameters of the fields.
+fn next_where_param_name(params: &[QueryParameter]) -> String {
+    let where_params = params
+        .iter()
+        .filter(|p| p.name.as_deref().is_some_and(|n| n.starts_with("__WHERE_")))
+        .count();
+    format!("__WHERE_{}", where_params)
+}
+
+/// The param of [`QueryBuilder::as_of`], added by `build_query`.
+const AS_OF_PARAM: &str = "__ts";
+/// The param of [`QueryBuilder::parameterize_limit`], added by `build_query`.
+const LIMIT_PARAM: &str = "__LIMIT";
+/// The params that `build_query` adds, see [`QueryBuilder::merge_filters`].
+const RESERVED_PARAMS: [&str; 2] = [AS_OF_PARAM, LIMIT_PARAM];
+
+/// Fails if a param named `name` already exists, instead of binding the
+/// value of another param to it.
+fn check_param_is_free(params: &[QueryParameter], name: &str) -> Result<()> {
+    if params.iter().any(|p| p.name.as_deref() == Some(name)) {
+        return Err(anyhow!("the query parameter {} is already used", name));
+    }
+    Ok(())
+}
+
+/// Replaces every `@name` in the query that has an entry in `renames` with
+/// `@new_name`, all at once so renamed names are not renamed again.
+fn rename_params(query: &str, renames: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(query.len());
+    let mut rest = query;
+    while let Some(index) = rest.find('@') {
+        result.push_str(&rest[..=index]);
+        rest = &rest[index + 1..];
+        let name_len = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let name = &rest[..name_len];
+        match renames.get(name) {
+            Some(new_name) => result.push_str(new_name),
+            None => result.push_str(name),
+        }
+        rest = &rest[name_len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Whether `clause` mentions `column` as an identifier, not as part of
+/// another name or a `@param`.
+fn references_column(clause: &str, column: &str) -> bool {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_';
+    let mut rest = clause;
+    while let Some(start) = rest.find(is_name_char) {
+        let before = rest[..start].chars().last();
+        let name_rest = &rest[start..];
+        let len = name_rest
+            .find(|c| !is_name_char(c))
+            .unwrap_or(name_rest.len());
+        if &name_rest[..len] == column && before != Some('@') {
+            return true;
+        }
+        rest = &name_rest[len..];
+    }
+    false
+}
+
+/// Whether the query failed with a 404 for the table `table_name` of
+/// `Table`, and not for another table it reads from (e.g. in a subquery).
+fn is_table_not_found<Table: BigQueryTable>(
+    error: &anyhow::Error,
+    client: &BigqueryClient,
+    table_name: &str,
+) -> bool {
+    let Some(bigquery_error) = error.downcast_ref::<BigqueryError>() else {
+        return false;
+    };
+    // BigQuery names the missing table as `project:dataset.table`
+    let table_reference = format!(
+        "{}:{}.{}",
+        client.get_project_id(),
+        Table::get_dataset_id_from_client(client),
+        table_name
+    );
+    bigquery_error.status == Some(404) && bigquery_error.message.contains(&table_reference)
+}
+
+/// Attaches the SQL of the request to the error. The params are added by
+/// [`QueryBuilder::run`], which knows which of them are sensitive.
+fn with_request_query(error: BigqueryError, request: &QueryRequest) -> BigqueryError {
+    match &request.query {
+        Some(query) => error.with_query(query),
+        None => error,
+    }
+}
+
+/// How often a query is retried if [`BigqueryClient::should_retry`] allows it.
+const MAX_QUERY_RETRIES: u32 = 3;
+/// The delay before the first retry, doubled for every further one.
+const QUERY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub(crate) async fn run_query_with_client(
     client: &BigqueryClient,
-    request: QueryRequest,
+    mut request: QueryRequest,
 ) -> Result<(Response<Body>, google_bigquery2::api::QueryResponse)> {
-    let project_id = client.get_project_id();
-    let (response, query_response) = client
-        .get_client()
-        .jobs()
-        .query(request, project_id)
-        .doit()
-        .await?;
+    let project_id = client.get_job_project_id();
+    if request.labels.is_none() {
+        request.labels = client.get_request_labels();
+    }
+    // the same id for every attempt: BigQuery answers a repeated request
+    // with the result of the first one, so a retried write is not run twice
+    if request.request_id.is_none() {
+        request.request_id = Some(new_random_id());
+    }
+    let mut retries = 0;
+    let (response, query_response) = loop {
+        let error = match client
+            .get_client()
+            .jobs()
+            .query(request.clone(), project_id)
+            .doit()
+            .await
+        {
+            Ok(result) => break result,
+            Err(error) => error,
+        };
+        let retryable = BigqueryError::from_api_error(&error)
+            .is_some_and(|bigquery_error| client.should_retry(&bigquery_error));
+        if !retryable || retries >= MAX_QUERY_RETRIES {
+            let bigquery_error = BigqueryError::from_api_error(&error)
+                .unwrap_or_else(|| BigqueryError::new(&error.to_string(), None));
+            let bigquery_error = with_request_query(bigquery_error, &request);
+            return Err(anyhow::Error::from(error).context(bigquery_error));
+        }
+        let delay = QUERY_RETRY_DELAY * 2u32.pow(retries);
+        retries += 1;
+        warn!("query failed, retrying in {:?}: {}", delay, error);
+        tokio::time::sleep(delay).await;
+    };
 
     if response.status() != 200 {
-        return Err(anyhow!(
-            "Wrong status code returned! ({})",
-            response.status()
-        ));
+        let message = format!("Wrong status code returned! ({})", response.status());
+        let bigquery_error =
+            BigqueryError::new(&message, None).with_status(response.status().as_u16());
+        return Err(with_request_query(bigquery_error, &request).into());
     }
+    let job_id = query_response
+        .job_reference
+        .as_ref()
+        .and_then(|job_reference| job_reference.job_id.clone());
+    info!(
+        request_id = client.get_request_id(),
+        "query job id: {:?}", job_id
+    );
 
     Ok((response, query_response))
 }
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use google_bigquery2::api::{QueryParameter, QueryRequest, QueryResponse, TableFieldSchema};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::data::query_builder::run_query_with_client;
+use crate::prelude::*;
+
+/// Runs an ad-hoc query without a table struct and returns every row keyed
+/// by the column names of the result schema.
+///
+/// Useful for aggregates and other queries that don't map to a table:
+/// ```ignore
+/// let rows = run_raw(&client, "SELECT COUNT(*) AS n FROM t WHERE x > @min", vec![param("min", &3i64)]).await?;
+/// ```
+pub async fn run_raw(
+    client: &BigqueryClient,
+    sql: &str,
+    params: Vec<QueryParameter>,
+) -> Result<Vec<HashMap<String, Value>>> {
+    trace!("run_raw({}, {} params)", sql, params.len());
+    let query_parameters = match params.is_empty() {
+        true => None,
+        false => Some(params),
+    };
+    let query_request = QueryRequest {
+        query: Some(sql.to_string()),
+        query_parameters,
+        use_legacy_sql: Some(false),
+        ..Default::default()
+    };
+    let (_, query_response) = run_query_with_client(client, query_request).await?;
+    let rows = rows_from_query_response(query_response)?;
+    debug!("run_raw: {} rows", rows.len());
+    Ok(rows)
+}
+
+/// Deserializes every row of the response into `R`, with the column names
+/// as keys.
+///
+/// BigQuery returns numbers and booleans as strings, so these cells are
+/// converted to JSON numbers and booleans (by the column types of the
+/// schema) before deserializing.
+pub fn deserialize_rows<R: DeserializeOwned>(query_response: QueryResponse) -> Result<Vec<R>> {
+    let columns = query_response
+        .schema
+        .as_ref()
+        .and_then(|schema| schema.fields.clone())
+        .unwrap_or_default();
+    let mut result = vec![];
+    for mut row in rows_from_query_response(query_response)? {
+        let mut object = serde_json::Map::new();
+        for column in &columns {
+            let name = column.name.clone().unwrap_or_default();
+            if let Some(value) = row.remove(&name) {
+                object.insert(name, coerce_value(column, value));
+            }
+        }
+        result.push(serde_json::from_value(Value::Object(object))?);
+    }
+    Ok(result)
+}
+
+/// Converts the string cells of numeric and boolean columns to JSON numbers
+/// and booleans, also inside records and arrays.
+fn coerce_value(column: &TableFieldSchema, value: Value) -> Value {
+    if column.mode.as_deref() == Some("REPEATED") {
+        let element_column = TableFieldSchema {
+            mode: None,
+            ..column.clone()
+        };
+        return match value {
+            Value::Array(elements) => Value::Array(
+                elements
+                    .into_iter()
+                    .map(|element| coerce_value(&element_column, element))
+                    .collect(),
+            ),
+            _ => value,
+        };
+    }
+    match (column.type_.as_deref(), value) {
+        (Some("RECORD") | Some("STRUCT"), Value::Object(mut record)) => {
+            for field in column.fields.iter().flatten() {
+                let name = field.name.clone().unwrap_or_default();
+                if let Some(value) = record.remove(&name) {
+                    record.insert(name, coerce_value(field, value));
+                }
+            }
+            Value::Object(record)
+        }
+        (Some("INTEGER") | Some("INT64"), Value::String(string)) => match string.parse::<i64>() {
+            Ok(number) => Value::from(number),
+            Err(_) => Value::String(string),
+        },
+        (Some("FLOAT") | Some("FLOAT64"), Value::String(string)) => {
+            // NaN and Infinity have no JSON number, so they stay strings
+            match string
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+            {
+                Some(number) => Value::Number(number),
+                None => Value::String(string),
+            }
+        }
+        (Some("BOOLEAN") | Some("BOOL"), Value::String(string)) => match string.as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::String(string),
+        },
+        (_, value) => value,
+    }
+}
+
+/// Maps the cells of every row to the column names of the response schema.
+///
+/// RECORD cells (`{"f": [{"v": ...}]}`) become JSON objects keyed by the
+/// names of their fields, and repeated cells arrays of their values.
+pub(crate) fn rows_from_query_response(
+    query_response: QueryResponse,
+) -> Result<Vec<HashMap<String, Value>>> {
+    rows_from_query_response_or_positional(query_response, &[])
+}
+
+/// Like [`rows_from_query_response`], but if the response has rows without a
+/// schema (which happens for some cached and script results), the cells are
+/// mapped to `selected_columns` by position instead.
+pub(crate) fn rows_from_query_response_or_positional(
+    query_response: QueryResponse,
+    selected_columns: &[String],
+) -> Result<Vec<HashMap<String, Value>>> {
+    let (columns, source) = match query_response.schema.and_then(|schema| schema.fields) {
+        Some(columns) => (columns, "the schema has"),
+        None => {
+            if query_response
+                .rows
+                .as_ref()
+                .is_some_and(|rows| !rows.is_empty())
+            {
+                warn!(
+                    "query response has rows but no schema, mapping the cells by position to: {:?}",
+                    selected_columns
+                );
+            }
+            let columns = selected_columns
+                .iter()
+                .map(|name| TableFieldSchema {
+                    name: Some(name.clone()),
+                    ..Default::default()
+                })
+                .collect();
+            (columns, "the query selected")
+        }
+    };
+    let mut result = vec![];
+    for row in query_response.rows.unwrap_or_default() {
+        let cells = row.f.unwrap_or_default();
+        if cells.len() != columns.len() {
+            return Err(anyhow!(
+                "row has {} cells, but {} {} columns",
+                cells.len(),
+                source,
+                columns.len()
+            ));
+        }
+        let row_result: HashMap<String, Value> = columns
+            .iter()
+            .zip(cells)
+            .map(|(column, cell)| {
+                let value = cell_value(column, cell.v.unwrap_or(Value::Null));
+                (column.name.clone().unwrap_or_default(), value)
+            })
+            .collect();
+        result.push(row_result);
+    }
+    Ok(result)
+}
+
+fn cell_value(column: &TableFieldSchema, value: Value) -> Value {
+    let is_record = matches!(column.type_.as_deref(), Some("RECORD") | Some("STRUCT"));
+    let is_repeated = column.mode.as_deref() == Some("REPEATED");
+    if is_repeated {
+        // every element of an ARRAY is wrapped in its own `{"v": ...}`
+        let element_column = TableFieldSchema {
+            mode: None,
+            ..column.clone()
+        };
+        return match value {
+            Value::Array(elements) => Value::Array(
+                elements
+                    .into_iter()
+                    .map(|element| {
+                        let value = element.get("v").cloned().unwrap_or(Value::Null);
+                        cell_value(&element_column, value)
+                    })
+                    .collect(),
+            ),
+            _ => value,
+        };
+    }
+    let cells = match (is_record, &value) {
+        (true, Value::Object(record)) => match record.get("f") {
+            Some(Value::Array(cells)) => cells.clone(),
+            _ => return value,
+        },
+        _ => return value,
+    };
+    let fields = column.fields.clone().unwrap_or_default();
+    let record = fields
+        .iter()
+        .zip(cells)
+        .map(|(field, cell)| {
+            let value = cell.get("v").cloned().unwrap_or(Value::Null);
+            (
+                field.name.clone().unwrap_or_default(),
+                cell_value(field, value),
+            )
+        })
+        .collect();
+    Value::Object(record)
+}
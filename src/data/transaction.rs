@@ -0,0 +1,94 @@
+use google_bigquery2::api::QueryParameter;
+
+use crate::data::query_builder::{HasClient, QueryBuilder, QueryWasBuilt};
+use crate::prelude::*;
+
+/// Collects built queries to run them atomically as one
+/// `BEGIN TRANSACTION; ... COMMIT TRANSACTION;` script.
+///
+/// If any statement fails, the whole transaction is rolled back.
+/// Use it with [`BigqueryClient::transaction`].
+#[derive(Debug, Default, Clone)]
+pub struct Transaction {
+    statements: Vec<String>,
+    params: Vec<QueryParameter>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a built query to the transaction.
+    ///
+    /// The parameters of every statement are renamed (`@__PARAM_id` becomes
+    /// `@__PARAM_id_s0` for the first statement), so statements can use the
+    /// same parameter names.
+    pub fn add<Table: BigQueryTable, QueryType, StartingData>(
+        &mut self,
+        query: QueryBuilder<Table, QueryType, HasClient, QueryWasBuilt, StartingData>,
+    ) -> Result<()> {
+        trace!("Transaction::add({})", query.get_query_string());
+        let suffix = format!("_s{}", self.statements.len());
+        let mut statement = query.get_query_string().to_string();
+        for param in query.params() {
+            let mut param = param.clone();
+            let name = param
+                .name
+                .clone()
+                .ok_or_else(|| anyhow!("query parameter without a name"))?;
+            let new_name = format!("{}{}", name, suffix);
+            statement = rename_param(&statement, &name, &new_name);
+            param.name = Some(new_name);
+            self.params.push(param);
+        }
+        self.statements.push(statement);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// Returns the script and the parameters that are sent to BigQuery.
+    pub fn build_script(&self) -> (String, Vec<QueryParameter>) {
+        let mut script = String::from("BEGIN\n  BEGIN TRANSACTION;\n");
+        for statement in &self.statements {
+            script.push_str("  ");
+            script.push_str(statement);
+            script.push_str(";\n");
+        }
+        script.push_str("  COMMIT TRANSACTION;\n");
+        script.push_str("EXCEPTION WHEN ERROR THEN\n");
+        script.push_str("  ROLLBACK TRANSACTION;\n");
+        script.push_str("  RAISE USING MESSAGE = @@error.message;\n");
+        script.push_str("END;");
+        (script, self.params.clone())
+    }
+}
+
+/// Replaces every `@name` in the query with `@new_name`, but not longer
+/// names that start with `name`.
+fn rename_param(query: &str, name: &str, new_name: &str) -> String {
+    let pattern = format!("@{}", name);
+    let mut result = String::with_capacity(query.len());
+    let mut rest = query;
+    while let Some(index) = rest.find(&pattern) {
+        let after = &rest[index + pattern.len()..];
+        let is_whole_name = !after
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        result.push_str(&rest[..index]);
+        match is_whole_name {
+            true => {
+                result.push('@');
+                result.push_str(new_name);
+            }
+            false => result.push_str(&pattern),
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
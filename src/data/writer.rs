@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+use google_bigquery2::api::{
+    ErrorProto, JsonObject, JsonValue, TableDataInsertAllRequest, TableDataInsertAllRequestRows,
+};
+use serde_json::Value;
+
+use crate::client::BigqueryClient;
+use crate::data::{BigQueryTable, BigQueryWritable};
+use crate::prelude::*;
+use crate::utils::new_random_id;
+
+//region InsertRowsError
+/// A row of a flush that BigQuery rejected.
+#[derive(Debug, Clone)]
+pub struct FailedRow {
+    /// the position of the row in the flushed batch
+    pub index: usize,
+    /// the row as it was sent, with the db names as keys
+    pub row: Value,
+    pub errors: Vec<ErrorProto>,
+}
+
+/// Returned by [`TableWriter::flush`] when some rows of the batch were
+/// rejected. The other rows were written.
+#[derive(Debug, Clone)]
+pub struct InsertRowsError {
+    pub failed_rows: Vec<FailedRow>,
+    /// the number of rows in the flushed batch
+    pub batch_size: usize,
+}
+
+impl Display for InsertRowsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} rows failed to insert",
+            self.failed_rows.len(),
+            self.batch_size
+        )?;
+        let first_message = self
+            .failed_rows
+            .iter()
+            .flat_map(|row| &row.errors)
+            .find_map(|error| error.message.as_deref());
+        if let Some(message) = first_message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for InsertRowsError {}
+//endregion
+
+/// Buffers rows in memory and writes them with a streaming insert once
+/// `max_rows` rows are buffered or the oldest row is older than `max_age`.
+///
+/// The thresholds are checked on [`push`](TableWriter::push), there is no
+/// background timer. Call [`flush`](TableWriter::flush) when done: dropping
+/// a writer with buffered rows flushes them in a spawned task, but any error
+/// is only logged.
+///
+/// Every row gets an insert id when it is pushed, so BigQuery drops the
+/// duplicates if a flush is repeated after a failed request.
+pub struct TableWriter<T: BigQueryTable + BigQueryWritable> {
+    client: BigqueryClient,
+    table_name: String,
+    /// the buffered rows with their insert id
+    rows: Vec<(String, Value)>,
+    max_rows: usize,
+    max_age: Option<Duration>,
+    oldest_row: Option<Instant>,
+    table: PhantomData<T>,
+}
+
+impl<T: BigQueryTable + BigQueryWritable> TableWriter<T> {
+    pub fn new(client: BigqueryClient, max_rows: usize) -> Self {
+        Self {
+            client,
+            table_name: T::get_table_name(),
+            rows: Vec::new(),
+            max_rows: max_rows.max(1),
+            max_age: None,
+            oldest_row: None,
+            table: PhantomData,
+        }
+    }
+    /// Also flushes once the oldest buffered row is older than `max_age`.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+    /// Writes to another table with the same columns.
+    pub fn with_table_name(mut self, table_name: &str) -> Self {
+        self.table_name = table_name.to_string();
+        self
+    }
+    /// The number of buffered rows that were not written yet.
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Buffers the row and flushes if a threshold is reached.
+    pub async fn push(&mut self, row: &T) -> Result<()> {
+        trace!("push({})", row.compact_debug());
+        let mut json = row.to_json()?;
+        if let Value::Object(map) = &mut json {
+            let db_names = T::get_query_fields(true);
+            for field_name in T::get_field_exprs().keys() {
+                if let Some(db_name) = db_names.get(field_name) {
+                    map.remove(db_name);
+                }
+            }
+        }
+        self.rows.push((new_random_id(), json));
+        self.oldest_row.get_or_insert_with(Instant::now);
+        let too_old = match (self.max_age, self.oldest_row) {
+            (Some(max_age), Some(oldest_row)) => oldest_row.elapsed() >= max_age,
+            _ => false,
+        };
+        if self.rows.len() >= self.max_rows || too_old {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes all buffered rows and returns how many were written.
+    ///
+    /// Once BigQuery answered, the buffer is emptied even if some rows were
+    /// rejected; those are returned in an [`InsertRowsError`]. If the request
+    /// itself failed (e.g. a network error or a 5xx), the rows stay buffered
+    /// and the next flush sends them again with the same insert ids.
+    pub async fn flush(&mut self) -> Result<usize> {
+        trace!("flush() {} rows", self.rows.len());
+        let result = insert_rows::<T>(&self.client, &self.table_name, &self.rows).await;
+        let answered = match &result {
+            Ok(_) => true,
+            Err(error) => error.downcast_ref::<InsertRowsError>().is_some(),
+        };
+        if answered {
+            self.rows.clear();
+            self.oldest_row = None;
+        }
+        result
+    }
+}
+
+impl<T: BigQueryTable + BigQueryWritable> Drop for TableWriter<T> {
+    fn drop(&mut self) {
+        if self.rows.is_empty() {
+            return;
+        }
+        let rows = std::mem::take(&mut self.rows);
+        let client = self.client.clone();
+        let table_name = self.table_name.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                warn!(
+                    "TableWriter for {} dropped with {} rows, flushing in the background",
+                    table_name,
+                    rows.len()
+                );
+                handle.spawn(async move {
+                    if let Err(e) = insert_rows::<T>(&client, &table_name, &rows).await {
+                        error!("background flush of {} failed: {}", table_name, e);
+                    }
+                });
+            }
+            Err(_) => error!(
+                "TableWriter for {} dropped outside of a runtime, {} rows are lost",
+                table_name,
+                rows.len()
+            ),
+        }
+    }
+}
+
+/// Writes the rows with one streaming insert, skipping the invalid ones.
+async fn insert_rows<T: BigQueryTable + ?Sized>(
+    client: &BigqueryClient,
+    table_name: &str,
+    rows: &[(String, Value)],
+) -> Result<usize> {
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    let batch_size = rows.len();
+    let project_id = client.get_project_id();
+    let dataset_id = T::get_dataset_id_from_client(client);
+    if project_id.is_empty() || dataset_id.is_empty() {
+        return Err(anyhow!(
+            "can not insert {} rows into {}: the client has no project or dataset id",
+            batch_size,
+            table_name
+        ));
+    }
+    let request_rows = rows
+        .iter()
+        .map(|(insert_id, row)| {
+            let json = match row {
+                Value::Object(map) => map
+                    .iter()
+                    .map(|(key, value)| (key.clone(), JsonValue(value.clone())))
+                    .collect::<HashMap<String, JsonValue>>(),
+                _ => HashMap::new(),
+            };
+            TableDataInsertAllRequestRows {
+                insert_id: Some(insert_id.clone()),
+                json: Some(JsonObject(Some(json))),
+            }
+        })
+        .collect();
+    let request = TableDataInsertAllRequest {
+        rows: Some(request_rows),
+        skip_invalid_rows: Some(true),
+        ..Default::default()
+    };
+    let (_, response) = client
+        .get_client()
+        .tabledata()
+        .insert_all(request, project_id, &dataset_id, table_name)
+        .doit()
+        .await?;
+    let failed_rows: Vec<FailedRow> = response
+        .insert_errors
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|insert_error| {
+            let index = insert_error.index? as usize;
+            Some(FailedRow {
+                index,
+                row: rows.get(index)?.1.clone(),
+                errors: insert_error.errors.unwrap_or_default(),
+            })
+        })
+        .collect();
+    if !failed_rows.is_empty() {
+        return Err(InsertRowsError {
+            failed_rows,
+            batch_size,
+        }
+        .into());
+    }
+    debug!("inserted {} rows into {}", batch_size, table_name);
+    Ok(batch_size)
+}
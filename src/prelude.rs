@@ -1,7 +1,11 @@
-pub use google_bigquery_v2_derive::BigDataTableDerive;
+pub use google_bigquery_v2_derive::{BigDataColumnsDerive, BigDataTableDerive};
 
 pub use crate::client::BigqueryClient;
-pub use crate::data::{BigQueryTable, BigQueryTableBase, OrderDirection};
+pub use crate::data::param_conversion::Patch;
+pub use crate::data::{
+    BigQueryColumns, BigQueryRecord, BigQueryTable, BigQueryTableBase, BigQueryWritable,
+    OrderDirection,
+};
 
 pub use anyhow::{anyhow, Result};
 
@@ -1 +1,84 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::prelude::*;
+
+/// The maximum length of a column name in BigQuery.
+const MAX_IDENTIFIER_LENGTH: usize = 300;
+
+/// Validates a column name for raw where clauses and projections (like
+/// [`merge_filters`](crate::data::query_builder::QueryBuilder::merge_filters))
+/// and returns it quoted with backticks.
+///
+/// Only names that start with a letter or underscore and contain letters,
+/// digits and underscores are accepted, so an untrusted name can not break
+/// out of the identifier. Values still have to be passed as parameters.
+pub fn safe_identifier(name: &str) -> Result<String> {
+    let mut chars = name.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    let valid_rest = chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_start || !valid_rest || name.len() > MAX_IDENTIFIER_LENGTH {
+        return Err(anyhow!("invalid identifier: {:?}", name));
+    }
+    Ok(format!("`{}`", name))
+}
+
+/// Returns the fully qualified, quoted identifier of a table in any project,
+/// like `` `bigquery-public-data.samples.shakespeare` ``, for raw queries that
+/// don't depend on the dataset of the client.
+///
+/// The parts may only contain letters, digits, `_` and `-`.
+pub fn qualified_table_identifier(
+    project_id: &str,
+    dataset_id: &str,
+    table: &str,
+) -> Result<String> {
+    for part in [project_id, dataset_id, table] {
+        check_table_identifier_part(part)?;
+    }
+    Ok(format!("`{}.{}.{}`", project_id, dataset_id, table))
+}
+
+/// Checks that `part` is a valid project, dataset or table name for
+/// [`qualified_table_identifier`]: letters, digits, `_` and `-`.
+pub(crate) fn check_table_identifier_part(part: &str) -> Result<()> {
+    let valid = !part.is_empty()
+        && part.len() <= MAX_IDENTIFIER_LENGTH
+        && part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(anyhow!("invalid table identifier part: {:?}", part));
+    }
+    Ok(())
+}
+
+/// A random id in the UUID format, for the ids that make API requests
+/// idempotent (`QueryRequest.request_id` and the insert ids of streaming
+/// inserts).
+pub(crate) fn new_random_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u32(std::process::id());
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.write_u128(now.as_nanos());
+    let high = hasher.finish();
+    hasher.write_u64(high);
+    let low = hasher.finish();
+    let hex = format!("{:016x}{:016x}", high, low);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
@@ -1,11 +1,31 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
 use log::LevelFilter;
 use nameof::name_of;
 
-use google_bigquery_v2::data::query_builder::QueryResultType;
+use google_bigquery_v2::data::filter::Filter;
+use google_bigquery_v2::data::jobs::QueryCancelledError;
+use google_bigquery_v2::data::param_conversion::{
+    convert_value_to_string, ConvertBigQueryParams, FieldConversionError,
+};
+use google_bigquery_v2::data::query_builder::{
+    BigqueryError, Comparison, MaxRows, MaxRowsBehavior, QueryBuilder, QueryResultType,
+    QueryWasNotBuilt, UdfResource,
+};
+use google_bigquery_v2::data::transaction::Transaction;
+use google_bigquery_v2::data::writer::{FailedRow, InsertRowsError, TableWriter};
+use google_bigquery_v2::data::{
+    deserialize_rows, param, ConflictError, FieldChange, PermissionDeniedError,
+};
 use google_bigquery_v2::prelude::*;
+use google_bigquery_v2::utils::{qualified_table_identifier, safe_identifier};
+use serde_json::json;
 
 #[derive(BigDataTableDerive, Debug, Default, Clone)]
 #[db_name("Infos")]
+#[db_builder]
 pub struct DbInfos {
     #[client]
     client: BigqueryClient,
@@ -21,6 +41,260 @@ pub struct DbInfos {
     info4b: Option<bool>,
 }
 
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("Secrets")]
+pub struct DbSecrets {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    #[db_sensitive]
+    token: Option<String>,
+    note: Option<String>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("PatchInfos")]
+pub struct DbPatchInfos {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    a: Patch<String>,
+    b: Patch<String>,
+    c: Patch<i64>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("events_*")]
+pub struct DbEvents {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    name: Option<String>,
+}
+
+#[derive(BigDataColumnsDerive, Debug, Default, Clone, PartialEq)]
+pub struct Address {
+    street: Option<String>,
+    #[db_name("town")]
+    city: Option<String>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("Customers")]
+pub struct DbCustomers {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    #[db_flatten]
+    address: Address,
+    #[db_flatten("billing_")]
+    billing: Address,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("Shipments")]
+pub struct DbShipments {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    #[db_record]
+    destination: Address,
+    #[db_record]
+    origin: Option<Address>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("Routes")]
+pub struct DbRoutes {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    #[db_record]
+    stops: Vec<Address>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TicketStatus {
+    #[default]
+    Open,
+    Closed,
+}
+
+/// Stores the status as an integer code.
+fn encode_status(status: &TicketStatus) -> serde_json::Value {
+    match status {
+        TicketStatus::Open => json!(1),
+        TicketStatus::Closed => json!(2),
+    }
+}
+
+fn decode_status(value: &serde_json::Value) -> Result<TicketStatus> {
+    match i64::from_param(value)? {
+        1 => Ok(TicketStatus::Open),
+        2 => Ok(TicketStatus::Closed),
+        code => Err(anyhow!("unknown ticket status {}", code)),
+    }
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("Tickets")]
+pub struct DbTickets {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    #[db_with(encode_status, decode_status, "INT64")]
+    status: TicketStatus,
+    title: Option<String>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("People")]
+pub struct DbPeople {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    name: Option<String>,
+    #[db_expr("LOWER(name)")]
+    name_lower: Option<String>,
+}
+
+#[derive(BigDataColumnsDerive, Debug, Default, Clone, PartialEq)]
+pub struct OrderItem {
+    sku: Option<String>,
+    quantity: Option<i64>,
+}
+
+/// The rows of `OrderItems` grouped by order.
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("OrderItems")]
+#[db_view]
+pub struct DbOrderWithItems {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    order_id: i64,
+    #[db_record]
+    items: Vec<OrderItem>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("RequiredInfos")]
+pub struct DbRequiredInfos {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    #[required]
+    id: i64,
+    info: Option<String>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("IntPartitioned")]
+#[db_partition_by("bucket")]
+pub struct DbIntPartitioned {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    bucket: Option<i64>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("BuiltOrders")]
+#[db_builder]
+pub struct DbBuiltOrders {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    order_id: i64,
+    #[db_record]
+    items: Vec<OrderItem>,
+    #[db_ignore]
+    loaded_at: i64,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("UserEvents")]
+#[db_partition_by("ts")]
+#[db_cluster_by("user_id, kind")]
+#[db_require_partition_filter("ts")]
+pub struct DbUserEvents {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    #[required]
+    id: i64,
+    ts: Option<DateTime<Utc>>,
+    user_id: Option<i64>,
+    kind: Option<String>,
+}
+
+/// The id is generated by the server (e.g. with `DEFAULT GENERATE_UUID()`).
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("Logs")]
+pub struct DbLogs {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: Option<String>,
+    source: Option<String>,
+    message: Option<String>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("PageViews")]
+#[db_dataset("analytics")]
+pub struct DbPageViews {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    url: Option<String>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("LogLines")]
+#[db_default_limit(1000)]
+pub struct DbLogLines {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    line: Option<String>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("Notes")]
+pub struct DbNotes {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    #[db_empty_as_null]
+    title: String,
+    body: String,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("VersionedInfos")]
+pub struct DbVersionedInfos {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    id: i64,
+    info: Option<String>,
+    #[db_version]
+    version: Option<i64>,
+}
+
 #[tokio::test]
 async fn test1() {
     init_logger();
@@ -144,22 +418,16 @@ async fn test_query_builder_1() {
         .get_query_string()
         .to_string();
     let expected_query_string =
-        "SELECT info1, info, info3, yes, info4i, Id FROM `testrustproject-372221.test1.Infos` WHERE info1 is NULL AND info3 = @__PARAM_0 ORDER BY info ASC".to_string()
+        "SELECT info1, info, info3, yes, info4i, Id FROM `testrustproject-372221.test1.Infos` WHERE info1 is NULL AND info3 = @__WHERE_0 ORDER BY info ASC".to_string()
         ;
     debug!("query   : {}", query_string);
     debug!("expected: {}", expected_query_string);
     debug!("request: {:?}", query_builder.clone().build_query());
 
     assert_eq!(query_string, expected_query_string);
-    // assert_eq!(
-    //     query_builder
-    //         .clone()
-    //         .build_query_request()
-    //         .query_parameters
-    //         .unwrap()
-    //         .len(),
-    //     1
-    // );
+    let built_query = query_builder.clone().build_query().unwrap();
+    assert_eq!(built_query.params().len(), 1);
+    assert_eq!(built_query.params()[0].name.as_deref(), Some("__WHERE_0"));
     let res = query_builder
         .clone()
         .build_query()
@@ -170,76 +438,4283 @@ async fn test_query_builder_1() {
     debug!("res: {:?}", res);
 }
 
-async fn get_test_client() -> BigqueryClient {
-    BigqueryClient::new("testrustproject-372221", "test1", None)
-        .await
+#[test]
+fn test_is_full_scan() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .build_query()
+        .unwrap();
+    assert!(query_builder.is_full_scan());
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq(name_of!(info3 in DbInfos), Some(&"group".to_string()))
         .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(!query_builder.is_full_scan());
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .set_limit(10)
+        .build_query()
+        .unwrap();
+    assert!(!query_builder.is_full_scan());
 }
 
-#[tokio::test]
-async fn simple_query() {
+#[test]
+fn test_insert_from_select() {
     init_logger();
-    let client = get_test_client().await;
-    let q = DbInfos::select()
-        .with_client(client)
-        .add_order_by(name_of!(row_id in DbInfos), OrderDirection::Descending)
+    let select = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .with_table_name("InfosStaging")
+        .unwrap()
+        .add_where_eq(name_of!(info3 in DbInfos), Some(&"group".to_string()))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    let query_builder = DbInfos::insert()
+        .with_client(BigqueryClient::empty())
+        .build_query_from_select(select)
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "INSERT INTO `..Infos` (info1, info, info3, yes, info4i, Id) \
+        SELECT info1, info, info3, yes, info4i, Id FROM `..InfosStaging` WHERE info3 = @__WHERE_0"
+    );
+    assert_eq!(query_builder.params().len(), 1);
+
+    let select = DbSecrets::select()
+        .with_client(BigqueryClient::empty())
         .build_query()
+        .unwrap();
+    let result = DbInfos::insert()
+        .with_client(BigqueryClient::empty())
+        .build_query_from_select(select);
+    assert!(result.is_err(), "the column count does not match");
+
+    // the computed column of the select is not inserted
+    let select = DbPeople::select()
+        .with_client(BigqueryClient::empty())
+        .with_table_name("PeopleStaging")
         .unwrap()
-        .run()
-        .await
+        .build_query()
         .unwrap();
-    let q = match q {
-        QueryResultType::WithRowData(q) => q,
-        QueryResultType::WithoutRowData(e) => panic!("no data: {:?}", e),
+    let query_builder = DbPeople::insert()
+        .with_client(BigqueryClient::empty())
+        .build_query_from_select(select)
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "INSERT INTO `..People` (id, name) SELECT * EXCEPT(name_lower) FROM \
+        (SELECT id, name, LOWER(name) AS name_lower FROM `..PeopleStaging`)"
+    );
+}
+
+#[test]
+fn test_where_by_example_skips_expr_fields() {
+    let example = DbPeople {
+        id: 1,
+        name: Some("Ada".to_string()),
+        name_lower: Some("ada".to_string()),
+        ..Default::default()
     };
-    let mut last_num = 999999999999999999;
-    for line in q {
-        info!("line: {:?}", line);
-        debug!("row_id > last: {} <= {}", line.row_id, last_num);
-        assert!(line.row_id <= last_num);
-        last_num = line.row_id;
-    }
+    let query_builder = DbPeople::select()
+        .with_client(BigqueryClient::empty())
+        .set_data(example)
+        .add_where_by_example_including_nulls()
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT id, name, LOWER(name) AS name_lower FROM `..People` WHERE id = @__PARAM_id AND name = @__PARAM_name"
+    );
 }
 
-#[tokio::test]
-async fn test_select_limit_1() {
+#[test]
+fn test_where_ieq() {
     init_logger();
-    let client = get_test_client().await;
-    let q: Vec<DbInfos> = DbInfos::select()
-        .with_client(client)
-        .set_limit(1)
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_ieq(name_of!(info2 in DbInfos), "Ada@Example.com")
+        .unwrap()
         .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("WHERE LOWER(info) = LOWER(@__WHERE_0)"));
+    let params = query_builder.params();
+    assert_eq!(params.len(), 1);
+    assert_eq!(
+        params[0].parameter_value.as_ref().unwrap().value.as_deref(),
+        Some("Ada@Example.com")
+    );
+    assert!(DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_ieq("missing", "x")
+        .is_err());
+}
+
+#[test]
+fn test_where_eq_field() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq_field(name_of!(info1 in DbInfos), name_of!(info2 in DbInfos))
         .unwrap()
-        .run()
-        .await
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` WHERE info1 = info"
+    );
+}
+
+#[test]
+fn test_where_field_cmp() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_field_cmp(
+            name_of!(info1 in DbInfos),
+            Comparison::Lt,
+            name_of!(info2 in DbInfos),
+        )
         .unwrap()
-        .expect_with_data("no data");
-    assert_eq!(q.len(), 1);
+        .add_where_field_cmp(
+            name_of!(info4i in DbInfos),
+            Comparison::Ne,
+            name_of!(row_id in DbInfos),
+        )
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` \
+         WHERE info1 < info AND info4i != Id"
+    );
+    assert!(query_builder.params().is_empty());
+
+    for (op, sql) in [
+        (Comparison::Eq, "="),
+        (Comparison::Le, "<="),
+        (Comparison::Gt, ">"),
+        (Comparison::Ge, ">="),
+    ] {
+        let query_builder = DbInfos::select()
+            .with_client(BigqueryClient::empty())
+            .add_where_field_cmp(name_of!(info1 in DbInfos), op, name_of!(info3 in DbInfos))
+            .unwrap()
+            .build_query()
+            .unwrap();
+        assert!(query_builder
+            .get_query_string()
+            .ends_with(&format!("WHERE info1 {} info3", sql)));
+    }
+    assert!(DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_field_cmp("missing", Comparison::Lt, name_of!(info1 in DbInfos))
+        .is_err());
 }
 
-#[tokio::test]
-async fn test_upsert() {
+#[test]
+fn test_where_by_example() {
     init_logger();
-    let client = get_test_client().await;
-    let mut local = DbInfos {
-        client: client.clone(),
-        row_id: 1923,
-        info1: None,
-        info2: None,
-        info3: None,
-        info4i: None,
-        info4b: None,
+    let example = DbInfos {
+        row_id: 5,
+        info1: Some("a".to_string()),
+        info4i: Some(3),
+        ..Default::default()
     };
-    local.upsert().await.expect("could not perform upsert!");
-    DbInfos::delete()
-        .with_client(client)
-        .set_data(local)
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .set_data(example.clone())
+        .add_where_by_example()
+        .unwrap()
         .build_query()
-        .expect("could not build delete")
-        .run()
-        .await
-        .expect("could not run delete")
-        .expect_without_data("delete should not return any data");
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` WHERE info1 = @__PARAM_info1 AND info4i = @__PARAM_info4i AND Id = @__PARAM_Id"
+    );
+
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .set_data(example)
+        .add_where_by_example_including_nulls()
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` WHERE info1 = @__PARAM_info1 AND info is NULL AND info3 is NULL AND yes is NULL AND info4i = @__PARAM_info4i AND Id = @__PARAM_Id"
+    );
+}
+
+#[test]
+fn test_where_eq_str() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq(name_of!(info1 in DbInfos), Some(&"abc"))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("WHERE info1 = @__WHERE_0"));
+    let params = query_builder.get_redacted_params();
+    assert_eq!(
+        params[0].parameter_type.as_ref().unwrap().type_.as_deref(),
+        Some("STRING")
+    );
+    assert_eq!(query_builder.params().len(), 1);
+    assert!(<&str>::from_param(&json!("abc")).is_err());
+}
+
+#[test]
+fn test_where_eq_typed() {
+    let ts = DateTime::parse_from_rfc3339("2023-04-05T06:07:08Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    for bigquery_type in ["TIMESTAMP", "DATETIME"] {
+        let query_builder = DbUserEvents::select()
+            .with_client(BigqueryClient::empty())
+            .add_where_eq_typed(name_of!(ts in DbUserEvents), Some(&ts), bigquery_type)
+            .unwrap()
+            .build_query()
+            .unwrap();
+        assert!(query_builder
+            .get_query_string()
+            .ends_with("WHERE ts = @__WHERE_0"));
+        let params = query_builder.get_redacted_params();
+        assert_eq!(
+            params[0].parameter_type.as_ref().unwrap().type_.as_deref(),
+            Some(bigquery_type)
+        );
+        assert_eq!(
+            params[0].parameter_value.as_ref().unwrap().value.as_deref(),
+            Some("2023-04-05 06:07:08")
+        );
+    }
+    let query_builder = DbUserEvents::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq_typed(
+            name_of!(ts in DbUserEvents),
+            None::<&DateTime<Utc>>,
+            "DATETIME",
+        )
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("WHERE ts is NULL"));
+    assert!(query_builder.params().is_empty());
+}
+
+#[test]
+fn test_order_by_aggregate_alias() {
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_order_by("cnt", OrderDirection::Descending)
+        .add_order_by(name_of!(info2 in DbInfos), OrderDirection::Ascending)
+        .group_by(
+            &[name_of!(info2 in DbInfos)],
+            &[("COUNT(*)", "cnt"), ("MAX(Id)", "max_id")],
+        )
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info, COUNT(*) AS cnt, MAX(Id) AS max_id FROM `..Infos` \
+        GROUP BY info ORDER BY cnt DESC, info ASC"
+    );
+    // without the group_by, cnt is not a known column
+    assert!(DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_order_by("cnt", OrderDirection::Descending)
+        .build_query()
+        .is_err());
+    assert!(DbInfos::select()
+        .group_by(&[], &[("COUNT(*)", "c`nt")])
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_group_by_rows_are_not_parsed() {
+    let error = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .group_by(&[name_of!(info2 in DbInfos)], &[("COUNT(*)", "cnt")])
+        .unwrap()
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "the rows of a group_by can not be parsed as Infos, use run_raw"
+    );
+}
+
+#[test]
+fn test_default_limit() {
+    assert_eq!(DbLogLines::get_default_limit(), Some(1000));
+    assert_eq!(DbInfos::get_default_limit(), None);
+    let query_builder = DbLogLines::select()
+        .with_client(BigqueryClient::empty())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT id, line FROM `..LogLines` LIMIT 1000"
+    );
+    let query_builder = DbLogLines::select()
+        .with_client(BigqueryClient::empty())
+        .set_limit(10)
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT id, line FROM `..LogLines` LIMIT 10"
+    );
+    assert!(!DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .build_query()
+        .unwrap()
+        .get_query_string()
+        .contains("LIMIT"));
+    assert!(!DbLogLines::select()
+        .with_client(BigqueryClient::empty())
+        .without_limit()
+        .build_query()
+        .unwrap()
+        .get_query_string()
+        .contains("LIMIT"));
+}
+
+#[tokio::test]
+async fn test_reload_all_ignores_default_limit() {
+    init_logger();
+    let server = MockServer::start(vec![(
+        200,
+        json!({
+            "jobComplete": true,
+            "schema": {"fields": [
+                {"name": "id", "type": "INTEGER", "mode": "NULLABLE"},
+                {"name": "line", "type": "STRING", "mode": "NULLABLE"}
+            ]},
+            "rows": [
+                {"f": [{"v": "1"}, {"v": "first"}]},
+                {"f": [{"v": "2"}, {"v": "second"}]}
+            ]
+        })
+        .to_string(),
+    )]);
+    let mut entities: Vec<DbLogLines> = (1..=2)
+        .map(|id| DbLogLines {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let missing = DbLogLines::reload_all(server.client(), &mut entities)
+        .await
+        .unwrap();
+    assert!(missing.is_empty());
+    assert_eq!(entities[1].line.as_deref(), Some("second"));
+    let requests = server.requests();
+    let body: serde_json::Value = serde_json::from_str(&requests[0].1).unwrap();
+    let query = body["query"].as_str().unwrap();
+    assert!(!query.contains("LIMIT"), "{}", query);
+}
+
+#[tokio::test]
+async fn test_export_ndjson_pages() {
+    init_logger();
+    let schema = json!({"fields": [
+        {"name": "id", "type": "INTEGER", "mode": "NULLABLE"},
+        {"name": "line", "type": "STRING", "mode": "NULLABLE"}
+    ]});
+    let server = MockServer::start(vec![
+        (
+            200,
+            json!({
+                "jobComplete": true,
+                "jobReference": {"projectId": "project", "jobId": "job_1"},
+                "pageToken": "page_2",
+                "totalRows": "3",
+                "schema": schema,
+                "rows": [
+                    {"f": [{"v": "1"}, {"v": "first"}]},
+                    {"f": [{"v": "2"}, {"v": "second"}]}
+                ]
+            })
+            .to_string(),
+        ),
+        (
+            200,
+            json!({
+                "jobComplete": true,
+                "jobReference": {"projectId": "project", "jobId": "job_1"},
+                "totalRows": "3",
+                "schema": schema,
+                "rows": [{"f": [{"v": "3"}, {"v": "third"}]}]
+            })
+            .to_string(),
+        ),
+    ]);
+    let client = server
+        .client()
+        .with_project("project")
+        .with_dataset("dataset");
+    let mut buffer = Vec::new();
+    let count = DbLogLines::select()
+        .with_client(client)
+        .build_query()
+        .unwrap()
+        .export_ndjson(&mut buffer)
+        .await
+        .unwrap();
+    assert_eq!(count, 3);
+    let lines: Vec<serde_json::Value> = String::from_utf8(buffer)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines[2], json!({"id": 3, "line": "third"}));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(
+        requests[1].0.contains("/queries/job_1") && requests[1].0.contains("pageToken=page_2"),
+        "{}",
+        requests[1].0
+    );
+}
+
+#[test]
+fn test_array_from_param() {
+    use google_bigquery2::api::QueryResponse;
+    let query_response: QueryResponse = serde_json::from_value(json!({
+        "kind": "bigquery#queryResponse",
+        "schema": {
+            "fields": [
+                {"name": "id", "type": "INTEGER", "mode": "NULLABLE"},
+                {"name": "tags", "type": "STRING", "mode": "REPEATED"},
+                {"name": "scores", "type": "INTEGER", "mode": "REPEATED"}
+            ]
+        },
+        "rows": [
+            {"f": [{"v": "1"}, {"v": [{"v": "a"}, {"v": "b"}]}, {"v": [{"v": "3"}, {"v": "4"}]}]},
+            {"f": [{"v": "2"}, {"v": []}, {"v": null}]}
+        ],
+        "totalRows": "2",
+        "jobComplete": true
+    }))
+    .unwrap();
+    let cells: Vec<Vec<serde_json::Value>> = query_response
+        .rows
+        .unwrap()
+        .into_iter()
+        .map(|row| {
+            row.f
+                .unwrap()
+                .into_iter()
+                .map(|cell| cell.v.unwrap_or_default())
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        Vec::<String>::from_param(&cells[0][1]).unwrap(),
+        vec!["a".to_string(), "b".to_string()]
+    );
+    assert_eq!(Vec::<i64>::from_param(&cells[0][2]).unwrap(), vec![3, 4]);
+    assert!(Vec::<String>::from_param(&cells[1][1]).unwrap().is_empty());
+    assert!(Vec::<i64>::from_param(&cells[1][2]).unwrap().is_empty());
+    // already unwrapped, like the rows of `run_raw`
+    assert_eq!(
+        Vec::<i64>::from_param(&json!(["5", 6])).unwrap(),
+        vec![5, 6]
+    );
+    assert!(Vec::<i64>::from_param(&json!({"v": null}))
+        .unwrap()
+        .is_empty());
+    assert!(Vec::<i64>::from_param(&json!("7")).is_err());
+    assert_eq!(vec![1i64, 2].to_param(), json!([1, 2]));
+}
+
+#[test]
+fn test_wildcard_suffix_between() {
+    init_logger();
+    let query_builder = DbEvents::select()
+        .with_client(BigqueryClient::empty())
+        .wildcard_suffix_between("20240101", "20240131")
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT id, name FROM `..events_*` WHERE _TABLE_SUFFIX BETWEEN @__WHERE_0 AND @__WHERE_1"
+    );
+    let params = query_builder.params();
+    assert_eq!(params.len(), 2);
+    assert_eq!(
+        params[0].parameter_value.as_ref().unwrap().value.as_deref(),
+        Some("20240101")
+    );
+
+    // every range gets its own parameters
+    let query_builder = DbEvents::select()
+        .with_client(BigqueryClient::empty())
+        .wildcard_suffix_between("20240101", "20240131")
+        .unwrap()
+        .wildcard_suffix_between("20240110", "20240120")
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder.get_query_string().ends_with(
+        "WHERE _TABLE_SUFFIX BETWEEN @__WHERE_0 AND @__WHERE_1 \
+        AND _TABLE_SUFFIX BETWEEN @__WHERE_2 AND @__WHERE_3"
+    ));
+    assert_eq!(query_builder.params().len(), 4);
+
+    assert!(DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .wildcard_suffix_between("a", "b")
+        .is_err());
+}
+
+#[test]
+fn test_flatten() {
+    init_logger();
+    let query_builder = DbCustomers::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq("address.city", Some(&"Berlin".to_string()))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT address_town, address_street, billing_town, billing_street, id FROM `..Customers` WHERE address_town = @__WHERE_0"
+    );
+
+    let data = DbCustomers {
+        id: 1,
+        address: Address {
+            street: Some("Main St".to_string()),
+            city: None,
+        },
+        ..Default::default()
+    };
+    let insert = DbCustomers::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(data.clone())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        insert.get_query_string(),
+        "insert into `..Customers` (address_town, address_street, billing_town, billing_street, id) values(NULL, @__PARAM_address_street, NULL, NULL, @__PARAM_id)"
+    );
+    assert_eq!(
+        data.get_field_value("address.street").unwrap(),
+        json!("Main St")
+    );
+
+    let row = [
+        ("id", json!("2")),
+        ("address_street", json!("a")),
+        ("address_town", json!("b")),
+        ("billing_street", json!(null)),
+        ("billing_town", json!("c")),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect();
+    let customer = DbCustomers::new_from_query_result_row(BigqueryClient::empty(), &row).unwrap();
+    assert_eq!(customer.id, 2);
+    assert_eq!(customer.address.city.as_deref(), Some("b"));
+    assert_eq!(
+        customer.billing,
+        Address {
+            street: None,
+            city: Some("c".to_string())
+        }
+    );
+}
+
+#[test]
+fn test_db_with() {
+    init_logger();
+    assert_eq!(DbTickets::get_field_types()["status"], "INT64");
+    let mut ticket = DbTickets {
+        id: 1,
+        status: TicketStatus::Closed,
+        ..Default::default()
+    };
+    assert_eq!(ticket.get_field_value("status").unwrap(), json!(2));
+    let update = DbTickets::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(ticket.clone())
+        .build_query()
+        .unwrap();
+    let status = update
+        .params()
+        .iter()
+        .find(|p| p.name.as_deref() == Some("__PARAM_status"))
+        .unwrap();
+    assert_eq!(
+        status.parameter_type.as_ref().unwrap().type_.as_deref(),
+        Some("INT64")
+    );
+    assert_eq!(
+        status.parameter_value.as_ref().unwrap().value.as_deref(),
+        Some("2")
+    );
+
+    ticket.set_field_value("status", &json!("1")).unwrap();
+    assert_eq!(ticket.status, TicketStatus::Open);
+    assert!(ticket.set_field_value("status", &json!("7")).is_err());
+
+    let row = [
+        ("id", json!("3")),
+        ("status", json!("2")),
+        ("title", json!("broken")),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect();
+    let ticket = DbTickets::new_from_query_result_row(BigqueryClient::empty(), &row).unwrap();
+    assert_eq!(ticket.status, TicketStatus::Closed);
+    assert_eq!(ticket.title.as_deref(), Some("broken"));
+}
+
+#[test]
+fn test_db_expr() {
+    init_logger();
+    let select = DbPeople::select()
+        .with_client(BigqueryClient::empty())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        select.get_query_string(),
+        "SELECT id, name, LOWER(name) AS name_lower FROM `..People`"
+    );
+    let person = DbPeople {
+        id: 1,
+        name: Some("Ada".to_string()),
+        name_lower: Some("ada".to_string()),
+        ..Default::default()
+    };
+    let insert = DbPeople::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(person.clone())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        insert.get_query_string(),
+        "insert into `..People` (id, name) values(@__PARAM_id, @__PARAM_name)"
+    );
+    assert_eq!(insert.params().len(), 2);
+    let update = DbPeople::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(person)
+        .build_query()
+        .unwrap();
+    assert!(!update.get_query_string().contains("name_lower"));
+    let columns: Vec<String> = DbPeople::get_table_schema()
+        .fields
+        .unwrap()
+        .into_iter()
+        .map(|f| f.name.unwrap())
+        .collect();
+    assert_eq!(columns, vec!["id".to_string(), "name".to_string()]);
+}
+
+#[test]
+fn test_record() {
+    use google_bigquery2::api::{
+        QueryResponse, TableCell, TableFieldSchema, TableRow, TableSchema,
+    };
+    init_logger();
+    let data = DbShipments {
+        id: 1,
+        destination: Address {
+            street: Some("Main St".to_string()),
+            city: None,
+        },
+        ..Default::default()
+    };
+    let insert = DbShipments::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(data.clone())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        insert.get_query_string(),
+        "insert into `..Shipments` (destination, id, origin) values(@__PARAM_destination, @__PARAM_id, NULL)"
+    );
+    let destination = insert
+        .params()
+        .iter()
+        .find(|p| p.name.as_deref() == Some("__PARAM_destination"))
+        .unwrap();
+    let param_type = destination.parameter_type.as_ref().unwrap();
+    assert_eq!(param_type.type_.as_deref(), Some("STRUCT"));
+    let struct_types: Vec<_> = param_type
+        .struct_types
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|t| {
+            (
+                t.name.clone().unwrap(),
+                t.type_.clone().unwrap().type_.unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        struct_types,
+        vec![
+            ("street".to_string(), "STRING".to_string()),
+            ("town".to_string(), "STRING".to_string())
+        ]
+    );
+    let struct_values = destination
+        .parameter_value
+        .as_ref()
+        .unwrap()
+        .struct_values
+        .as_ref()
+        .unwrap();
+    assert_eq!(struct_values["street"].value.as_deref(), Some("Main St"));
+    assert_eq!(struct_values["town"].value, None);
+    assert_eq!(
+        data.get_field_value("destination").unwrap(),
+        json!({"street": "Main St", "town": null})
+    );
+
+    // BigQuery returns records as nested f/v cells
+    let record_schema = |name: &str| TableFieldSchema {
+        name: Some(name.to_string()),
+        type_: Some("RECORD".to_string()),
+        fields: Some(vec![
+            TableFieldSchema {
+                name: Some("street".to_string()),
+                type_: Some("STRING".to_string()),
+                ..Default::default()
+            },
+            TableFieldSchema {
+                name: Some("town".to_string()),
+                type_: Some("STRING".to_string()),
+                ..Default::default()
+            },
+        ]),
+        ..Default::default()
+    };
+    let query_response = QueryResponse {
+        schema: Some(TableSchema {
+            fields: Some(vec![
+                record_schema("destination"),
+                TableFieldSchema {
+                    name: Some("id".to_string()),
+                    type_: Some("INTEGER".to_string()),
+                    ..Default::default()
+                },
+                record_schema("origin"),
+            ]),
+        }),
+        rows: Some(vec![TableRow {
+            f: Some(vec![
+                TableCell {
+                    v: Some(json!({"f": [{"v": "Main St"}, {"v": null}]})),
+                },
+                TableCell {
+                    v: Some(json!("1")),
+                },
+                TableCell {
+                    v: Some(json!(null)),
+                },
+            ]),
+        }]),
+        ..Default::default()
+    };
+    let rows =
+        DbShipments::new_from_query_response(BigqueryClient::empty(), query_response).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].id, data.id);
+    assert_eq!(rows[0].destination, data.destination);
+    assert_eq!(rows[0].origin, None);
+}
+
+#[test]
+fn test_record_array() {
+    use google_bigquery2::api::{
+        QueryResponse, TableCell, TableFieldSchema, TableRow, TableSchema,
+    };
+    init_logger();
+    let data = DbRoutes {
+        id: 2,
+        stops: vec![
+            Address {
+                street: Some("Main St".to_string()),
+                city: Some("Springfield".to_string()),
+            },
+            Address {
+                street: None,
+                city: Some("Shelbyville".to_string()),
+            },
+        ],
+        ..Default::default()
+    };
+    let insert = DbRoutes::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(data.clone())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        insert.get_query_string(),
+        "insert into `..Routes` (id, stops) values(@__PARAM_id, @__PARAM_stops)"
+    );
+    let stops = insert
+        .params()
+        .iter()
+        .find(|p| p.name.as_deref() == Some("__PARAM_stops"))
+        .unwrap();
+    let param_type = stops.parameter_type.as_ref().unwrap();
+    assert_eq!(param_type.type_.as_deref(), Some("ARRAY"));
+    let element_type = param_type.array_type.clone().flatten().unwrap();
+    assert_eq!(element_type.type_.as_deref(), Some("STRUCT"));
+    assert_eq!(element_type.struct_types.unwrap().len(), 2);
+    let array_values = stops
+        .parameter_value
+        .as_ref()
+        .unwrap()
+        .array_values
+        .as_ref()
+        .unwrap();
+    assert_eq!(array_values.len(), 2);
+    let second = array_values[1].struct_values.as_ref().unwrap();
+    assert_eq!(second["street"].value, None);
+    assert_eq!(second["town"].value.as_deref(), Some("Shelbyville"));
+    assert_eq!(
+        data.get_field_value("stops").unwrap(),
+        json!([
+            {"street": "Main St", "town": "Springfield"},
+            {"street": null, "town": "Shelbyville"}
+        ])
+    );
+
+    // every element of a repeated record is wrapped in its own v cell
+    let query_response = QueryResponse {
+        schema: Some(TableSchema {
+            fields: Some(vec![
+                TableFieldSchema {
+                    name: Some("id".to_string()),
+                    type_: Some("INTEGER".to_string()),
+                    ..Default::default()
+                },
+                TableFieldSchema {
+                    name: Some("stops".to_string()),
+                    type_: Some("RECORD".to_string()),
+                    mode: Some("REPEATED".to_string()),
+                    fields: Some(vec![
+                        TableFieldSchema {
+                            name: Some("street".to_string()),
+                            type_: Some("STRING".to_string()),
+                            ..Default::default()
+                        },
+                        TableFieldSchema {
+                            name: Some("town".to_string()),
+                            type_: Some("STRING".to_string()),
+                            ..Default::default()
+                        },
+                    ]),
+                    ..Default::default()
+                },
+            ]),
+        }),
+        rows: Some(vec![TableRow {
+            f: Some(vec![
+                TableCell {
+                    v: Some(json!("2")),
+                },
+                TableCell {
+                    v: Some(json!([
+                        {"v": {"f": [{"v": "Main St"}, {"v": "Springfield"}]}},
+                        {"v": {"f": [{"v": null}, {"v": "Shelbyville"}]}}
+                    ])),
+                },
+            ]),
+        }]),
+        ..Default::default()
+    };
+    let rows = DbRoutes::new_from_query_response(BigqueryClient::empty(), query_response).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].id, data.id);
+    assert_eq!(rows[0].stops, data.stops);
+}
+
+#[test]
+fn test_query_response_without_schema() {
+    use google_bigquery2::api::{QueryResponse, TableCell, TableRow};
+    init_logger();
+    let row = |values: &[serde_json::Value]| TableRow {
+        f: Some(
+            values
+                .iter()
+                .map(|v| TableCell { v: Some(v.clone()) })
+                .collect(),
+        ),
+    };
+    // info1, info, info3, yes, info4i, Id
+    let query_response = QueryResponse {
+        rows: Some(vec![row(&[
+            json!("a"),
+            json!(null),
+            json!("c"),
+            json!("true"),
+            json!("4"),
+            json!("7"),
+        ])]),
+        ..Default::default()
+    };
+    let rows = DbInfos::new_from_query_response(BigqueryClient::empty(), query_response).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].row_id, 7);
+    assert_eq!(rows[0].info1.as_deref(), Some("a"));
+    assert_eq!(rows[0].info2, None);
+    assert_eq!(rows[0].info4b, Some(true));
+
+    let query_response = QueryResponse {
+        rows: Some(vec![row(&[json!("a"), json!("b"), json!("7")])]),
+        ..Default::default()
+    };
+    let error = DbInfos::new_from_query_response(BigqueryClient::empty(), query_response)
+        .expect_err("the row has too few cells");
+    assert_eq!(
+        error.to_string(),
+        "row has 3 cells, but the query selected 6 columns"
+    );
+}
+
+#[test]
+fn test_aggregate_children() {
+    use google_bigquery2::api::{
+        QueryResponse, TableCell, TableFieldSchema, TableRow, TableSchema,
+    };
+    init_logger();
+    let query_builder = DbOrderWithItems::select()
+        .with_client(BigqueryClient::empty())
+        .aggregate_children(name_of!(items in DbOrderWithItems))
+        .unwrap()
+        .add_where_eq(name_of!(order_id in DbOrderWithItems), Some(&1i64))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT ARRAY_AGG(STRUCT(quantity, sku)) AS items, order_id FROM `..OrderItems` \
+        WHERE order_id = @__WHERE_0 GROUP BY order_id"
+    );
+    assert!(DbOrderWithItems::select()
+        .with_client(BigqueryClient::empty())
+        .aggregate_children(name_of!(order_id in DbOrderWithItems))
+        .is_err());
+
+    let column = |name: &str, type_: &str| TableFieldSchema {
+        name: Some(name.to_string()),
+        type_: Some(type_.to_string()),
+        ..Default::default()
+    };
+    let item = |sku: &str, quantity: &str| json!({"v": {"f": [{"v": quantity}, {"v": sku}]}});
+    let row = |items: serde_json::Value, order_id: &str| TableRow {
+        f: Some(vec![
+            TableCell { v: Some(items) },
+            TableCell {
+                v: Some(json!(order_id)),
+            },
+        ]),
+    };
+    let query_response = QueryResponse {
+        schema: Some(TableSchema {
+            fields: Some(vec![
+                TableFieldSchema {
+                    mode: Some("REPEATED".to_string()),
+                    fields: Some(vec![column("quantity", "INTEGER"), column("sku", "STRING")]),
+                    ..column("items", "RECORD")
+                },
+                column("order_id", "INTEGER"),
+            ]),
+        }),
+        rows: Some(vec![
+            row(json!([item("a", "1"), item("b", "2")]), "1"),
+            row(json!([item("c", "3")]), "2"),
+        ]),
+        ..Default::default()
+    };
+    let orders =
+        DbOrderWithItems::new_from_query_response(BigqueryClient::empty(), query_response).unwrap();
+    assert_eq!(orders.len(), 2);
+    assert_eq!(orders[0].order_id, 1);
+    assert_eq!(
+        orders[0].items,
+        vec![
+            OrderItem {
+                sku: Some("a".to_string()),
+                quantity: Some(1)
+            },
+            OrderItem {
+                sku: Some("b".to_string()),
+                quantity: Some(2)
+            },
+        ]
+    );
+    assert_eq!(orders[1].order_id, 2);
+    assert_eq!(
+        orders[1].items,
+        vec![OrderItem {
+            sku: Some("c".to_string()),
+            quantity: Some(3)
+        }]
+    );
+}
+
+#[test]
+fn test_where_in() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_in(name_of!(info4i in DbInfos), &[Some(1), None, Some(3)])
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("WHERE (info4i IN UNNEST(@__WHERE_0) OR info4i is NULL)"));
+    let params = query_builder.params();
+    assert_eq!(params.len(), 1);
+    let array_type = params[0].parameter_type.as_ref().unwrap();
+    assert_eq!(array_type.type_.as_deref(), Some("ARRAY"));
+    let values: Vec<Option<String>> = params[0]
+        .parameter_value
+        .as_ref()
+        .unwrap()
+        .array_values
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|v| v.value.clone())
+        .collect();
+    assert_eq!(values, vec![Some("1".to_string()), Some("3".to_string())]);
+
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_in(name_of!(info4i in DbInfos), &[Some(1), Some(2)])
+        .unwrap()
+        .add_where_in::<i32>(name_of!(info4i in DbInfos), &[None])
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("WHERE info4i IN UNNEST(@__WHERE_0) AND info4i is NULL"));
+}
+
+#[test]
+fn test_partial_query_result_row() {
+    init_logger();
+    let row = [("Id", json!("5")), ("info", json!("partial"))]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+    let entry = DbInfos::new_from_partial_query_result_row(BigqueryClient::empty(), &row).unwrap();
+    assert_eq!(entry.row_id, 5);
+    assert_eq!(entry.info2.as_deref(), Some("partial"));
+    assert_eq!(entry.info1, None);
+    assert_eq!(entry.info4i, None);
+}
+
+#[test]
+fn test_naive_date_time() {
+    use chrono::NaiveDate;
+    let time = NaiveDate::from_ymd_opt(2023, 4, 5)
+        .unwrap()
+        .and_hms_micro_opt(6, 7, 8, 123456)
+        .unwrap();
+    let value = time.to_param();
+    assert_eq!(value, json!("2023-04-05 06:07:08.123456"));
+    assert_eq!(NaiveDateTime::from_param(&value).unwrap(), time);
+    assert_eq!(
+        NaiveDateTime::from_param(&json!("2023-04-05T06:07:08.123456")).unwrap(),
+        time
+    );
+    let whole_seconds = NaiveDate::from_ymd_opt(2023, 4, 5)
+        .unwrap()
+        .and_hms_opt(6, 7, 8)
+        .unwrap();
+    assert_eq!(
+        NaiveDateTime::from_param(&json!("2023-04-05T06:07:08")).unwrap(),
+        whole_seconds
+    );
+    let param = param("t", &time);
+    assert_eq!(
+        param.parameter_type.unwrap().type_.as_deref(),
+        Some("DATETIME")
+    );
+}
+
+#[test]
+fn test_with_udf() {
+    init_logger();
+    let add_one = UdfResource::inline("CREATE TEMP FUNCTION add_one(x INT64) AS (x + 1);");
+    let js = UdfResource::javascript(
+        "double(x FLOAT64) RETURNS FLOAT64",
+        "return x * 2;",
+        &["gs://bucket/lib.js"],
+    );
+    assert_eq!(
+        js.definition(),
+        "CREATE TEMP FUNCTION double(x FLOAT64) RETURNS FLOAT64 LANGUAGE js \
+         OPTIONS (library=[\"gs://bucket/lib.js\"]) AS r\"\"\"return x * 2;\"\"\""
+    );
+    let query = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .with_udf(add_one)
+        .with_udf(js)
+        .merge_filters(vec!["add_one(info4i) = 5".to_string()], vec![], vec![])
+        .unwrap()
+        .build_query()
+        .unwrap();
+    let request = query.build_query_request();
+    let request_query = request.query.unwrap();
+    assert!(request_query.starts_with(
+        "CREATE TEMP FUNCTION add_one(x INT64) AS (x + 1);\nCREATE TEMP FUNCTION double("
+    ));
+    assert!(request_query.ends_with(query.get_query_string()));
+    assert!(query.get_query_string().contains("add_one(info4i) = 5"));
+}
+
+#[test]
+fn test_zero_limit() {
+    init_logger();
+    let result = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .try_set_limit(0);
+    assert!(result.is_err());
+    let query = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .try_set_limit(5)
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query.get_query_string().ends_with("LIMIT 5"));
+    // set_limit still accepts 0 and only warns
+    let query = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .set_limit(0)
+        .build_query()
+        .unwrap();
+    assert!(query.get_query_string().ends_with("LIMIT 0"));
+}
+
+#[tokio::test]
+async fn test_get_by_pk_wrong_type() {
+    init_logger();
+    let error = DbInfos::get_by_pk(BigqueryClient::empty(), &"123")
+        .await
+        .expect_err("a &str pk should be rejected for an INT64 pk");
+    let message = error.to_string();
+    assert!(message.contains("INT64"), "{}", message);
+    assert!(message.contains("STRING"), "{}", message);
+}
+
+#[tokio::test]
+async fn test_select_star_except_replace() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .select_star_except(&[name_of!(info1 in DbInfos), name_of!(info4b in DbInfos)])
+        .unwrap()
+        .add_where_eq(name_of!(row_id in DbInfos), Some(&7))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT * EXCEPT (info1, yes) FROM `..Infos` WHERE Id = @__WHERE_0"
+    );
+    let error = query_builder
+        .run()
+        .await
+        .expect_err("the rows can not be parsed as DbInfos");
+    assert!(error.to_string().contains("run_raw"), "{}", error);
+
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .select_star_replace(&[("UPPER(info1)", name_of!(info1 in DbInfos))])
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT * REPLACE (UPPER(info1) AS info1) FROM `..Infos`"
+    );
+
+    assert!(DbInfos::select()
+        .select_star_except(&["not_a_field"])
+        .is_err());
+    assert!(DbInfos::select().select_star_replace(&[]).is_err());
+}
+
+#[tokio::test]
+async fn test_get_by_pks_chunked_offline() {
+    init_logger();
+    let rows = DbInfos::get_by_pks::<i64>(BigqueryClient::empty(), &[])
+        .await
+        .expect("no pks should not send a query");
+    assert!(rows.is_empty());
+    assert!(
+        DbInfos::get_by_pks_chunked(BigqueryClient::empty(), &[1i64], 0)
+            .await
+            .is_err()
+    );
+    assert!(DbInfos::get_by_pks(BigqueryClient::empty(), &["1"])
+        .await
+        .is_err());
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_get_by_pks_chunked() {
+    init_logger();
+    let client = get_test_client().await;
+    let mut expected: Vec<i64> = DbInfos::select()
+        .with_client(client.clone())
+        .set_limit(5)
+        .pks()
+        .await
+        .unwrap();
+    // a missing pk is skipped
+    let mut pks = expected.clone();
+    pks.push(-4713);
+    let rows = DbInfos::get_by_pks_chunked(client, &pks, 2).await.unwrap();
+    let mut ids: Vec<i64> = rows.iter().map(|row| row.row_id).collect();
+    ids.sort();
+    expected.sort();
+    assert_eq!(ids, expected);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_run_into() {
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct InfoSummary {
+        #[serde(rename = "Id")]
+        id: i64,
+        info1: Option<String>,
+    }
+    init_logger();
+    let client = get_test_client().await;
+    let query = DbInfos::select()
+        .with_client(client)
+        .add_order_by(name_of!(row_id in DbInfos), OrderDirection::Ascending)
+        .set_limit(3)
+        .build_query()
+        .unwrap();
+    let expected: Vec<InfoSummary> = query
+        .clone()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("no data")
+        .into_iter()
+        .map(|row| InfoSummary {
+            id: row.row_id,
+            info1: row.info1,
+        })
+        .collect();
+    let rows: Vec<InfoSummary> = query.run_into().await.unwrap();
+    assert_eq!(rows, expected);
+}
+
+#[test]
+fn test_deserialize_rows() {
+    use google_bigquery2::api::{
+        QueryResponse, TableCell, TableFieldSchema, TableRow, TableSchema,
+    };
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct Stats {
+        name: String,
+        total: i64,
+        ratio: f64,
+        active: bool,
+        codes: Vec<i64>,
+        note: Option<String>,
+    }
+    init_logger();
+    let column = |name: &str, type_: &str| TableFieldSchema {
+        name: Some(name.to_string()),
+        type_: Some(type_.to_string()),
+        ..Default::default()
+    };
+    let query_response = QueryResponse {
+        schema: Some(TableSchema {
+            fields: Some(vec![
+                column("name", "STRING"),
+                column("total", "INTEGER"),
+                column("ratio", "FLOAT"),
+                column("active", "BOOLEAN"),
+                TableFieldSchema {
+                    mode: Some("REPEATED".to_string()),
+                    ..column("codes", "INTEGER")
+                },
+                column("note", "STRING"),
+            ]),
+        }),
+        rows: Some(vec![TableRow {
+            f: Some(
+                [
+                    json!("42"),
+                    json!("7"),
+                    json!("2.5E-1"),
+                    json!("true"),
+                    json!([{"v": "1"}, {"v": "2"}]),
+                    json!(null),
+                ]
+                .into_iter()
+                .map(|v| TableCell { v: Some(v) })
+                .collect(),
+            ),
+        }]),
+        ..Default::default()
+    };
+    let rows: Vec<Stats> = deserialize_rows(query_response).unwrap();
+    assert_eq!(
+        rows,
+        vec![Stats {
+            name: "42".to_string(),
+            total: 7,
+            ratio: 0.25,
+            active: true,
+            codes: vec![1, 2],
+            note: None,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn test_pks_wrong_type() {
+    init_logger();
+    let error = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .pks::<String>()
+        .await
+        .expect_err("STRING pks should be rejected for an INT64 pk");
+    let message = error.to_string();
+    assert!(message.contains("INT64"), "{}", message);
+    assert!(message.contains("STRING"), "{}", message);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_run_map() {
+    init_logger();
+    let client = get_test_client().await;
+    let query = DbInfos::select()
+        .with_client(client)
+        .add_order_by(name_of!(row_id in DbInfos), OrderDirection::Ascending)
+        .set_limit(3)
+        .build_query()
+        .unwrap();
+    let expected: Vec<i64> = query
+        .clone()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("no data")
+        .iter()
+        .map(|row| row.row_id)
+        .collect();
+    let ids: Vec<i64> = query
+        .run_map(|row| i64::from_param(&row["Id"]))
+        .await
+        .unwrap();
+    assert_eq!(ids, expected);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_pks() {
+    init_logger();
+    let client = get_test_client().await;
+    let expected: Vec<i64> = DbInfos::select()
+        .with_client(client.clone())
+        .add_where_eq(name_of!(info4b in DbInfos), Some(&true))
+        .unwrap()
+        .add_order_by(name_of!(row_id in DbInfos), OrderDirection::Ascending)
+        .set_limit(5)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("no data")
+        .iter()
+        .map(|row| row.row_id)
+        .collect();
+    let pks: Vec<i64> = DbInfos::select()
+        .with_client(client)
+        .add_where_eq(name_of!(info4b in DbInfos), Some(&true))
+        .unwrap()
+        .add_order_by(name_of!(row_id in DbInfos), OrderDirection::Ascending)
+        .set_limit(5)
+        .pks()
+        .await
+        .unwrap();
+    assert_eq!(pks, expected);
+}
+
+#[test]
+fn test_select_fields() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .select_fields(&[name_of!(info1 in DbInfos), name_of!(info4i in DbInfos)])
+        .unwrap()
+        .add_where_eq(name_of!(row_id in DbInfos), Some(&7))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info4i, Id FROM `..Infos` WHERE Id = @__WHERE_0"
+    );
+
+    let result = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .select_fields(&["does_not_exist"]);
+    assert!(result.is_err(), "unknown fields must be rejected");
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_get_by_pk_fields() {
+    init_logger();
+    let client = get_test_client().await;
+    let full = DbInfos::get_by_pk(client.clone(), &123123).await.unwrap();
+    let partial = DbInfos::get_by_pk_fields(client, &123123, &[name_of!(info4i in DbInfos)])
+        .await
+        .unwrap();
+    assert_eq!(partial.row_id, 123123);
+    assert_eq!(partial.info4i, full.info4i);
+    assert_eq!(partial.info1, None);
+    assert_eq!(partial.info2, None);
+    assert_eq!(partial.info3, None);
+    assert_eq!(partial.info4b, None);
+}
+
+struct ActiveOnly;
+
+impl Filter<DbInfos> for ActiveOnly {
+    fn apply<QueryType: Debug, Client: Debug, StartingData: Debug>(
+        self,
+        builder: QueryBuilder<DbInfos, QueryType, Client, QueryWasNotBuilt, StartingData>,
+    ) -> Result<QueryBuilder<DbInfos, QueryType, Client, QueryWasNotBuilt, StartingData>> {
+        builder.add_where_eq(name_of!(info4b in DbInfos), Some(&true))
+    }
+}
+
+struct IdAtLeast(i64);
+
+impl Filter<DbInfos> for IdAtLeast {
+    fn apply<QueryType: Debug, Client: Debug, StartingData: Debug>(
+        self,
+        builder: QueryBuilder<DbInfos, QueryType, Client, QueryWasNotBuilt, StartingData>,
+    ) -> Result<QueryBuilder<DbInfos, QueryType, Client, QueryWasNotBuilt, StartingData>> {
+        builder.merge_filters(
+            vec!["Id >= @min_id".to_string()],
+            vec![param("min_id", &self.0)],
+            vec![],
+        )
+    }
+}
+
+#[test]
+fn test_safe_identifier() {
+    assert_eq!(safe_identifier("info1").unwrap(), "`info1`");
+    assert_eq!(safe_identifier("_private").unwrap(), "`_private`");
+    assert_eq!(safe_identifier("Id").unwrap(), "`Id`");
+    for name in [
+        "",
+        "1abc",
+        "a`b",
+        "a; DROP TABLE x",
+        "a b",
+        "a.b",
+        "a-b",
+        "ä",
+        &"a".repeat(301),
+    ] {
+        assert!(safe_identifier(name).is_err(), "{:?}", name);
+    }
+}
+
+#[test]
+fn test_filter() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .filter(ActiveOnly)
+        .unwrap()
+        .filter(IdAtLeast(10))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` WHERE yes = @__WHERE_0 AND Id >= @min_id"
+    );
+    assert_eq!(query_builder.params().len(), 2);
+}
+
+#[test]
+fn test_add_where_in_subquery() {
+    init_logger();
+    let subquery = DbLogs::select()
+        .with_client(BigqueryClient::empty())
+        .select_column(name_of!(id in DbLogs))
+        .unwrap()
+        .add_where_eq(name_of!(message in DbLogs), Some(&"failed".to_string()))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        subquery.get_query_string(),
+        "SELECT id FROM `..Logs` WHERE message = @__WHERE_0"
+    );
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq(name_of!(info4b in DbInfos), Some(&true))
+        .unwrap()
+        .add_where_in_subquery(name_of!(info1 in DbInfos), subquery)
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` WHERE yes = @__WHERE_0 \
+         AND info1 IN (SELECT id FROM `..Logs` WHERE message = @__WHERE_1)"
+    );
+    let names: Vec<&str> = query_builder
+        .params()
+        .iter()
+        .map(|p| p.name.as_deref().unwrap())
+        .collect();
+    assert_eq!(names, vec!["__WHERE_0", "__WHERE_1"]);
+    assert_eq!(
+        query_builder.params()[1]
+            .parameter_value
+            .as_ref()
+            .unwrap()
+            .value
+            .as_deref(),
+        Some("failed")
+    );
+}
+
+#[test]
+fn test_add_where_in_subquery_with_as_of() {
+    init_logger();
+    let time = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    let subquery = DbLogs::select()
+        .with_client(BigqueryClient::empty())
+        .select_column(name_of!(id in DbLogs))
+        .unwrap()
+        .as_of(time("2023-01-01T00:00:00Z"))
+        .build_query()
+        .unwrap();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .as_of(time("2024-01-01T00:00:00Z"))
+        .add_where_in_subquery(name_of!(info1 in DbInfos), subquery)
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` FOR SYSTEM_TIME AS OF @__ts \
+         WHERE info1 IN (SELECT id FROM `..Logs` FOR SYSTEM_TIME AS OF @__ts_0)"
+    );
+    let value = |name: &str| {
+        query_builder
+            .params()
+            .iter()
+            .find(|p| p.name.as_deref() == Some(name))
+            .and_then(|p| p.parameter_value.as_ref()?.value.clone())
+    };
+    assert_eq!(value("__ts").as_deref(), Some("2024-01-01 00:00:00"));
+    assert_eq!(value("__ts_0").as_deref(), Some("2023-01-01 00:00:00"));
+}
+
+#[test]
+fn test_add_where_in_subquery_keeps_sensitive_params_and_udfs() {
+    init_logger();
+    let udf = UdfResource::inline("CREATE TEMP FUNCTION norm(x STRING) AS (LOWER(x))");
+    let subquery = DbSecrets::select()
+        .with_client(BigqueryClient::empty())
+        .with_udf(udf.clone())
+        .select_column(name_of!(id in DbSecrets))
+        .unwrap()
+        .add_where_eq(name_of!(token in DbSecrets), Some(&"hunter2".to_string()))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_in_subquery(name_of!(row_id in DbInfos), subquery)
+        .unwrap()
+        .build_query()
+        .unwrap();
+    let logged = format!("{:?}", query_builder.get_redacted_params());
+    assert!(!logged.contains("hunter2"), "{}", logged);
+    let request_query = query_builder.build_query_request().query.unwrap();
+    assert!(
+        request_query.starts_with(&format!("{};", udf.definition())),
+        "{}",
+        request_query
+    );
+}
+
+#[test]
+fn test_merge_filters() {
+    init_logger();
+    let (shared_where, shared_params, shared_sensitive) = DbInfos::select()
+        .add_where_eq(name_of!(info1 in DbInfos), Some(&"a".to_string()))
+        .unwrap()
+        .add_where_eq(name_of!(info2 in DbInfos), None::<&String>)
+        .unwrap()
+        .into_filters();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq(name_of!(info3 in DbInfos), Some(&"c".to_string()))
+        .unwrap()
+        .merge_filters(shared_where, shared_params, shared_sensitive)
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("WHERE info3 = @__WHERE_0 AND info1 = @__WHERE_1 AND info is NULL"));
+    let params: Vec<_> = query_builder
+        .params()
+        .iter()
+        .map(|p| {
+            (
+                p.name.clone().unwrap(),
+                p.parameter_value.clone().unwrap().value.unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        params,
+        vec![
+            ("__WHERE_0".to_string(), "c".to_string()),
+            ("__WHERE_1".to_string(), "a".to_string())
+        ]
+    );
+}
+
+#[test]
+fn test_merge_filters_keeps_sensitive_params() {
+    init_logger();
+    let (secret_where, secret_params, secret_sensitive) = DbSecrets::select()
+        .add_where_eq(name_of!(token in DbSecrets), Some(&"hunter2".to_string()))
+        .unwrap()
+        .into_filters();
+    assert_eq!(secret_sensitive, vec!["__WHERE_0", "__PARAM_token"]);
+    let query_builder = DbSecrets::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq(name_of!(note in DbSecrets), Some(&"hello".to_string()))
+        .unwrap()
+        .merge_filters(secret_where, secret_params, secret_sensitive)
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("WHERE note = @__WHERE_0 AND token = @__WHERE_1"));
+    let logged = format!("{:?}", query_builder.get_redacted_params());
+    assert!(!logged.contains("hunter2"), "{}", logged);
+    assert!(logged.contains("hello"), "{}", logged);
+}
+
+#[test]
+fn test_insert_returning_request() {
+    init_logger();
+    let data = DbInfos {
+        row_id: 7,
+        info1: Some("a".to_string()),
+        ..Default::default()
+    };
+    let request = DbInfos::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .build_query()
+        .unwrap()
+        .build_insert_returning_request()
+        .unwrap();
+    let query = request.query.unwrap();
+    assert!(query.starts_with("insert into `..Infos`"), "{}", query);
+    assert!(query.ends_with(
+        ";\nSELECT info1, info, info3, yes, info4i, Id FROM `..Infos` WHERE Id = @__PARAM_Id;"
+    ));
+
+    // without a pk the row is matched on the other non-null fields
+    let data = DbLogs {
+        source: Some("test".to_string()),
+        message: Some("hello".to_string()),
+        ..Default::default()
+    };
+    let request = DbLogs::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .build_query()
+        .unwrap()
+        .build_insert_returning_request()
+        .unwrap();
+    assert!(request
+        .query
+        .unwrap()
+        .ends_with("FROM `..Logs` WHERE message = @__PARAM_message AND source = @__PARAM_source;"));
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_insert_returning() {
+    init_logger();
+    let client = get_test_client().await;
+    let data = DbInfos {
+        client: client.clone(),
+        row_id: 4714,
+        info1: Some("returning".to_string()),
+        info4i: Some(12),
+        ..Default::default()
+    };
+    let inserted = DbInfos::insert()
+        .with_client(client.clone())
+        .set_data(data.clone())
+        .build_query()
+        .unwrap()
+        .insert_returning()
+        .await
+        .unwrap();
+    assert_eq!(inserted.row_id, data.row_id);
+    assert_eq!(inserted.info1, data.info1);
+    assert_eq!(inserted.info4i, data.info4i);
+    DbInfos::delete()
+        .with_client(client)
+        .set_data(inserted)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_with_table_name() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .with_table_name("Infos_20230101")
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos_20230101`"
+    );
+
+    let delete = DbInfos::delete()
+        .with_client(BigqueryClient::empty())
+        .with_table_name("Infos_archive")
+        .unwrap()
+        .set_data(DbInfos::default())
+        .build_query()
+        .unwrap();
+    assert!(delete
+        .get_query_string()
+        .starts_with("DELETE FROM `..Infos_archive` WHERE"));
+
+    assert!(DbInfos::select()
+        .with_table_name("Infos` WHERE 1=1 --")
+        .is_err());
+}
+
+#[test]
+fn test_clear_filters() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq(name_of!(info1 in DbInfos), Some(&"a".to_string()))
+        .unwrap()
+        .add_where_eq::<bool>(name_of!(info4b in DbInfos), None)
+        .unwrap()
+        .set_limit(5)
+        .clear_filters()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` LIMIT 5"
+    );
+    assert!(query_builder.params().is_empty());
+}
+
+#[test]
+fn test_date_time_fractional_seconds() {
+    let time = DateTime::parse_from_rfc3339("2023-01-01T00:00:00.123456Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    assert_eq!(
+        DateTime::<Utc>::from_param(&json!("2023-01-01T00:00:00.123456Z")).unwrap(),
+        time
+    );
+    let value = time.to_param();
+    assert_eq!(value, json!("2023-01-01 00:00:00.123456"));
+    assert_eq!(DateTime::<Utc>::from_param(&value).unwrap(), time);
+
+    let whole_seconds = DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    assert_eq!(whole_seconds.to_param(), json!("2023-01-01 00:00:00"));
+
+    let nanos = DateTime::parse_from_rfc3339("2023-01-01T00:00:00.123456789Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    assert_eq!(nanos.to_param(), json!("2023-01-01 00:00:00.123456"));
+    assert_eq!(
+        nanos.naive_utc().to_param(),
+        json!("2023-01-01 00:00:00.123456")
+    );
+}
+
+#[test]
+fn test_query_result_row_error_context() {
+    init_logger();
+    let row = [
+        ("Id", json!("1")),
+        ("info1", json!(null)),
+        ("info", json!(null)),
+        ("info3", json!(null)),
+        ("info4i", json!("not a number")),
+        ("yes", json!(null)),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect();
+    let error = DbInfos::new_from_query_result_row(BigqueryClient::empty(), &row)
+        .expect_err("info4i should fail to parse");
+    assert!(
+        error
+            .to_string()
+            .starts_with("failed to parse column 'info4i' (field info4i): "),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_field_conversion_error() {
+    init_logger();
+    let row = [
+        ("Id", json!("abc")),
+        ("info1", json!(null)),
+        ("info", json!(null)),
+        ("info3", json!(null)),
+        ("info4i", json!(null)),
+        ("yes", json!(null)),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect();
+    let error = DbInfos::new_from_query_result_row(BigqueryClient::empty(), &row)
+        .expect_err("a string can not be parsed into an i64");
+    let error = error
+        .downcast_ref::<FieldConversionError>()
+        .expect("should be a FieldConversionError");
+    assert_eq!(error.column, "Id");
+    assert_eq!(error.field, "row_id");
+    assert_eq!(error.expected_bq_type, "INT64");
+    assert_eq!(error.got_value, json!("abc"));
+}
+
+#[test]
+fn test_field_conversion_error_missing_column() {
+    init_logger();
+    let row = [("Id", json!("1")), ("info1", json!(null))]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+    let error = DbInfos::new_from_query_result_row(BigqueryClient::empty(), &row)
+        .expect_err("info is not in the row");
+    let error = error
+        .downcast_ref::<FieldConversionError>()
+        .expect("should be a FieldConversionError");
+    assert_eq!(error.column, "info");
+    assert_eq!(error.field, "info2");
+    assert_eq!(error.got_value, json!(null));
+    assert_eq!(error.message, "column missing from result");
+
+    let row = [
+        ("id", json!("2")),
+        ("address_street", json!("a")),
+        ("address_town", json!("b")),
+        ("billing_street", json!(null)),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect();
+    let error = DbCustomers::new_from_query_result_row(BigqueryClient::empty(), &row)
+        .expect_err("billing_town is not in the row");
+    let error = error
+        .downcast_ref::<FieldConversionError>()
+        .expect("should be a FieldConversionError");
+    assert_eq!(error.column, "billing_town");
+    assert_eq!(error.field, "city");
+    assert_eq!(error.expected_bq_type, "STRING");
+    assert_eq!(error.message, "column missing from result");
+}
+
+#[test]
+fn test_as_of() {
+    init_logger();
+    let time = DateTime::parse_from_rfc3339("2023-01-01T12:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .as_of(time)
+        .add_where_eq(name_of!(row_id in DbInfos), Some(&1))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` FOR SYSTEM_TIME AS OF @__ts WHERE Id = @__WHERE_0"
+    );
+    let ts = query_builder
+        .params()
+        .iter()
+        .find(|p| p.name.as_deref() == Some("__ts"))
+        .unwrap();
+    assert_eq!(
+        ts.parameter_type.as_ref().unwrap().type_.as_deref(),
+        Some("TIMESTAMP")
+    );
+    assert_eq!(
+        ts.parameter_value.as_ref().unwrap().value.as_deref(),
+        Some("2023-01-01 12:00:00")
+    );
+}
+
+#[test]
+fn test_empty_as_null() {
+    init_logger();
+    let data = DbNotes {
+        id: 1,
+        title: String::new(),
+        body: String::new(),
+        ..Default::default()
+    };
+    let insert = DbNotes::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(data.clone())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        insert.get_query_string(),
+        "insert into `..Notes` (body, id, title) values(@__PARAM_body, @__PARAM_id, NULL)"
+    );
+
+    let update = DbNotes::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(DbNotes {
+            title: "t".to_string(),
+            ..data
+        })
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        update.get_query_string(),
+        "update `..Notes` set body = @__PARAM_body, id = @__PARAM_id, title = @__PARAM_title WHERE id = @__PARAM_id"
+    );
+}
+
+#[test]
+fn test_max_rows() {
+    init_logger();
+    let max_rows = MaxRows::new(2, MaxRowsBehavior::Error);
+    assert_eq!(max_rows.apply(vec![1, 2]).unwrap(), vec![1, 2]);
+    let err = max_rows.apply(vec![1, 2, 3]).unwrap_err();
+    assert!(err.to_string().contains("exceeds max_rows of 2"), "{}", err);
+
+    let max_rows = MaxRows::new(2, MaxRowsBehavior::Truncate);
+    assert_eq!(max_rows.apply(vec![1, 2, 3]).unwrap(), vec![1, 2]);
+
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .max_rows(10)
+        .build_query()
+        .unwrap();
+    debug!("query: {:?}", query_builder);
+}
+
+#[test]
+fn test_integer_from_param() {
+    init_logger();
+    assert_eq!(i64::from_param(&json!("42")).unwrap(), 42);
+    assert_eq!(i64::from_param(&json!(42)).unwrap(), 42);
+    assert_eq!(i32::from_param(&json!("-7")).unwrap(), -7);
+    assert_eq!(i32::from_param(&json!(-7)).unwrap(), -7);
+    assert!(i32::from_param(&json!(1.5)).is_err());
+}
+
+#[test]
+fn test_float_from_param() {
+    init_logger();
+    assert_eq!(f64::from_param(&json!("1.5")).unwrap(), 1.5);
+    assert_eq!(f64::from_param(&json!(1.5)).unwrap(), 1.5);
+    assert_eq!(f64::from_param(&json!("-2")).unwrap(), -2.0);
+    assert_eq!(f64::from_param(&json!(-2)).unwrap(), -2.0);
+    assert_eq!(f64::from_param(&json!("1.25E-3")).unwrap(), 0.00125);
+    assert_eq!(f64::from_param(&json!(1.5e10)).unwrap(), 1.5e10);
+    assert_eq!(f64::from_param(&json!("Infinity")).unwrap(), f64::INFINITY);
+    assert!(f64::from_param(&json!("NaN")).unwrap().is_nan());
+    assert!(f64::from_param(&json!("abc")).is_err());
+    assert!(f64::from_param(&json!(true)).is_err());
+}
+
+#[test]
+fn test_update_matching() {
+    init_logger();
+    let data = DbInfos {
+        info1: Some("updated".to_string()),
+        info4i: Some(2),
+        ..Default::default()
+    };
+    let query_builder = DbInfos::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(data.clone())
+        .update_matching()
+        .add_where_eq(name_of!(info3 in DbInfos), Some(&"group".to_string()))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "update `..Infos` set info1 = @__PARAM_info1, info = NULL, info3 = NULL, yes = NULL, info4i = @__PARAM_info4i WHERE info3 = @__WHERE_0"
+    );
+
+    let result = DbInfos::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .update_matching()
+        .build_query();
+    assert!(result.is_err(), "update_matching without where must fail");
+}
+
+#[test]
+fn test_where_and_field_params_do_not_collide() {
+    init_logger();
+    let data = DbInfos {
+        info1: Some("updated".to_string()),
+        ..Default::default()
+    };
+    let query_builder = DbInfos::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .update_matching()
+        .add_where_eq(name_of!(info1 in DbInfos), Some(&"old".to_string()))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("set info1 = @__PARAM_info1, info = NULL, info3 = NULL, yes = NULL, info4i = NULL WHERE info1 = @__WHERE_0"));
+    let mut names: Vec<_> = query_builder
+        .params()
+        .iter()
+        .map(|p| p.name.clone().unwrap())
+        .collect();
+    let count = names.len();
+    names.sort();
+    names.dedup();
+    assert_eq!(names.len(), count, "parameter names must be unique");
+    assert!(names.contains(&"__WHERE_0".to_string()));
+    assert!(names.contains(&"__PARAM_info1".to_string()));
+}
+
+#[test]
+fn test_new_from_query_response_uses_schema_order() {
+    use google_bigquery2::api::{
+        QueryResponse, TableCell, TableFieldSchema, TableRow, TableSchema,
+    };
+    init_logger();
+    // reversed compared to the sorted field order (info1, info, info3, yes, info4i, Id)
+    let columns = ["Id", "info4i", "yes", "info3", "info", "info1"];
+    let values = [
+        json!("7"),
+        json!("4"),
+        json!("true"),
+        json!("c"),
+        json!("b"),
+        json!("a"),
+    ];
+    let query_response = QueryResponse {
+        schema: Some(TableSchema {
+            fields: Some(
+                columns
+                    .iter()
+                    .map(|name| TableFieldSchema {
+                        name: Some(name.to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+        }),
+        rows: Some(vec![TableRow {
+            f: Some(
+                values
+                    .into_iter()
+                    .map(|v| TableCell { v: Some(v) })
+                    .collect(),
+            ),
+        }]),
+        ..Default::default()
+    };
+    let rows = DbInfos::new_from_query_response(BigqueryClient::empty(), query_response).unwrap();
+    assert_eq!(rows.len(), 1);
+    let row = &rows[0];
+    assert_eq!(row.row_id, 7);
+    assert_eq!(row.info1.as_deref(), Some("a"));
+    assert_eq!(row.info2.as_deref(), Some("b"));
+    assert_eq!(row.info3.as_deref(), Some("c"));
+    assert_eq!(row.info4i, Some(4));
+    assert_eq!(row.info4b, Some(true));
+}
+
+#[test]
+fn test_where_typed_null() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_typed_null::<i32>(name_of!(info4i in DbInfos))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with("WHERE info4i = @__WHERE_0"));
+    let params = query_builder.params();
+    assert_eq!(params.len(), 1);
+    assert_eq!(params[0].name.as_deref(), Some("__WHERE_0"));
+    assert_eq!(
+        params[0].parameter_type.as_ref().unwrap().type_.as_deref(),
+        Some("INT64")
+    );
+    assert!(params[0].parameter_value.is_none());
+}
+
+#[test]
+fn test_generated_order_matches_parse_order() {
+    use google_bigquery2::api::{
+        QueryResponse, TableCell, TableFieldSchema, TableRow, TableSchema,
+    };
+    init_logger();
+    let select = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .build_query()
+        .unwrap();
+    let insert = DbInfos::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(DbInfos::default())
+        .build_query()
+        .unwrap();
+    let columns: Vec<&str> = select
+        .get_query_string()
+        .trim_start_matches("SELECT ")
+        .split(" FROM ")
+        .next()
+        .unwrap()
+        .split(", ")
+        .collect();
+    assert!(insert
+        .get_query_string()
+        .contains(&format!("({})", columns.join(", "))));
+
+    // BigQuery returns the columns in the order they were selected
+    let values = [
+        ("info1", json!("a")),
+        ("info", json!("b")),
+        ("info3", json!("c")),
+        ("yes", json!("true")),
+        ("info4i", json!("4")),
+        ("Id", json!("7")),
+    ];
+    let query_response = QueryResponse {
+        schema: Some(TableSchema {
+            fields: Some(
+                columns
+                    .iter()
+                    .map(|name| TableFieldSchema {
+                        name: Some(name.to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+        }),
+        rows: Some(vec![TableRow {
+            f: Some(
+                columns
+                    .iter()
+                    .map(|name| TableCell {
+                        v: values
+                            .iter()
+                            .find(|(n, _)| n == name)
+                            .map(|(_, v)| v.clone()),
+                    })
+                    .collect(),
+            ),
+        }]),
+        ..Default::default()
+    };
+    let rows = DbInfos::new_from_query_response(BigqueryClient::empty(), query_response).unwrap();
+    let row = &rows[0];
+    assert_eq!(row.row_id, 7);
+    assert_eq!(row.info1.as_deref(), Some("a"));
+    assert_eq!(row.info2.as_deref(), Some("b"));
+    assert_eq!(row.info3.as_deref(), Some("c"));
+    assert_eq!(row.info4i, Some(4));
+    assert_eq!(row.info4b, Some(true));
+}
+
+#[test]
+fn test_compact_debug() {
+    let data = DbInfos {
+        row_id: 7,
+        info1: Some("a long value that should not be logged".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(data.compact_debug(), "Infos(Id = 7)");
+    let data = DbSecrets {
+        id: 3,
+        token: Some("secret".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(data.compact_debug(), "Secrets(id = 3)");
+}
+
+#[test]
+fn test_diff_with() {
+    init_logger();
+    let remote = DbInfos {
+        row_id: 8,
+        info1: Some("a".to_string()),
+        info4i: Some(1),
+        ..Default::default()
+    };
+    let mut local = remote.clone();
+    local.info1 = Some("b".to_string());
+    local.info4b = Some(true);
+    let changes = local.diff_with(Some(&remote)).unwrap();
+    assert_eq!(
+        changes,
+        vec![
+            FieldChange {
+                field_name: "info1".to_string(),
+                db_name: "info1".to_string(),
+                old_value: Some(json!("a")),
+                new_value: json!("b"),
+            },
+            FieldChange {
+                field_name: "info4b".to_string(),
+                db_name: "yes".to_string(),
+                old_value: Some(json!(null)),
+                new_value: json!("TRUE"),
+            },
+        ]
+    );
+    assert!(remote.diff_with(Some(&remote)).unwrap().is_empty());
+    let all_new = local.diff_with(None).unwrap();
+    assert_eq!(all_new.len(), 6);
+    assert!(all_new.iter().all(|change| change.old_value.is_none()));
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_diff_with_remote() {
+    init_logger();
+    let client = get_test_client().await;
+    let mut local = DbInfos::select()
+        .with_client(client.clone())
+        .set_limit(1)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("no data")
+        .remove(0);
+    assert!(local
+        .diff_with_remote(client.clone())
+        .await
+        .unwrap()
+        .is_empty());
+    local.info1 = Some("changed locally".to_string());
+    let changes = local.diff_with_remote(client.clone()).await.unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].db_name, "info1");
+
+    local.row_id = -4712;
+    let changes = local.diff_with_remote(client).await.unwrap();
+    assert_eq!(changes.len(), 6);
+}
+
+#[test]
+fn test_builder() {
+    init_logger();
+    let data = DbInfos::builder()
+        .with_row_id(1926)
+        .with_info1(Some("built".to_string()))
+        .with_info4i(Some(4))
+        .build(BigqueryClient::empty())
+        .unwrap();
+    assert_eq!(data.row_id, 1926);
+    assert_eq!(data.info1.as_deref(), Some("built"));
+    assert_eq!(data.info2, None);
+    assert_eq!(data.info4i, Some(4));
+
+    let error = DbInfos::builder()
+        .with_info1(Some("no pk".to_string()))
+        .build(BigqueryClient::empty())
+        .expect_err("row_id is required");
+    assert!(error.to_string().contains("row_id"), "{}", error);
+
+    let order = DbBuiltOrders::builder()
+        .with_order_id(7)
+        .build(BigqueryClient::empty())
+        .unwrap();
+    assert_eq!(order.order_id, 7);
+    assert!(order.items.is_empty());
+    assert_eq!(order.loaded_at, 0);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_builder_insert() {
+    init_logger();
+    let client = get_test_client().await;
+    let data = DbInfos::builder()
+        .with_row_id(1926)
+        .with_info1(Some("built".to_string()))
+        .build(client.clone())
+        .unwrap();
+    DbInfos::insert()
+        .with_client(client.clone())
+        .set_data(data.clone())
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    let loaded = DbInfos::get_by_pk(client.clone(), &1926i64).await.unwrap();
+    assert_eq!(loaded.info1.as_deref(), Some("built"));
+    DbInfos::delete()
+        .with_client(client)
+        .set_data(data)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_from_db_json() {
+    init_logger();
+    let data = DbInfos {
+        row_id: 5,
+        info1: Some("a".to_string()),
+        info2: Some("b".to_string()),
+        info4i: Some(3),
+        info4b: Some(false),
+        ..Default::default()
+    };
+    let json = data.to_json().unwrap();
+    let parsed = DbInfos::from_db_json(BigqueryClient::empty(), json.as_object().unwrap()).unwrap();
+    assert_eq!(parsed.row_id, data.row_id);
+    assert_eq!(parsed.info1, data.info1);
+    assert_eq!(parsed.info2, data.info2);
+    assert_eq!(parsed.info3, data.info3);
+    assert_eq!(parsed.info4i, data.info4i);
+    assert_eq!(parsed.info4b, data.info4b);
+    assert_eq!(parsed.to_json().unwrap(), json);
+
+    let unknown = json!({"Id": "1", "unknown": "x"});
+    assert!(DbInfos::from_db_json(BigqueryClient::empty(), unknown.as_object().unwrap()).is_err());
+}
+
+#[test]
+fn test_param() {
+    init_logger();
+    let min_age = param("min_age", &18i64);
+    assert_eq!(min_age.name.as_deref(), Some("min_age"));
+    assert_eq!(
+        min_age.parameter_type.unwrap().type_.as_deref(),
+        Some("INT64")
+    );
+    assert_eq!(
+        min_age.parameter_value.unwrap().value.as_deref(),
+        Some("18")
+    );
+
+    let null_param = param::<Option<String>>("name", &None);
+    assert_eq!(
+        null_param.parameter_type.unwrap().type_.as_deref(),
+        Some("STRING")
+    );
+    assert!(null_param.parameter_value.is_none());
+}
+
+#[test]
+fn test_bool_from_param() {
+    init_logger();
+    for value in ["TRUE", "true", "True", "t", "T", "1", "yes", "YES"] {
+        assert!(bool::from_param(&json!(value)).unwrap(), "{}", value);
+    }
+    for value in ["FALSE", "false", "False", "f", "F", "0", "no", "No"] {
+        assert!(!bool::from_param(&json!(value)).unwrap(), "{}", value);
+    }
+    assert!(bool::from_param(&json!(true)).unwrap());
+    assert!(bool::from_param(&json!(1)).unwrap());
+    assert!(!bool::from_param(&json!(0)).unwrap());
+    assert!(bool::from_param(&json!("2")).is_err());
+    assert!(bool::from_param(&json!("maybe")).is_err());
+    assert_eq!(true.to_param(), json!("TRUE"));
+    assert_eq!(false.to_param(), json!("FALSE"));
+}
+
+/// Counts the warnings that are logged while it is the default subscriber.
+#[derive(Default)]
+struct WarningCounter(std::sync::atomic::AtomicUsize);
+
+impl tracing::Subscriber for WarningCounter {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+    fn event(&self, event: &tracing::Event<'_>) {
+        if *event.metadata().level() == tracing::Level::WARN {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+    fn enter(&self, _: &tracing::span::Id) {}
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[test]
+fn test_int64_param_without_warning() {
+    let counter = std::sync::Arc::new(WarningCounter::default());
+    let params = tracing::subscriber::with_default(counter.clone(), || {
+        vec![
+            param("big", &i64::MAX),
+            param("small", &-42i32),
+            param("some", &Some(7i64)),
+            param("patch", &Patch::Value(8i64)),
+        ]
+    });
+    assert_eq!(counter.0.load(std::sync::atomic::Ordering::Relaxed), 0);
+    let values: Vec<Option<String>> = params
+        .into_iter()
+        .map(|p| p.parameter_value.and_then(|v| v.value))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            Some("9223372036854775807".to_string()),
+            Some("-42".to_string()),
+            Some("7".to_string()),
+            Some("8".to_string()),
+        ]
+    );
+    assert_eq!(None::<i64>.to_param_string(), None);
+
+    // the counter does see the warning of the fallback path
+    tracing::subscriber::with_default(counter.clone(), || {
+        convert_value_to_string(json!([1])).unwrap();
+    });
+    assert_eq!(counter.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_redacted_params() {
+    init_logger();
+    let query_builder = DbSecrets::select()
+        .with_client(BigqueryClient::empty())
+        .add_where_eq(name_of!(token in DbSecrets), Some(&"hunter2".to_string()))
+        .unwrap()
+        .add_where_eq(name_of!(note in DbSecrets), Some(&"hello".to_string()))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    let logged = format!("{:?}", query_builder.get_redacted_params());
+    assert!(!logged.contains("hunter2"), "{}", logged);
+    assert!(logged.contains("hello"), "{}", logged);
+
+    let example = DbSecrets {
+        token: Some("hunter2".to_string()),
+        ..Default::default()
+    };
+    let query_builder = DbSecrets::select()
+        .with_client(BigqueryClient::empty())
+        .set_data(example)
+        .add_where_by_example()
+        .unwrap()
+        .build_query()
+        .unwrap();
+    let logged = format!("{:?}", query_builder.get_redacted_params());
+    assert!(!logged.contains("hunter2"), "{}", logged);
+
+    let query_builder = DbSecrets::select()
+        .with_client(BigqueryClient::empty().redact_params(true))
+        .add_where_eq(name_of!(note in DbSecrets), Some(&"hello".to_string()))
+        .unwrap()
+        .build_query()
+        .unwrap();
+    let logged = format!("{:?}", query_builder.get_redacted_params());
+    assert!(!logged.contains("hello"), "{}", logged);
+}
+
+#[test]
+fn test_sensitive_values_are_not_logged() {
+    let recorder = FieldRecorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || {
+        let data = DbSecrets {
+            id: 1,
+            token: Some("hunter2".to_string()),
+            note: Some("hello".to_string()),
+            ..Default::default()
+        };
+        let insert = DbSecrets::insert()
+            .with_client(BigqueryClient::empty())
+            .set_data(data.clone())
+            .build_query()
+            .unwrap();
+        debug!("built: {:?}", insert);
+        let update = DbSecrets::update()
+            .with_client(BigqueryClient::empty())
+            .set_data(data.clone())
+            .build_query()
+            .unwrap();
+        debug!("built: {:?}", update);
+        let select = DbSecrets::select()
+            .with_client(BigqueryClient::empty())
+            .add_where_eq(name_of!(token in DbSecrets), Some(&"hunter2".to_string()))
+            .unwrap()
+            .add_where_in(
+                name_of!(token in DbSecrets),
+                &[Some("hunter2".to_string()), None],
+            )
+            .unwrap()
+            .build_query()
+            .unwrap();
+        debug!("built: {:?}", select);
+        let mut copy = DbSecrets::default();
+        copy.set_field_value("token", &json!("hunter2")).unwrap();
+        let row: HashMap<String, serde_json::Value> = [
+            ("id".to_string(), json!("1")),
+            ("token".to_string(), json!("hunter2")),
+            ("note".to_string(), json!("hello")),
+        ]
+        .into_iter()
+        .collect();
+        let parsed = DbSecrets::new_from_query_result_row(BigqueryClient::empty(), &row).unwrap();
+        assert_eq!(parsed.token.as_deref(), Some("hunter2"));
+        let parsed =
+            DbSecrets::new_from_partial_query_result_row(BigqueryClient::empty(), &row).unwrap();
+        assert_eq!(parsed.token.as_deref(), Some("hunter2"));
+    });
+    let fields = recorder.0.lock().unwrap();
+    assert!(fields.iter().any(|(_, value)| value.contains("built:")));
+    for (field, value) in fields.iter() {
+        assert!(!value.contains("hunter2"), "{} = {}", field, value);
+    }
+}
+
+#[test]
+fn test_encoded_param_is_not_logged() {
+    let recorder = FieldRecorder::default();
+    let param = tracing::subscriber::with_default(recorder.clone(), || {
+        google_bigquery_v2::data::encoded_param("__PARAM_token", "STRING", &json!("hunter2"))
+            .unwrap()
+            .unwrap()
+    });
+    assert_eq!(
+        param.parameter_value.unwrap().value.as_deref(),
+        Some("hunter2")
+    );
+    for (field, value) in recorder.0.lock().unwrap().iter() {
+        assert!(!value.contains("hunter2"), "{} = {}", field, value);
+    }
+}
+
+#[test]
+fn test_insert_use_defaults() {
+    init_logger();
+    let data = DbInfos {
+        row_id: 42,
+        info1: Some("a".to_string()),
+        ..Default::default()
+    };
+    let insert = DbInfos::insert()
+        .use_defaults()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .build_query()
+        .unwrap();
+    // explicit nulls are not replaced by the default
+    assert_eq!(
+        insert.get_query_string(),
+        "insert into `..Infos` (info1, info, info3, yes, info4i, Id) values(@__PARAM_info1, NULL, NULL, NULL, NULL, @__PARAM_Id)"
+    );
+
+    let data = DbPatchInfos {
+        id: 1,
+        a: Patch::Value("a".to_string()),
+        b: Patch::Null,
+        c: Patch::Undefined,
+        ..Default::default()
+    };
+    let insert = DbPatchInfos::insert()
+        .use_defaults()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        insert.get_query_string(),
+        "insert into `..PatchInfos` (a, b, c, id) values(@__PARAM_a, NULL, DEFAULT, @__PARAM_id)"
+    );
+}
+
+#[test]
+fn test_insert_and_update_query_strings() {
+    init_logger();
+    let data = DbInfos {
+        row_id: 42,
+        info1: Some("a".to_string()),
+        info4b: Some(false),
+        ..Default::default()
+    };
+    let insert = DbInfos::insert()
+        .with_client(BigqueryClient::empty())
+        .set_data(data.clone())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        insert.get_query_string(),
+        "insert into `..Infos` (info1, info, info3, yes, info4i, Id) values(@__PARAM_info1, NULL, NULL, @__PARAM_yes, NULL, @__PARAM_Id)"
+    );
+
+    let update = DbInfos::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(data.clone())
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        update.get_query_string(),
+        "update `..Infos` set info1 = @__PARAM_info1, info = NULL, info3 = NULL, yes = @__PARAM_yes, info4i = NULL, Id = @__PARAM_Id WHERE Id = @__PARAM_Id"
+    );
+
+    let delete = DbInfos::delete()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        delete.get_query_string(),
+        "DELETE FROM `..Infos` WHERE Id = @__PARAM_Id"
+    );
+}
+
+#[test]
+fn test_patch_update() {
+    init_logger();
+    let data = DbPatchInfos {
+        id: 1,
+        a: Patch::Undefined,
+        b: Patch::Null,
+        c: Patch::Value(3),
+        ..Default::default()
+    };
+    let update = DbPatchInfos::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        update.get_query_string(),
+        "update `..PatchInfos` set b = NULL, c = @__PARAM_c, id = @__PARAM_id WHERE id = @__PARAM_id"
+    );
+
+    assert_eq!(Patch::<i64>::from_param(&json!(null)).unwrap(), Patch::Null);
+    assert_eq!(
+        Patch::<i64>::from_param(&json!("3")).unwrap(),
+        Patch::Value(3)
+    );
+    assert!(Patch::<i64>::default().is_undefined());
+}
+
+#[test]
+fn test_pk_accessors() {
+    init_logger();
+    let mut entry = DbInfos::default();
+    assert_eq!(*entry.pk(), 0);
+    entry.set_pk(123);
+    assert_eq!(*entry.pk(), 123);
+    assert_eq!(entry.row_id, 123);
+}
+
+#[test]
+fn test_parameterized_limit() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .set_limit(10)
+        .build_query()
+        .unwrap();
+    assert!(query_builder.get_query_string().ends_with(" LIMIT 10"));
+
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .set_limit(10)
+        .parameterize_limit()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` LIMIT @__LIMIT"
+    );
+    let params = query_builder.get_redacted_params();
+    assert_eq!(params.len(), 1);
+    assert_eq!(params[0].name.as_deref(), Some("__LIMIT"));
+    assert_eq!(
+        params[0].parameter_value.as_ref().unwrap().value.as_deref(),
+        Some("10")
+    );
+}
+
+#[test]
+fn test_paged_stable_order() {
+    init_logger();
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_order_by(name_of!(info2 in DbInfos), OrderDirection::Descending)
+        .paged(2, 25)
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT info1, info, info3, yes, info4i, Id FROM `..Infos` ORDER BY info DESC, Id ASC LIMIT 25 OFFSET 50"
+    );
+
+    // the pk is not added again if it is already ordered by
+    let query_builder = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .add_order_by(name_of!(row_id in DbInfos), OrderDirection::Descending)
+        .paged(0, 10)
+        .unwrap()
+        .build_query()
+        .unwrap();
+    assert!(query_builder
+        .get_query_string()
+        .ends_with(" ORDER BY Id DESC LIMIT 10 OFFSET 0"));
+
+    assert!(DbInfos::select().paged(u32::MAX, 2).is_err());
+}
+
+#[test]
+fn test_versioned_update() {
+    init_logger();
+    assert_eq!(
+        DbVersionedInfos::get_version_field_name().as_deref(),
+        Some("version")
+    );
+    assert_eq!(DbInfos::get_version_field_name(), None);
+    let data = DbVersionedInfos {
+        id: 7,
+        info: Some("a".to_string()),
+        version: Some(2),
+        ..Default::default()
+    };
+    let update = DbVersionedInfos::update()
+        .with_client(BigqueryClient::empty())
+        .set_data(data)
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        update.get_query_string(),
+        "update `..VersionedInfos` set id = @__PARAM_id, info = @__PARAM_info, version = IFNULL(version, 0) + 1 WHERE id = @__PARAM_id AND version = @__PARAM_version"
+    );
+}
+
+async fn get_test_client() -> BigqueryClient {
+    BigqueryClient::new("testrustproject-372221", "test1", None)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn simple_query() {
+    init_logger();
+    let client = get_test_client().await;
+    let q = DbInfos::select()
+        .with_client(client)
+        .add_order_by(name_of!(row_id in DbInfos), OrderDirection::Descending)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    let q = match q {
+        QueryResultType::WithRowData(q) => q,
+        QueryResultType::WithoutRowData(e) => panic!("no data: {:?}", e),
+    };
+    let mut last_num = 999999999999999999;
+    for line in q {
+        info!("line: {:?}", line);
+        debug!("row_id > last: {} <= {}", line.row_id, last_num);
+        assert!(line.row_id <= last_num);
+        last_num = line.row_id;
+    }
+}
+
+#[tokio::test]
+async fn test_select_limit_1() {
+    init_logger();
+    let client = get_test_client().await;
+    let q: Vec<DbInfos> = DbInfos::select()
+        .with_client(client)
+        .set_limit(1)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("no data");
+    assert_eq!(q.len(), 1);
+}
+
+#[tokio::test]
+async fn test_upsert() {
+    init_logger();
+    let client = get_test_client().await;
+    let mut local = DbInfos {
+        client: client.clone(),
+        row_id: 1923,
+        info1: None,
+        info2: None,
+        info3: None,
+        info4i: None,
+        info4b: None,
+    };
+    local.upsert().await.expect("could not perform upsert!");
+    DbInfos::delete()
+        .with_client(client)
+        .set_data(local)
+        .build_query()
+        .expect("could not build delete")
+        .run()
+        .await
+        .expect("could not run delete")
+        .expect_without_data("delete should not return any data");
+}
+
+#[tokio::test]
+async fn test_find_duplicate_pks_wrong_type() {
+    init_logger();
+    let error = DbInfos::find_duplicate_pks::<String>(BigqueryClient::empty())
+        .await
+        .expect_err("STRING pks should be rejected for an INT64 pk");
+    assert!(error.to_string().contains("INT64"), "{}", error);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_find_duplicate_pks() {
+    init_logger();
+    let client = get_test_client().await;
+    let duplicate = DbInfos {
+        client: client.clone(),
+        row_id: 1925,
+        ..Default::default()
+    };
+    for _ in 0..2 {
+        DbInfos::insert()
+            .with_client(client.clone())
+            .set_data(duplicate.clone())
+            .build_query()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+    }
+    let duplicates: Vec<i64> = DbInfos::find_duplicate_pks(client.clone()).await.unwrap();
+    DbInfos::delete()
+        .with_client(client)
+        .set_data(duplicate)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    assert!(duplicates.contains(&1925), "{:?}", duplicates);
+}
+
+#[tokio::test]
+async fn test_upsert_on() {
+    init_logger();
+    let client = get_test_client().await;
+    let mut local = DbInfos {
+        client: client.clone(),
+        row_id: 1924,
+        info1: Some("upsert_on natural key".to_string()),
+        info2: None,
+        info3: None,
+        info4i: Some(1),
+        info4b: None,
+    };
+    local.upsert_on(name_of!(info1 in DbInfos)).await.unwrap();
+    local.info4i = Some(2);
+    local.upsert_on(name_of!(info1 in DbInfos)).await.unwrap();
+
+    let rows = DbInfos::select()
+        .with_client(client.clone())
+        .add_where_eq(name_of!(info1 in DbInfos), local.info1.as_ref())
+        .unwrap()
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("select should return data");
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].info4i, Some(2));
+
+    DbInfos::delete()
+        .with_client(client)
+        .set_data(local)
+        .build_query()
+        .expect("could not build delete")
+        .run()
+        .await
+        .expect("could not run delete")
+        .expect_without_data("delete should not return any data");
+}
+
+#[tokio::test]
+async fn test_export_ndjson() {
+    init_logger();
+    let client = get_test_client().await;
+    let mut buffer = Vec::new();
+    let count = DbInfos::select()
+        .with_client(client)
+        .set_limit(3)
+        .build_query()
+        .unwrap()
+        .export_ndjson(&mut buffer)
+        .await
+        .unwrap();
+    let lines = String::from_utf8(buffer).unwrap();
+    assert_eq!(lines.lines().count() as u64, count);
+    for line in lines.lines() {
+        let row: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert!(row.get("Id").is_some(), "row is missing the pk: {}", line);
+    }
+}
+
+#[tokio::test]
+async fn test_save_conflict() {
+    init_logger();
+    let client = get_test_client().await;
+    let mut entry = DbVersionedInfos {
+        client: client.clone(),
+        id: 4711,
+        info: Some("first".to_string()),
+        version: Some(0),
+    };
+    DbVersionedInfos::insert()
+        .with_client(client.clone())
+        .set_data(entry.clone())
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    let mut stale = entry.clone();
+
+    entry.info = Some("second".to_string());
+    entry.save_versioned().await.expect("first save failed");
+    assert_eq!(entry.version, Some(1));
+
+    stale.info = Some("stale".to_string());
+    let error = stale.save().await.expect_err("stale save should conflict");
+    assert!(error.downcast_ref::<ConflictError>().is_some());
+
+    DbVersionedInfos::delete()
+        .with_client(client)
+        .set_data(entry)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_truncate_empty_client() {
+    init_logger();
+    let error = DbVersionedInfos::truncate(BigqueryClient::empty())
+        .await
+        .expect_err("truncate with an empty client should fail");
+    assert!(error.to_string().contains("VersionedInfos"));
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_truncate() {
+    init_logger();
+    let client = get_test_client().await;
+    DbVersionedInfos::insert()
+        .with_client(client.clone())
+        .set_data(DbVersionedInfos {
+            id: 4712,
+            info: Some("truncate me".to_string()),
+            ..Default::default()
+        })
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+
+    DbVersionedInfos::truncate(client.clone()).await.unwrap();
+
+    let rows = DbVersionedInfos::select()
+        .with_client(client)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("select should return data");
+    assert!(rows.is_empty());
+}
+
+#[test]
+fn test_to_json() {
+    let entry = DbInfos {
+        row_id: 7,
+        info2: Some("x".to_string()),
+        ..Default::default()
+    };
+    let json = entry.to_json().unwrap();
+    assert_eq!(json["Id"], json!(7));
+    assert_eq!(json["info"], json!("x"));
+    assert_eq!(json["yes"], json!(null));
+}
+
+#[test]
+fn test_table_schema() {
+    let schema = DbInfos::get_table_schema();
+    let fields: Vec<(String, String)> = schema
+        .fields
+        .unwrap()
+        .into_iter()
+        .map(|f| (f.name.unwrap(), f.type_.unwrap()))
+        .collect();
+    let expected = [
+        ("info1", "STRING"),
+        ("info", "STRING"),
+        ("info3", "STRING"),
+        ("yes", "BOOL"),
+        ("info4i", "INT64"),
+        ("Id", "INT64"),
+    ]
+    .map(|(name, ty)| (name.to_string(), ty.to_string()));
+    assert_eq!(fields, expected);
+}
+
+#[test]
+fn test_create_table_ddl() {
+    assert_eq!(
+        DbUserEvents::get_create_table_ddl(&BigqueryClient::empty()).unwrap(),
+        "CREATE TABLE `..UserEvents` (id INT64 NOT NULL, kind STRING, ts DATETIME, user_id INT64) \
+        PARTITION BY DATE(ts) CLUSTER BY user_id, kind"
+    );
+    assert_eq!(
+        DbInfos::get_create_table_ddl(&BigqueryClient::empty()).unwrap(),
+        "CREATE TABLE `..Infos` (info1 STRING, info STRING, info3 STRING, yes BOOL, \
+        info4i INT64, Id INT64)"
+    );
+    assert_eq!(
+        DbOrderWithItems::get_create_table_ddl(&BigqueryClient::empty()).unwrap(),
+        "CREATE TABLE `..OrderItems` (items ARRAY<STRUCT<quantity INT64, sku STRING>>, order_id INT64)"
+    );
+}
+
+#[test]
+fn test_record_table_schema() {
+    let schema = DbOrderWithItems::get_table_schema();
+    let items = schema
+        .fields
+        .unwrap()
+        .into_iter()
+        .find(|f| f.name.as_deref() == Some("items"))
+        .unwrap();
+    assert_eq!(items.type_.as_deref(), Some("RECORD"));
+    assert_eq!(items.mode.as_deref(), Some("REPEATED"));
+    let members: Vec<(String, String)> = items
+        .fields
+        .unwrap()
+        .into_iter()
+        .map(|f| (f.name.unwrap(), f.type_.unwrap()))
+        .collect();
+    assert_eq!(
+        members,
+        [("quantity", "INT64"), ("sku", "STRING")]
+            .map(|(name, ty)| (name.to_string(), ty.to_string()))
+    );
+    assert_eq!(
+        DbOrderWithItems::json_schema()[0]["fields"][1],
+        json!({"name": "sku", "type": "STRING", "mode": "NULLABLE"})
+    );
+}
+
+#[test]
+fn test_create_table_ddl_rejects_non_date_partition() {
+    let error = DbIntPartitioned::get_create_table_ddl(&BigqueryClient::empty()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "IntPartitioned can not be partitioned by bucket: the column has type INT64, expected DATE, DATETIME or TIMESTAMP"
+    );
+}
+
+#[test]
+fn test_require_partition_filter() {
+    let select = || DbUserEvents::select().with_client(BigqueryClient::empty());
+    let error = select().build_query().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "UserEvents requires a where clause on the partition column ts"
+    );
+    let error = select()
+        .add_where_eq(name_of!(user_id in DbUserEvents), Some(&1i64))
+        .unwrap()
+        .build_query()
+        .unwrap_err();
+    assert!(error.to_string().contains("partition column ts"));
+    assert!(select()
+        .add_where_eq(name_of!(ts in DbUserEvents), None::<&DateTime<Utc>>)
+        .unwrap()
+        .build_query()
+        .is_ok());
+    assert!(DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .build_query()
+        .is_ok());
+}
+
+#[test]
+fn test_json_schema() {
+    assert_eq!(
+        DbRequiredInfos::json_schema(),
+        json!([
+            {"name": "id", "type": "INT64", "mode": "REQUIRED"},
+            {"name": "info", "type": "STRING", "mode": "NULLABLE"},
+        ])
+    );
+}
+
+/// Loads the NDJSON file at `BIGQUERY_TEST_GCS_URI` into the `Infos` table.
+#[cfg(feature = "gcs_integration_tests")]
+#[tokio::test]
+async fn test_load_from_gcs() {
+    use google_bigquery_v2::data::jobs::LoadFormat;
+    init_logger();
+    let client = get_test_client().await;
+    let gcs_uri = std::env::var("BIGQUERY_TEST_GCS_URI").expect("BIGQUERY_TEST_GCS_URI not set");
+    let stats = DbInfos::load_from_gcs(client, &gcs_uri, LoadFormat::NewlineDelimitedJson)
+        .await
+        .expect("load job failed");
+    debug!("load stats: {:?}", stats);
+    assert!(stats.output_rows > 0, "no rows were loaded");
+}
+
+/// Exports the `Infos` table as NDJSON to `BIGQUERY_TEST_GCS_EXPORT_URI`.
+#[cfg(feature = "gcs_integration_tests")]
+#[tokio::test]
+async fn test_extract_to_gcs() {
+    use google_bigquery_v2::data::jobs::ExtractFormat;
+    init_logger();
+    let client = get_test_client().await;
+    let gcs_uri = std::env::var("BIGQUERY_TEST_GCS_EXPORT_URI")
+        .expect("BIGQUERY_TEST_GCS_EXPORT_URI not set");
+    DbInfos::extract_to_gcs(client, &gcs_uri, ExtractFormat::NewlineDelimitedJson)
+        .await
+        .expect("extract job failed");
+}
+
+#[test]
+fn test_http_only_client() {
+    let client = BigqueryClient::empty()
+        .allow_http_only(true)
+        .with_root_url("http://localhost:9050");
+    assert!(client.get_allow_http_only());
+    assert!(!BigqueryClient::empty().get_allow_http_only());
+}
+
+#[tokio::test]
+async fn test_ping_empty_client() {
+    let error = BigqueryClient::empty()
+        .ping()
+        .await
+        .expect_err("ping with an empty client should fail");
+    assert!(error.to_string().contains("project id"));
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_ping() {
+    init_logger();
+    let client = get_test_client().await;
+    client.ping().await.expect("ping failed");
+}
+
+#[test]
+fn test_save_takes_a_shared_reference() {
+    let entry = DbInfos::default();
+    // only checks the signature, the future is never polled
+    let _future = entry.save();
+}
+
+#[test]
+fn test_auto_create_is_off_by_default() {
+    let client = BigqueryClient::empty();
+    assert!(!client.get_auto_create());
+    let client = client.auto_create(true);
+    assert!(client.get_auto_create());
+    assert!(client.with_dataset("other").get_auto_create());
+}
+
+#[test]
+fn test_retry_if() {
+    use google_bigquery2::api::ErrorProto;
+    use std::sync::Arc;
+    let with_reason = |reason: &str| {
+        BigqueryError::new(
+            "quota exceeded",
+            Some(vec![ErrorProto {
+                reason: Some(reason.to_string()),
+                ..Default::default()
+            }]),
+        )
+        .with_status(403)
+    };
+    let client = BigqueryClient::empty();
+    assert!(client.should_retry(&BigqueryError::new("", None).with_status(429)));
+    assert!(client.should_retry(&BigqueryError::new("", None).with_status(503)));
+    assert!(!client.should_retry(&BigqueryError::new("", None).with_status(400)));
+    assert!(!client.should_retry(&with_reason("quotaExceeded")));
+
+    let client = client.retry_if(Arc::new(|error| error.has_reason("quotaExceeded")));
+    assert!(client.should_retry(&with_reason("quotaExceeded")));
+    assert!(!client.should_retry(&with_reason("accessDenied")));
+    assert!(!client.should_retry(&BigqueryError::new("", None).with_status(503)));
+    assert!(client
+        .with_dataset("other")
+        .should_retry(&with_reason("quotaExceeded")));
+}
+
+#[tokio::test]
+async fn test_failed_query_error_contains_sql() {
+    init_logger();
+    // nothing listens on the discard port, so the request fails right away
+    let client = BigqueryClient::empty()
+        .allow_http_only(true)
+        .with_root_url("http://127.0.0.1:9/");
+    let error = DbSecrets::select()
+        .with_client(client)
+        .add_where_eq(name_of!(token in DbSecrets), Some(&"secret"))
+        .unwrap()
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap_err();
+    let bigquery_error = error.downcast_ref::<BigqueryError>().unwrap();
+    let query = bigquery_error.query.as_deref().unwrap();
+    assert!(query.starts_with("SELECT "));
+    assert!(query.ends_with("FROM `..Secrets` WHERE token = @__WHERE_0"));
+    assert!(error.to_string().contains(query));
+    let params = bigquery_error.params.as_ref().unwrap();
+    assert_eq!(params.len(), 1);
+    assert_eq!(
+        params[0].parameter_value.as_ref().unwrap().value.as_deref(),
+        Some("<redacted>")
+    );
+}
+
+/// Collects the fields of all events, to check what is logged.
+#[derive(Clone, Default)]
+struct FieldRecorder(std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+struct FieldVisitor<'a>(&'a std::sync::Mutex<Vec<(String, String)>>);
+
+impl tracing::field::Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        let field = field.name().to_string();
+        self.0.lock().unwrap().push((field, value.to_string()));
+    }
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn Debug) {
+        let field = field.name().to_string();
+        self.0.lock().unwrap().push((field, format!("{:?}", value)));
+    }
+}
+
+impl tracing::Subscriber for FieldRecorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+    fn event(&self, event: &tracing::Event<'_>) {
+        event.record(&mut FieldVisitor(&self.0));
+    }
+    fn enter(&self, _: &tracing::span::Id) {}
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+/// A minimal HTTP server for offline tests of the API calls: it answers the
+/// requests in order with the given (status, JSON body) responses and
+/// records each request line with its body.
+struct MockServer {
+    url: String,
+    requests: std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>,
+}
+
+impl MockServer {
+    fn start(responses: Vec<(u16, String)>) -> Self {
+        use std::io::{BufRead, Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for (status, body) in responses {
+                let Ok((stream, _)) = listener.accept() else {
+                    return;
+                };
+                let mut reader = std::io::BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut request_body = vec![0; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                recorded.lock().unwrap().push((
+                    request_line.trim().to_string(),
+                    String::from_utf8(request_body).unwrap(),
+                ));
+                let response = format!(
+                    "HTTP/1.1 {} MOCK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let mut stream = reader.into_inner();
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        Self { url, requests }
+    }
+
+    fn client(&self) -> BigqueryClient {
+        BigqueryClient::empty()
+            .allow_http_only(true)
+            .with_root_url(self.url.as_str())
+    }
+
+    /// The recorded (request line, body) pairs.
+    fn requests(&self) -> Vec<(String, String)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[tokio::test]
+async fn test_retried_query_keeps_its_request_id() {
+    init_logger();
+    let server = MockServer::start(vec![
+        (
+            503,
+            json!({"error": {"code": 503, "message": "backend error"}}).to_string(),
+        ),
+        (
+            200,
+            json!({"jobComplete": true, "numDmlAffectedRows": "1"}).to_string(),
+        ),
+    ]);
+    let data = DbInfos {
+        row_id: 1,
+        ..Default::default()
+    };
+    DbInfos::insert()
+        .with_client(server.client())
+        .set_data(data)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    let request_ids: Vec<serde_json::Value> = server
+        .requests()
+        .iter()
+        .map(|(_, body)| {
+            serde_json::from_str::<serde_json::Value>(body).unwrap()["requestId"].clone()
+        })
+        .collect();
+    assert_eq!(request_ids.len(), 2, "the 503 is retried once");
+    assert!(request_ids[0].is_string());
+    assert_eq!(request_ids[0], request_ids[1]);
+}
+
+#[test]
+fn test_request_id() {
+    let client = BigqueryClient::empty().with_request_id("Req/42");
+    assert_eq!(client.get_request_id(), Some("Req/42"));
+    let recorder = FieldRecorder::default();
+    let query_request = tracing::subscriber::with_default(recorder.clone(), || {
+        DbInfos::select()
+            .with_client(client.clone())
+            .build_query()
+            .unwrap()
+            .with_request_id("abc-1")
+            .build_query_request()
+    });
+    let labels = query_request.labels.unwrap();
+    assert_eq!(labels.get("request_id").map(String::as_str), Some("abc-1"));
+    assert!(recorder
+        .0
+        .lock()
+        .unwrap()
+        .contains(&("request_id".to_string(), "abc-1".to_string())));
+
+    // the id of the client, lowercased and with the '/' replaced for the label
+    let query_request = DbInfos::select()
+        .with_client(client)
+        .build_query()
+        .unwrap()
+        .build_query_request();
+    let labels = query_request.labels.unwrap();
+    assert_eq!(labels.get("request_id").map(String::as_str), Some("req_42"));
+    let query_request = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .build_query()
+        .unwrap()
+        .build_query_request();
+    assert_eq!(query_request.labels, None);
+}
+
+#[test]
+fn test_permission_denied_error() {
+    let error = PermissionDeniedError::new(
+        "`p.d.Infos`",
+        "Access Denied: Table p:d.Infos: Permission bigquery.tables.get denied on table \
+        p:d.Infos (or it may not exist).",
+    );
+    assert_eq!(error.permission.as_deref(), Some("bigquery.tables.get"));
+    assert_eq!(
+        error.to_string(),
+        "PermissionDenied: bigquery.tables.get is missing on `p.d.Infos`"
+    );
+    let error = PermissionDeniedError::new("`p.d.Infos`", "Access Denied");
+    assert_eq!(error.permission, None);
+    assert_eq!(
+        error.to_string(),
+        "PermissionDenied: no access to `p.d.Infos`: Access Denied"
+    );
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_check_access() {
+    init_logger();
+    let client = get_test_client().await;
+    DbInfos::check_access(&client).await.unwrap();
+    // a project the test service account has no access to
+    let error = DbInfos::check_access(&client.with_target("bigquery", "private"))
+        .await
+        .unwrap_err();
+    assert!(
+        error.downcast_ref::<PermissionDeniedError>().is_some(),
+        "unexpected error: {:?}",
+        error
+    );
+}
+
+#[test]
+fn test_qualified_table_identifier() {
+    assert_eq!(
+        qualified_table_identifier("bigquery-public-data", "samples", "shakespeare").unwrap(),
+        "`bigquery-public-data.samples.shakespeare`"
+    );
+    assert!(qualified_table_identifier("project", "", "table").is_err());
+    assert!(qualified_table_identifier("project", "data`set", "table").is_err());
+    assert!(qualified_table_identifier("project", "dataset", "a.b").is_err());
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_public_dataset_without_dataset_id() {
+    init_logger();
+    let client = BigqueryClient::new_for_project("testrustproject-372221", None)
+        .await
+        .unwrap();
+    assert_eq!(client.get_dataset_id(), "");
+    let table =
+        qualified_table_identifier("bigquery-public-data", "samples", "shakespeare").unwrap();
+    let words: Option<i64> = client
+        .query_scalar(
+            &format!("SELECT COUNT(*) FROM {} WHERE corpus = @corpus", table),
+            vec![param("corpus", &"hamlet".to_string())],
+        )
+        .await
+        .unwrap();
+    assert!(words.unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_table_writer_buffers_rows() {
+    init_logger();
+    let mut writer = TableWriter::<DbInfos>::new(BigqueryClient::empty(), 3);
+    for row_id in 0..2 {
+        writer
+            .push(&DbInfos {
+                row_id,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+    assert_eq!(writer.len(), 2);
+    // the third row reaches the threshold, but the client has no project
+    let error = writer
+        .push(&DbInfos {
+            row_id: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("can not insert 3 rows into Infos"));
+    // nothing was sent, so the rows are kept for the next flush
+    assert_eq!(writer.len(), 3);
+}
+
+#[tokio::test]
+async fn test_table_writer_keeps_rows_until_answered() {
+    init_logger();
+    let server = MockServer::start(vec![
+        (
+            503,
+            json!({"error": {"code": 503, "message": "backend error"}}).to_string(),
+        ),
+        (
+            200,
+            json!({"kind": "bigquery#tableDataInsertAllResponse"}).to_string(),
+        ),
+    ]);
+    let client = server
+        .client()
+        .with_project("project")
+        .with_dataset("dataset");
+    let mut writer = TableWriter::<DbInfos>::new(client, 10);
+    for row_id in 0..2 {
+        writer
+            .push(&DbInfos {
+                row_id,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+    assert!(writer.flush().await.is_err());
+    assert_eq!(writer.len(), 2);
+    assert_eq!(writer.flush().await.unwrap(), 2);
+    assert!(writer.is_empty());
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].0.contains("/tables/Infos/insertAll"));
+    let insert_ids: Vec<Vec<serde_json::Value>> = requests
+        .iter()
+        .map(|(_, body)| {
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            body["rows"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|row| row["insertId"].clone())
+                .collect()
+        })
+        .collect();
+    assert!(insert_ids[0][0].is_string());
+    assert_ne!(insert_ids[0][0], insert_ids[0][1]);
+    assert_eq!(
+        insert_ids[0], insert_ids[1],
+        "a repeated flush keeps the ids"
+    );
+}
+
+#[test]
+fn test_insert_rows_error() {
+    use google_bigquery2::api::ErrorProto;
+    let error = InsertRowsError {
+        failed_rows: vec![FailedRow {
+            index: 1,
+            row: json!({"Id": "1"}),
+            errors: vec![ErrorProto {
+                message: Some("no such field: nope".to_string()),
+                ..Default::default()
+            }],
+        }],
+        batch_size: 3,
+    };
+    assert_eq!(
+        error.to_string(),
+        "1 of 3 rows failed to insert: no such field: nope"
+    );
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_table_writer() {
+    init_logger();
+    let client = get_test_client().await;
+    // a table of its own, since streamed rows can't be deleted for a while
+    let table_name = format!("Writer_{}", Utc::now().timestamp_millis());
+    client
+        .run_script(
+            &format!(
+                "CREATE TABLE {} LIKE {}",
+                DbInfos::get_table_identifier_with_name(&client, &table_name),
+                DbInfos::get_table_identifier_from_client(&client)
+            ),
+            vec![],
+        )
+        .await
+        .unwrap();
+    let mut writer = TableWriter::<DbInfos>::new(client.clone(), 1000).with_table_name(&table_name);
+    for row_id in 0..2500 {
+        writer
+            .push(&DbInfos {
+                row_id,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+    assert_eq!(writer.len(), 500);
+    assert_eq!(writer.flush().await.unwrap(), 500);
+    let rows = DbInfos::select()
+        .with_client(client.clone())
+        .with_table_name(&table_name)
+        .unwrap()
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("select should return data");
+    client
+        .get_client()
+        .tables()
+        .delete(
+            client.get_project_id(),
+            client.get_dataset_id(),
+            &table_name,
+        )
+        .doit()
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 2500);
+}
+
+#[tokio::test]
+async fn test_auto_create_only_for_inserts_into_the_missing_table() {
+    init_logger();
+    let not_found = |table: &str| {
+        json!({"error": {
+            "code": 404,
+            "message": format!("Not found: Table project:dataset.{} was not found in location US", table),
+            "errors": [{"reason": "notFound", "message": "not found"}]
+        }})
+        .to_string()
+    };
+    let data = DbInfos {
+        row_id: 1,
+        ..Default::default()
+    };
+    let server = MockServer::start(vec![
+        (404, not_found("Infos")),
+        (
+            200,
+            json!({"tableReference": {"tableId": "Infos"}}).to_string(),
+        ),
+        (
+            200,
+            json!({"jobComplete": true, "numDmlAffectedRows": "1"}).to_string(),
+        ),
+    ]);
+    let client = server
+        .client()
+        .with_project("project")
+        .with_dataset("dataset")
+        .auto_create(true);
+    DbInfos::insert()
+        .with_client(client)
+        .set_data(data.clone())
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests[1]
+        .0
+        .starts_with("POST /bigquery/v2/projects/project/datasets/dataset/tables"));
+
+    // an update would find no row in a new, empty table
+    let server = MockServer::start(vec![(404, not_found("Infos"))]);
+    let client = server
+        .client()
+        .with_project("project")
+        .with_dataset("dataset")
+        .auto_create(true);
+    let result = DbInfos::update()
+        .with_client(client)
+        .set_data(data.clone())
+        .build_query()
+        .unwrap()
+        .run_dml()
+        .await;
+    assert!(result.is_err());
+    assert_eq!(server.requests().len(), 1);
+
+    // another table of the query is missing, e.g. one of a subquery
+    let server = MockServer::start(vec![(404, not_found("InfosStaging"))]);
+    let client = server
+        .client()
+        .with_project("project")
+        .with_dataset("dataset")
+        .auto_create(true);
+    let result = DbInfos::insert()
+        .with_client(client)
+        .set_data(data)
+        .build_query()
+        .unwrap()
+        .run_dml()
+        .await;
+    assert!(result.is_err());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_auto_create() {
+    init_logger();
+    let client = get_test_client().await.auto_create(true);
+    let table_name = format!("AutoCreated_{}", Utc::now().timestamp_millis());
+    let affected_rows = DbInfos::insert()
+        .with_client(client.clone())
+        .with_table_name(&table_name)
+        .unwrap()
+        .set_data(DbInfos {
+            row_id: 1,
+            info1: Some("created".to_string()),
+            ..Default::default()
+        })
+        .build_query()
+        .unwrap()
+        .run_dml()
+        .await
+        .unwrap();
+    assert_eq!(affected_rows, 1);
+    let rows = DbInfos::select()
+        .with_client(client.clone())
+        .with_table_name(&table_name)
+        .unwrap()
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("select should return data");
+    client
+        .get_client()
+        .tables()
+        .delete(
+            client.get_project_id(),
+            client.get_dataset_id(),
+            &table_name,
+        )
+        .doit()
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].info1.as_deref(), Some("created"));
+}
+
+#[tokio::test]
+async fn test_run_with_job_id() {
+    init_logger();
+    let server = MockServer::start(vec![(
+        200,
+        json!({"jobComplete": true, "jobReference": {"jobId": "job_1"}, "totalRows": "0"})
+            .to_string(),
+    )]);
+    let client = server
+        .client()
+        .with_project("project")
+        .with_dataset("dataset");
+    let (result, job_id) = DbInfos::select()
+        .with_client(client)
+        .build_query()
+        .unwrap()
+        .run_with_job_id()
+        .await
+        .unwrap();
+    assert!(result
+        .expect_with_data("select should return data")
+        .is_empty());
+    assert_eq!(job_id.as_deref(), Some("job_1"));
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_last_job_id() {
+    init_logger();
+    let client = get_test_client().await;
+    let (_, job_id) = DbInfos::select()
+        .with_client(client.clone())
+        .set_limit(1)
+        .build_query()
+        .unwrap()
+        .run_with_job_id()
+        .await
+        .unwrap();
+    let job_id = job_id.expect("the job id should be captured");
+    assert!(!job_id.is_empty());
+}
+
+#[tokio::test]
+async fn test_run_cancellable_already_cancelled() {
+    init_logger();
+    let result = DbInfos::select()
+        .with_client(BigqueryClient::empty())
+        .build_query()
+        .unwrap()
+        .run_cancellable(std::future::ready(()))
+        .await;
+    let error = result.expect_err("a cancelled query should not run");
+    let cancelled = error
+        .downcast_ref::<QueryCancelledError>()
+        .expect("should be a QueryCancelledError");
+    assert_eq!(cancelled.job_id, None);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_run_cancellable() {
+    init_logger();
+    let client = get_test_client().await;
+    let rows = DbInfos::select()
+        .with_client(client.clone())
+        .set_limit(1)
+        .build_query()
+        .unwrap()
+        .run_cancellable(std::future::pending())
+        .await
+        .unwrap()
+        .expect_with_data("select should return data");
+    assert_eq!(rows.len(), 1);
+
+    let result = DbInfos::select()
+        .with_client(client.clone())
+        .build_query()
+        .unwrap()
+        .run_cancellable(tokio::time::sleep(std::time::Duration::from_millis(10)))
+        .await;
+    let error = result.expect_err("the query should be cancelled");
+    let cancelled = error
+        .downcast_ref::<QueryCancelledError>()
+        .expect("should be a QueryCancelledError");
+    assert!(cancelled.job_id.is_some());
+}
+
+#[test]
+fn test_client_close() {
+    for i in 0..20 {
+        let client = BigqueryClient::empty().with_dataset(format!("tenant_{}", i));
+        let table = DbInfos {
+            client: client.clone(),
+            ..Default::default()
+        };
+        client.close();
+        drop(table);
+    }
+}
+
+#[test]
+fn test_client_retarget() {
+    let client = BigqueryClient::new_with_access_token(
+        "my-project",
+        "my_dataset",
+        "dummy-token".to_string(),
+        None,
+    );
+    let public = client.with_target("bigquery-public-data", "samples");
+    assert_eq!(
+        DbInfos::get_table_identifier_from_client(&public),
+        "`bigquery-public-data.samples.Infos`"
+    );
+    // the jobs still run in the own project
+    assert_eq!(public.get_job_project_id(), "my-project");
+
+    let other_dataset = client.with_dataset("other");
+    assert_eq!(
+        DbInfos::get_table_identifier_from_client(&other_dataset),
+        "`my-project.other.Infos`"
+    );
+    let other_project = public.with_project("another-project");
+    assert_eq!(
+        DbInfos::get_table_identifier_from_client(&other_project),
+        "`another-project.samples.Infos`"
+    );
+    assert_eq!(other_project.get_job_project_id(), "my-project");
+    assert_eq!(client.get_job_project_id(), "my-project");
+}
+
+#[test]
+fn test_db_dataset() {
+    init_logger();
+    let client = BigqueryClient::new_with_access_token(
+        "my-project",
+        "my_dataset",
+        "dummy-token".to_string(),
+        None,
+    );
+    assert_eq!(
+        DbPageViews::get_dataset_name(),
+        Some("analytics".to_string())
+    );
+    assert_eq!(DbInfos::get_dataset_name(), None);
+    assert_eq!(
+        DbPageViews::get_table_identifier_from_client(&client),
+        "`my-project.analytics.PageViews`"
+    );
+    assert_eq!(
+        DbPageViews::get_table_identifier_from_client(&client.with_dataset("other")),
+        "`my-project.analytics.PageViews`"
+    );
+    assert_eq!(
+        DbInfos::get_table_identifier_from_client(&client),
+        "`my-project.my_dataset.Infos`"
+    );
+    let query_builder = DbPageViews::select()
+        .with_client(client)
+        .build_query()
+        .unwrap();
+    assert_eq!(
+        query_builder.get_query_string(),
+        "SELECT id, url FROM `my-project.analytics.PageViews`"
+    );
+}
+
+#[tokio::test]
+async fn test_client_with_access_token() {
+    let client = BigqueryClient::new_with_access_token(
+        "project",
+        "dataset",
+        "dummy-token".to_string(),
+        Some(Utc::now() + chrono::Duration::hours(1)),
+    );
+    assert_eq!(client.get_project_id(), "project");
+    assert_eq!(client.get_dataset_id(), "dataset");
+    let token = client.get_client().auth.get_token(&[]).await.unwrap();
+    assert_eq!(token.as_deref(), Some("dummy-token"));
+
+    let expired = BigqueryClient::new_with_access_token(
+        "project",
+        "dataset",
+        "dummy-token".to_string(),
+        Some(Utc::now() - chrono::Duration::hours(1)),
+    );
+    assert!(expired.get_client().auth.get_token(&[]).await.is_err());
+}
+
+#[tokio::test]
+async fn test_reload_all_empty() {
+    let missing = DbInfos::reload_all(BigqueryClient::empty(), &mut [])
+        .await
+        .expect("reloading nothing should not send a query");
+    assert!(missing.is_empty());
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_reload_all() {
+    init_logger();
+    let client = get_test_client().await;
+    let mut entities: Vec<DbInfos> = DbInfos::select()
+        .with_client(client.clone())
+        .add_order_by(name_of!(row_id in DbInfos), OrderDirection::Ascending)
+        .set_limit(3)
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("no data");
+    assert_eq!(entities.len(), 3);
+    let expected: Vec<Option<String>> = entities.iter().map(|e| e.info1.clone()).collect();
+    for entity in entities.iter_mut() {
+        entity.info1 = Some("changed locally".to_string());
+    }
+    entities.push(DbInfos {
+        client: client.clone(),
+        row_id: -4711,
+        ..Default::default()
+    });
+
+    let missing = DbInfos::reload_all(client, &mut entities).await.unwrap();
+    assert_eq!(missing, vec![3]);
+    let reloaded: Vec<Option<String>> = entities[..3].iter().map(|e| e.info1.clone()).collect();
+    assert_eq!(reloaded, expected);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_run_raw() {
+    init_logger();
+    let client = get_test_client().await;
+    let rows = google_bigquery_v2::data::run_raw(&client, "SELECT 1 AS a, 'x' AS b", vec![])
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 1);
+    let mut keys: Vec<_> = rows[0].keys().cloned().collect();
+    keys.sort();
+    assert_eq!(keys, vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(rows[0]["a"], json!("1"));
+    assert_eq!(rows[0]["b"], json!("x"));
+}
+
+#[tokio::test]
+async fn test_run_raw_repeated_scalars() {
+    init_logger();
+    let server = MockServer::start(vec![(
+        200,
+        json!({
+            "jobComplete": true,
+            "schema": {"fields": [
+                {"name": "id", "type": "INTEGER", "mode": "NULLABLE"},
+                {"name": "tags", "type": "STRING", "mode": "REPEATED"}
+            ]},
+            "rows": [
+                {"f": [{"v": "1"}, {"v": [{"v": "a"}, {"v": "b"}]}]},
+                {"f": [{"v": "2"}, {"v": []}]}
+            ]
+        })
+        .to_string(),
+    )]);
+    let client = server
+        .client()
+        .with_project("project")
+        .with_dataset("dataset");
+    let rows = google_bigquery_v2::data::run_raw(&client, "SELECT id, tags FROM t", vec![])
+        .await
+        .unwrap();
+    assert_eq!(rows[0]["tags"], json!(["a", "b"]));
+    assert_eq!(rows[1]["tags"], json!([]));
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_query_scalar() {
+    init_logger();
+    let client = get_test_client().await;
+    let table = DbInfos::get_table_identifier_from_client(&client);
+    let count: Option<i64> = client
+        .query_scalar(&format!("SELECT COUNT(*) FROM {}", table), vec![])
+        .await
+        .unwrap();
+    assert!(count.unwrap() > 0);
+    let count: Option<i64> = client
+        .query_scalar(
+            &format!("SELECT COUNT(*) FROM {} WHERE Id < @min", table),
+            vec![param("min", &i64::MIN)],
+        )
+        .await
+        .unwrap();
+    assert_eq!(count, Some(0));
+    let none: Option<i64> = client
+        .query_scalar("SELECT x FROM UNNEST([1]) AS x WHERE x > 1", vec![])
+        .await
+        .unwrap();
+    assert_eq!(none, None);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_run_script() {
+    init_logger();
+    let client = get_test_client().await;
+    let table = DbVersionedInfos::get_table_identifier_from_client(&client);
+    let sql = format!(
+        "INSERT INTO {0} (id, info) VALUES (@id, 'script'); DELETE FROM {0} WHERE id = @id;",
+        table
+    );
+    let stats = client
+        .run_script(&sql, vec![param("id", &4713i64)])
+        .await
+        .unwrap();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].statement_type.as_deref(), Some("INSERT"));
+    assert_eq!(stats[0].num_dml_affected_rows, 1);
+    assert_eq!(stats[1].statement_type.as_deref(), Some("DELETE"));
+    assert_eq!(stats[1].num_dml_affected_rows, 1);
+}
+
+#[test]
+fn test_transaction_script() {
+    init_logger();
+    let mut transaction = Transaction::new();
+    for id in [1, 2] {
+        transaction
+            .add(
+                DbVersionedInfos::insert()
+                    .with_client(BigqueryClient::empty())
+                    .set_data(DbVersionedInfos {
+                        id,
+                        ..Default::default()
+                    })
+                    .build_query()
+                    .unwrap(),
+            )
+            .unwrap();
+    }
+    let (script, params) = transaction.build_script();
+    assert_eq!(
+        script,
+        "BEGIN\n  BEGIN TRANSACTION;\n  \
+         insert into `..VersionedInfos` (id, info, version) values(@__PARAM_id_s0, NULL, NULL);\n  \
+         insert into `..VersionedInfos` (id, info, version) values(@__PARAM_id_s1, NULL, NULL);\n  \
+         COMMIT TRANSACTION;\n\
+         EXCEPTION WHEN ERROR THEN\n  ROLLBACK TRANSACTION;\n  \
+         RAISE USING MESSAGE = @@error.message;\nEND;"
+    );
+    let names: Vec<_> = params.iter().map(|p| p.name.clone().unwrap()).collect();
+    assert_eq!(names, vec!["__PARAM_id_s0", "__PARAM_id_s1"]);
+}
+
+#[cfg(feature = "integration_tests")]
+#[tokio::test]
+async fn test_transaction_rollback() {
+    init_logger();
+    #[derive(BigDataTableDerive, Debug, Default, Clone)]
+    #[db_name("TableThatDoesNotExist")]
+    pub struct DbMissing {
+        #[client]
+        client: BigqueryClient,
+        #[primary_key]
+        id: i64,
+    }
+
+    let client = get_test_client().await;
+    let entry = DbVersionedInfos {
+        client: client.clone(),
+        id: 4714,
+        info: Some("transaction".to_string()),
+        version: None,
+    };
+    let result = client
+        .transaction(|tx| {
+            tx.add(
+                DbVersionedInfos::insert()
+                    .with_client(client.clone())
+                    .set_data(entry.clone())
+                    .build_query()?,
+            )?;
+            // the table does not exist, so this statement fails
+            tx.add(
+                DbMissing::insert()
+                    .with_client(client.clone())
+                    .set_data(DbMissing {
+                        id: 4714,
+                        ..Default::default()
+                    })
+                    .build_query()?,
+            )?;
+            Ok(())
+        })
+        .await;
+    assert!(result.is_err());
+    let rows = DbVersionedInfos::select()
+        .with_client(client)
+        .add_where_eq(name_of!(id in DbVersionedInfos), Some(&4714i64))
+        .unwrap()
+        .build_query()
+        .unwrap()
+        .run()
+        .await
+        .unwrap()
+        .expect_with_data("select should return data");
+    assert!(rows.is_empty(), "the first insert should be rolled back");
 }
 
 #[test]
@@ -0,0 +1,14 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+pub struct DuplicateDbName {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    row_id: i64,
+    #[db_name("info")]
+    info1: Option<String>,
+    info: Option<String>,
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataColumnsDerive, Debug, Default, Clone)]
+pub struct Address {
+    city: Option<String>,
+}
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+pub struct DuplicateFlattenedDbName {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    row_id: i64,
+    address_city: Option<String>,
+    #[db_flatten]
+    address: Address,
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+#[db_name("InfosView")]
+#[db_view]
+pub struct DbInfosView {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    row_id: i64,
+    info: Option<String>,
+}
+
+fn main() {
+    let _ = DbInfosView::select();
+    let _ = DbInfosView::insert();
+}
@@ -0,0 +1,13 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+pub struct MultiplePrimaryKeys {
+    #[client]
+    client: BigqueryClient,
+    #[primary_key]
+    row_id: i64,
+    #[primary_key]
+    other_id: i64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+pub struct NoClient {
+    #[primary_key]
+    row_id: i64,
+}
+
+fn main() {}
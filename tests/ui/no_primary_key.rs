@@ -0,0 +1,10 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+pub struct NoPrimaryKey {
+    #[client]
+    client: BigqueryClient,
+    row_id: i64,
+}
+
+fn main() {}
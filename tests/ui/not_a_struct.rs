@@ -0,0 +1,9 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataTableDerive, Debug, Clone)]
+pub enum NotAStruct {
+    A,
+    B,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+pub struct TupleStruct(#[client] BigqueryClient, #[primary_key] i64);
+
+fn main() {}
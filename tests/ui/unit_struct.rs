@@ -0,0 +1,6 @@
+use google_bigquery_v2::prelude::*;
+
+#[derive(BigDataTableDerive, Debug, Default, Clone)]
+pub struct UnitStruct;
+
+fn main() {}